@@ -0,0 +1,165 @@
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Bytes;
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use log::info;
+
+use crate::request::{request_id, RequestId, REQUEST_ID_KEY};
+
+/// The fields of an access record, which also sit in the logging MDC while it is written.
+struct AccessRecord {
+    remote: String,
+    method: String,
+    path: String,
+    version: String,
+    status: u16,
+    request_id: Option<RequestId>,
+    started: Instant
+}
+
+impl AccessRecord {
+    /// Writes the record once the response body has been sent.
+    ///
+    /// The fields are put in the MDC rather than formatted into a JSON string, so that the JSON
+    /// encoder escapes them and emits them as their own keys next to the message.
+    fn write(&self, bytes: usize) {
+        let duration = format!("{:.3}", self.started.elapsed().as_secs_f64() * 1000.0);
+        let status = self.status.to_string();
+        let size = bytes.to_string();
+        let fields = [
+            ("remote", self.remote.as_str()),
+            ("method", self.method.as_str()),
+            ("path", self.path.as_str()),
+            ("version", self.version.as_str()),
+            ("status", status.as_str()),
+            ("bytes", size.as_str()),
+            ("duration", duration.as_str())
+        ];
+
+        for (key, value) in fields {
+            log_mdc::insert(key, value);
+        }
+
+        if let Some(id) = &self.request_id {
+            log_mdc::insert(REQUEST_ID_KEY, id.as_str());
+        }
+
+        info!("{} {} {} {} {} {}B {}ms", self.remote, self.method, self.path, self.version, self.status, bytes, duration);
+
+        for (key, _) in fields {
+            log_mdc::remove(key);
+        }
+
+        if self.request_id.is_some() {
+            log_mdc::remove(REQUEST_ID_KEY);
+        }
+    }
+}
+
+/// Counts the bytes of a response body and writes the access record when it is dropped.
+pub struct AccessBody<B> {
+    body: Pin<Box<B>>,
+    bytes: usize,
+    record: AccessRecord
+}
+
+impl<B> Drop for AccessBody<B> {
+    fn drop(&mut self) {
+        self.record.write(self.bytes);
+    }
+}
+
+impl<B: MessageBody> MessageBody for AccessBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = this.body.as_mut().poll_next(context);
+
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            this.bytes += chunk.len();
+        }
+
+        poll
+    }
+}
+
+/// Logs one record per request with the client, request line, status, body size and duration.
+///
+/// Must be wrapped outside [`crate::request::RequestIdentity`] so that the record carries the request identifier.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AccessLog;
+
+impl<S, B> Transform<S, ServiceRequest> for AccessLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static
+{
+    type Response = ServiceResponse<AccessBody<B>>;
+    type Error = Error;
+    type Transform = AccessLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AccessLogMiddleware { service }))
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S
+}
+
+impl<S, B> Service<ServiceRequest> for AccessLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static
+{
+    type Response = ServiceResponse<AccessBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let remote = request
+            .connection_info()
+            .realip_remote_addr()
+            .unwrap_or("-")
+            .to_string();
+        let method = request.method().to_string();
+        let path = request.uri().to_string();
+        let version = format!("{:?}", request.version());
+
+        let future = self.service.call(request);
+
+        Box::pin(async move {
+            let response = future.await?;
+            let record = AccessRecord {
+                remote,
+                method,
+                path,
+                version,
+                status: response.status().as_u16(),
+                request_id: request_id(response.request()),
+                started
+            };
+
+            Ok(response.map_body(|_, body| AccessBody {
+                body: Box::pin(body),
+                bytes: 0,
+                record
+            }))
+        })
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::sync::Arc;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{Error, HttpMessage};
+use sha2::{Digest, Sha256};
+
+use crate::model::User;
+use crate::serialization::encode_hex;
+
+/// Returns the hexadecimal SHA-256 digest of a bearer token, which is what identifies its user.
+pub fn token_digest(token: &str) -> String {
+    encode_hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Authenticates users by the bearer token in the Authorization header.
+///
+/// Users are known by the SHA-256 digest of their token, so the tokens themselves are never kept.
+/// A request with a known token carries its [`User`] in the request extensions, while any other
+/// request is left unauthenticated for the endpoints to refuse.
+#[derive(Debug, Default, Clone)]
+pub struct BearerAuthentication {
+    users: Arc<HashMap<String, User>>
+}
+
+impl BearerAuthentication {
+    /// Authenticates the users of the given token digests.
+    pub fn new(users: HashMap<String, User>) -> Self {
+        BearerAuthentication {
+            users: Arc::new(users)
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for BearerAuthentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware {
+            service,
+            users: self.users.clone()
+        }))
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: S,
+    users: Arc<HashMap<String, User>>
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let user = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.users.get(&token_digest(token)))
+            .copied();
+
+        if let Some(user) = user {
+            request.extensions_mut().insert(user);
+        }
+
+        self.service.call(request)
+    }
+}
@@ -1,7 +1,7 @@
 use std::{time::Duration, fmt};
 
 use mobc::{Pool, Connection};
-use mobc_redis::{redis::{Client, RedisError, IntoConnectionInfo}, RedisConnectionManager};
+use mobc_redis::{redis::{cmd, Client, RedisError, IntoConnectionInfo}, RedisConnectionManager};
 
 pub type MobcPool = Pool<RedisConnectionManager>;
 pub type MobcConnection = Connection<RedisConnectionManager>;
@@ -9,14 +9,16 @@ pub type MobcConnection = Connection<RedisConnectionManager>;
 #[derive(Debug)]
 pub enum RedisDatabaseError {
     OpenClient(RedisError),
-    CreateConnection(mobc::Error<RedisError>)
+    CreateConnection(mobc::Error<RedisError>),
+    Ping(RedisError)
 }
 
 impl fmt::Display for RedisDatabaseError {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
             RedisDatabaseError::OpenClient(error) => write!(formatter, "{}", error),
-            RedisDatabaseError::CreateConnection(error) => write!(formatter, "{}", error)
+            RedisDatabaseError::CreateConnection(error) => write!(formatter, "{}", error),
+            RedisDatabaseError::Ping(error) => write!(formatter, "{}", error)
         }
     }
 }
@@ -25,7 +27,8 @@ impl std::error::Error for RedisDatabaseError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
             RedisDatabaseError::OpenClient(ref error) => Some(error),
-            RedisDatabaseError::CreateConnection(ref error) => Some(error)
+            RedisDatabaseError::CreateConnection(ref error) => Some(error),
+            RedisDatabaseError::Ping(ref error) => Some(error)
         }
     }
 }
@@ -52,3 +55,15 @@ pub async fn get_connection(pool: &MobcPool) -> Result<MobcConnection, RedisData
         .await
         .map_err(RedisDatabaseError::CreateConnection)
 }
+
+/// Checks that the database can be reached with a round-trip.
+pub async fn ping(pool: &MobcPool) -> Result<(), RedisDatabaseError> {
+    let mut connection = get_connection(pool).await?;
+
+    cmd("PING")
+        .query_async::<_, String>(&mut *connection)
+        .await
+        .map_err(RedisDatabaseError::Ping)?;
+
+    Ok(())
+}
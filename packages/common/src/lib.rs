@@ -3,3 +3,9 @@ pub mod database;
 pub mod alias;
 pub mod serialization;
 pub mod state;
+pub mod request;
+pub mod authentication;
+pub mod access;
+pub mod store;
+pub mod s3;
+pub mod wire;
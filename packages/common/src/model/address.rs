@@ -17,6 +17,7 @@ pub struct Address {
 }
 
 const HOST_PATTERN: &str = r"([a-zA-Z0-9_-]{32})@((?:[a-z0-9](?:[a-z0-9-]{0,61}[a-z0-9])?\.)+[a-z0-9][a-z0-9-]{0,61}[a-z0-9])";
+const DOMAIN_PATTERN: &str = r"^(?:[a-z0-9](?:[a-z0-9-]{0,61}[a-z0-9])?\.)+[a-z0-9][a-z0-9-]{0,61}[a-z0-9]$";
 
 #[derive(Debug)]
 pub enum ParseError {
@@ -44,6 +45,14 @@ impl error::Error for ParseError {
 
 lazy_static! {
     static ref RE: Regex = Regex::new(HOST_PATTERN).unwrap();
+    static ref DOMAIN_RE: Regex = Regex::new(DOMAIN_PATTERN).unwrap();
+}
+
+impl Address {
+    /// Returns true if the host can be part of an address.
+    pub fn is_valid_host(host: &str) -> bool {
+        DOMAIN_RE.is_match(host)
+    }
 }
 
 impl TryFrom<&str> for Address {
@@ -1,36 +1,89 @@
+use std::fmt;
+use bytes::Bytes;
 use serde::{Serialize, Deserialize, Deserializer, Serializer};
 use base64::Engine;
 use base64::engine::general_purpose::URL_SAFE;
-use serde::de::Error;
+use serde::de::{Error, Visitor};
 
 pub type OptionalBlob = Option<Blob>;
 
 /// An arbitrary block of binary data.
-#[derive(Debug, Clone)]
-pub struct Blob(Vec<u8>);
+///
+/// The data is reference counted, so clones share it instead of copying it. Human readable
+/// formats such as JSON carry it as URL-safe base64, binary formats as a native byte string.
+#[derive(Debug, Clone, Default)]
+pub struct Blob(Bytes);
 
 impl From<Vec<u8>> for Blob {
     fn from(value: Vec<u8>) -> Self {
+        Blob(Bytes::from(value))
+    }
+}
+
+impl From<Bytes> for Blob {
+    fn from(value: Bytes) -> Self {
         Blob(value)
     }
 }
 
+impl From<Blob> for Bytes {
+    fn from(value: Blob) -> Self {
+        value.0
+    }
+}
+
+impl Blob {
+    /// Returns the number of characters needed to encode data of the given size as base64.
+    pub fn encoded_len(size: u64) -> u64 {
+        size.div_ceil(3) * 4
+    }
+
+    /// Returns the number of bytes in the blob.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true if the blob holds no data.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl AsRef<[u8]> for Blob {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl Serialize for Blob {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where S: Serializer {
         let bytes = &self.0;
+
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(bytes);
+        }
+
         let base64 = URL_SAFE.encode(bytes);
 
         String::serialize(&base64, serializer)
     }
 }
 
-impl<'de> Deserialize<'de> for Blob {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where D: Deserializer<'de> {
-        let base64 = String::deserialize(deserializer)?;
+/// Decodes base64 straight from the input without copying the encoded text first, and takes
+/// byte strings from binary formats as they are.
+struct BlobVisitor;
+
+impl Visitor<'_> for BlobVisitor {
+    type Value = Blob;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a URL-safe base64 string or a byte string")
+    }
 
-        let bytes = URL_SAFE.decode(base64).map_err(|error| {
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where E: Error {
+        let bytes = URL_SAFE.decode(value).map_err(|error| {
             Error::custom(error)
         })?;
 
@@ -38,4 +91,29 @@ impl<'de> Deserialize<'de> for Blob {
             Blob::from(bytes)
         )
     }
+
+    fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E>
+    where E: Error {
+        Ok(
+            Blob::from(Bytes::copy_from_slice(value))
+        )
+    }
+
+    fn visit_byte_buf<E>(self, value: Vec<u8>) -> Result<Self::Value, E>
+    where E: Error {
+        Ok(
+            Blob::from(value)
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for Blob {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: Deserializer<'de> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(BlobVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(BlobVisitor)
+        }
+    }
 }
@@ -4,6 +4,7 @@ pub mod ciphers;
 pub mod moderation;
 pub mod blob;
 pub mod labels;
+pub mod user;
 
 pub use identifier::*;
 pub use address::*;
@@ -11,3 +12,4 @@ pub use ciphers::*;
 pub use moderation::*;
 pub use blob::*;
 pub use labels::*;
+pub use user::*;
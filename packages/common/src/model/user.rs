@@ -0,0 +1,10 @@
+use serde::{Serialize, Deserialize};
+
+use super::Identifier;
+
+/// A local user that has been authenticated for the current request.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct User {
+    /// The locally unique identifier of the user.
+    pub id: Identifier
+}
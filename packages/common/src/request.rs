@@ -0,0 +1,190 @@
+use std::fmt;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use actix_web::body::{BoxBody, EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use serde_json::json;
+
+use crate::model::Identifier;
+
+/// The header carrying the request identifier, both inbound and on outbound federation requests.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The logging MDC key of the request identifier.
+pub const REQUEST_ID_KEY: &str = "request_id";
+
+/// The longest request identifier accepted from a client.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Identifies one request across log lines, error responses and federated hops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(String);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", self.0)
+    }
+}
+
+impl RequestId {
+    /// Generates a new random request identifier.
+    pub fn new() -> Self {
+        RequestId(Identifier::new().to_string())
+    }
+
+    /// Accepts a client provided identifier if it is short and only uses URL safe characters.
+    pub fn parse(value: &str) -> Option<Self> {
+        let valid = !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH && value
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || matches!(character, '-' | '_' | '.'));
+
+        if valid {
+            Some(RequestId(value.to_string()))
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        RequestId::new()
+    }
+}
+
+/// Returns the identifier assigned to a request by the [`RequestIdentity`] middleware.
+pub fn request_id(request: &HttpRequest) -> Option<RequestId> {
+    request
+        .extensions()
+        .get::<RequestId>()
+        .cloned()
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(request_id(request).unwrap_or_default()))
+    }
+}
+
+/// Polls a future with the request identifier in the logging MDC.
+struct WithRequestId<F: Future> {
+    id: RequestId,
+    inner: Pin<Box<F>>
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        log_mdc::insert(REQUEST_ID_KEY, this.id.as_str());
+
+        let poll = this.inner.as_mut().poll(context);
+
+        log_mdc::remove(REQUEST_ID_KEY);
+
+        poll
+    }
+}
+
+/// Assigns every request an identifier, or accepts the one in `X-Request-Id`.
+///
+/// The identifier is logged with every line written while handling the request, echoed in the
+/// response header and added to error response bodies.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RequestIdentity;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdentity
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequestIdentityMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdentityMiddleware { service }))
+    }
+}
+
+pub struct RequestIdentityMiddleware<S> {
+    service: S
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdentityMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(RequestId::parse)
+            .unwrap_or_default();
+
+        request.extensions_mut().insert(id.clone());
+
+        let header = HeaderValue::from_str(id.as_str()).ok();
+
+        log_mdc::insert(REQUEST_ID_KEY, id.as_str());
+
+        let future = self.service.call(request);
+
+        log_mdc::remove(REQUEST_ID_KEY);
+
+        Box::pin(WithRequestId {
+            id: id.clone(),
+            inner: Box::pin(async move {
+                let mut response = future.await?;
+
+                if let Some(header) = header {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), header);
+                }
+
+                let message = match response.response().error() {
+                    Some(error) => error.to_string(),
+                    None => return Ok(response.map_into_left_body())
+                };
+
+                let body = json!({
+                    "error": message,
+                    "request_id": id.as_str()
+                });
+
+                let response = response.map_body(|head, _| {
+                    head.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+                    EitherBody::right(BoxBody::new(body.to_string()))
+                });
+
+                Ok(response)
+            })
+        })
+    }
+}
@@ -0,0 +1,508 @@
+use std::fmt;
+use std::time::Duration;
+use actix_web::body::MessageBody;
+use actix_web::http::header::{HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, HOST, LAST_MODIFIED, RANGE};
+use actix_web::http::{Method, StatusCode, Uri};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use awc::{Client, ClientRequest};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::serialization::encode_hex;
+
+/// The SHA-256 digest of an empty payload.
+const EMPTY_PAYLOAD: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+type HmacSha256 = Hmac<Sha256>;
+
+thread_local! {
+    /// The client requests to the object storage are sent with, which keeps connections open between them.
+    ///
+    /// Clients cannot be shared between threads, so every worker has its own instead of the bucket holding one.
+    static CLIENT: Client = Client::builder()
+        .disable_redirects()
+        .finish();
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct S3Configuration {
+    /// The URL of the S3-compatible service, such as https://s3.eu-west-1.amazonaws.com or http://localhost:9000 for MinIO.
+    pub endpoint: String,
+
+    /// The bucket blobs are kept in.
+    pub bucket: String,
+
+    /// The region requests are signed for.
+    #[serde(default = "default_region")]
+    pub region: String,
+
+    /// The access key identifier.
+    pub access_key: String,
+
+    /// The secret access key.
+    pub secret_key: String,
+
+    /// Whether the bucket is part of the request path instead of the host name, as MinIO and most stand-ins expect.
+    #[serde(default)]
+    pub path_style: bool,
+
+    /// How many seconds a request to the service may take, including the transfer of the blob.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64
+}
+
+fn default_region() -> String {
+    String::from("us-east-1")
+}
+
+fn default_timeout() -> u64 {
+    300
+}
+
+#[derive(Debug)]
+pub enum S3Error {
+    InvalidEndpoint(String),
+    NotFound(String),
+    Request(String),
+    Status(StatusCode, String),
+    Body(String),
+    Deserialize(quick_xml::DeError)
+}
+
+impl fmt::Display for S3Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            S3Error::InvalidEndpoint(endpoint) => write!(formatter, "{} is not an http or https URL", endpoint),
+            S3Error::NotFound(key) => write!(formatter, "The object {} does not exist", key),
+            S3Error::Request(error) => write!(formatter, "Unable to reach the object storage: {}", error),
+            S3Error::Status(status, message) => write!(formatter, "The object storage responded with {}: {}", status, message),
+            S3Error::Body(error) => write!(formatter, "Unable to read a response from the object storage: {}", error),
+            S3Error::Deserialize(error) => write!(formatter, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for S3Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            S3Error::Deserialize(ref error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+/// An object in a bucket listing.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct S3Object {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: DateTime<Utc>
+}
+
+/// A page of the objects in a bucket.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(default)]
+    contents: Vec<S3Object>,
+
+    #[serde(default)]
+    is_truncated: bool,
+
+    next_continuation_token: Option<String>
+}
+
+/// Percent-encodes a value the way Signature Version 4 expects, optionally leaving slashes alone.
+fn uri_encode(value: &str, keep_slashes: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if keep_slashes => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte))
+        }
+    }
+
+    encoded
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Encodes and sorts query parameters into the canonical query string of Signature Version 4.
+fn canonical_query(query: &[(&str, &str)]) -> String {
+    let mut parameters: Vec<(String, String)> = query
+        .iter()
+        .map(|(name, value)| (uri_encode(name, false), uri_encode(value, false)))
+        .collect();
+
+    parameters.sort();
+
+    let parameters: Vec<String> = parameters
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+
+    parameters.join("&")
+}
+
+/// Lists the names of the signed headers, which must be lowercase and sorted.
+fn signed_headers(headers: &[(&str, &str)]) -> String {
+    let names: Vec<&str> = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect();
+
+    names.join(";")
+}
+
+/// Builds the canonical request, from already encoded parts and lowercase, sorted headers.
+fn canonical_request(method: &str, path: &str, query: &str, headers: &[(&str, &str)], payload: &str) -> String {
+    let lines: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+
+    format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, lines, signed_headers(headers), payload)
+}
+
+/// Builds the string that is signed, which binds the hashed canonical request to its time and scope.
+fn string_to_sign(timestamp: &str, scope: &str, canonical: &str) -> String {
+    format!("{}\n{}\n{}\n{}", ALGORITHM, timestamp, scope, encode_hex(&Sha256::digest(canonical.as_bytes())))
+}
+
+/// Derives the key requests are signed with from the secret access key, which is only valid for one day, region and service.
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret_key).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+
+    hmac(&key, "aws4_request")
+}
+
+/// A bucket in an S3-compatible object storage, accessed with requests signed by Signature Version 4.
+#[derive(Debug, Clone)]
+pub struct S3Bucket {
+    scheme: String,
+    authority: String,
+    configuration: S3Configuration
+}
+
+impl S3Bucket {
+    pub fn new(configuration: &S3Configuration) -> Result<Self, S3Error> {
+        let invalid = || S3Error::InvalidEndpoint(configuration.endpoint.clone());
+        let uri: Uri = configuration.endpoint.parse().map_err(|_| invalid())?;
+
+        let scheme = match uri.scheme_str() {
+            Some(value @ ("http" | "https")) => value.to_string(),
+            _ => return Err(invalid())
+        };
+
+        let authority = uri.authority().ok_or_else(invalid)?.to_string();
+
+        let bucket = S3Bucket {
+            scheme,
+            authority,
+            configuration: configuration.clone()
+        };
+
+        Ok(bucket)
+    }
+
+    /// How many seconds a request to the service may take.
+    pub fn timeout(&self) -> u64 {
+        self.configuration.timeout
+    }
+
+    fn host(&self) -> String {
+        match self.configuration.path_style {
+            true => self.authority.clone(),
+            false => format!("{}.{}", self.configuration.bucket, self.authority)
+        }
+    }
+
+    fn path(&self, key: &str) -> String {
+        match self.configuration.path_style {
+            true if key.is_empty() => format!("/{}", uri_encode(&self.configuration.bucket, false)),
+            true => format!("/{}/{}", uri_encode(&self.configuration.bucket, false), uri_encode(key, true)),
+            false => format!("/{}", uri_encode(key, true))
+        }
+    }
+
+    /// Builds a signed request for an object, or for the bucket itself if the key is empty.
+    fn request(&self, method: Method, key: &str, query: &[(&str, &str)], payload: &str) -> ClientRequest {
+        let now = Utc::now();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let host = self.host();
+        let path = self.path(key);
+
+        let query = canonical_query(query);
+        let headers = [("host", host.as_str()), ("x-amz-content-sha256", payload), ("x-amz-date", timestamp.as_str())];
+        let canonical = canonical_request(method.as_str(), &path, &query, &headers, payload);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.configuration.region);
+        let key = signing_key(&self.configuration.secret_key, &date, &self.configuration.region, "s3");
+        let signature = encode_hex(&hmac(&key, &string_to_sign(&timestamp, &scope, &canonical)));
+
+        let authorization = format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, self.configuration.access_key, scope, signed_headers(&headers), signature
+        );
+
+        let url = match query.is_empty() {
+            true => format!("{}://{}{}", self.scheme, host, path),
+            false => format!("{}://{}{}?{}", self.scheme, host, path, query)
+        };
+
+        CLIENT.with(|client| client.request(method, url))
+            .timeout(Duration::from_secs(self.configuration.timeout))
+            .insert_header((HOST, host))
+            .insert_header(("x-amz-content-sha256", payload))
+            .insert_header(("x-amz-date", timestamp))
+            .insert_header(("authorization", authorization))
+    }
+
+    /// Turns an unsuccessful response into an error that includes what the service said.
+    async fn check<S>(key: &str, mut response: awc::ClientResponse<S>) -> Result<awc::ClientResponse<S>, S3Error>
+    where S: futures_util::Stream<Item = Result<Bytes, awc::error::PayloadError>> + Unpin {
+        let status = response.status();
+
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        if status == StatusCode::NOT_FOUND {
+            return Err(S3Error::NotFound(key.to_string()));
+        }
+
+        let body = response.body().await.unwrap_or_default();
+
+        Err(S3Error::Status(status, String::from_utf8_lossy(&body).to_string()))
+    }
+
+    /// Makes sure that the bucket exists and the credentials grant access to it.
+    pub async fn check_bucket(&self) -> Result<(), S3Error> {
+        let response = self.request(Method::HEAD, "", &[], EMPTY_PAYLOAD)
+            .send()
+            .await
+            .map_err(|error| S3Error::Request(error.to_string()))?;
+
+        Self::check(&self.configuration.bucket, response).await?;
+
+        Ok(())
+    }
+
+    /// Uploads an object whose payload has the given hexadecimal SHA-256 digest.
+    pub async fn put_object<B>(&self, key: &str, body: B, digest: &str) -> Result<(), S3Error>
+    where B: MessageBody + 'static {
+        let response = self.request(Method::PUT, key, &[], digest)
+            .send_body(body)
+            .await
+            .map_err(|error| S3Error::Request(error.to_string()))?;
+
+        Self::check(key, response).await?;
+
+        Ok(())
+    }
+
+    /// Downloads an object into memory.
+    pub async fn get_object(&self, key: &str) -> Result<Bytes, S3Error> {
+        let response = self.request(Method::GET, key, &[], EMPTY_PAYLOAD)
+            .send()
+            .await
+            .map_err(|error| S3Error::Request(error.to_string()))?;
+
+        let mut response = Self::check(key, response).await?;
+
+        response
+            .body()
+            .limit(usize::MAX)
+            .await
+            .map_err(|error| S3Error::Body(error.to_string()))
+    }
+
+    /// Streams an object to a client, passing on any range it asked for so that downloads can resume.
+    pub async fn serve_object(&self, key: &str, request: &HttpRequest) -> Result<HttpResponse, S3Error> {
+        let mut upstream = self.request(Method::GET, key, &[], EMPTY_PAYLOAD).no_decompress();
+
+        if let Some(range) = request.headers().get(RANGE) {
+            upstream = upstream.insert_header((RANGE, range.clone()));
+        }
+
+        let response = upstream
+            .send()
+            .await
+            .map_err(|error| S3Error::Request(error.to_string()))?;
+
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(HttpResponse::RangeNotSatisfiable().finish());
+        }
+
+        let response = Self::check(key, response).await?;
+        let mut builder = HttpResponse::build(response.status());
+
+        for name in [CONTENT_LENGTH, CONTENT_RANGE, LAST_MODIFIED] {
+            if let Some(value) = response.headers().get(&name) {
+                builder.insert_header((name, value.clone()));
+            }
+        }
+
+        builder.insert_header((ACCEPT_RANGES, HeaderValue::from_static("bytes")));
+
+        Ok(builder.streaming(response))
+    }
+
+    /// Returns when an object was last written, or None if it does not exist.
+    pub async fn last_modified(&self, key: &str) -> Result<Option<DateTime<Utc>>, S3Error> {
+        let response = self.request(Method::HEAD, key, &[], EMPTY_PAYLOAD)
+            .send()
+            .await
+            .map_err(|error| S3Error::Request(error.to_string()))?;
+
+        let response = match Self::check(key, response).await {
+            Ok(value) => value,
+            Err(S3Error::NotFound(_)) => return Ok(None),
+            Err(error) => return Err(error)
+        };
+
+        let modified = response
+            .headers()
+            .get(LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+            .map(|value| value.with_timezone(&Utc));
+
+        Ok(modified)
+    }
+
+    /// Removes an object, succeeding if it does not exist.
+    pub async fn delete_object(&self, key: &str) -> Result<(), S3Error> {
+        let response = self.request(Method::DELETE, key, &[], EMPTY_PAYLOAD)
+            .send()
+            .await
+            .map_err(|error| S3Error::Request(error.to_string()))?;
+
+        match Self::check(key, response).await {
+            Ok(_) | Err(S3Error::NotFound(_)) => Ok(()),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Lists every object in the bucket.
+    pub async fn list_objects(&self) -> Result<Vec<S3Object>, S3Error> {
+        let mut objects = vec![];
+        let mut token: Option<String> = None;
+
+        loop {
+            let mut query = vec![("list-type", "2")];
+
+            if let Some(value) = &token {
+                query.push(("continuation-token", value));
+            }
+
+            let response = self.request(Method::GET, "", &query, EMPTY_PAYLOAD)
+                .send()
+                .await
+                .map_err(|error| S3Error::Request(error.to_string()))?;
+
+            let body = Self::check(&self.configuration.bucket, response)
+                .await?
+                .body()
+                .limit(usize::MAX)
+                .await
+                .map_err(|error| S3Error::Body(error.to_string()))?;
+
+            let page: ListBucketResult = quick_xml::de::from_str(&String::from_utf8_lossy(&body))
+                .map_err(S3Error::Deserialize)?;
+
+            objects.extend(page.contents);
+
+            match page.next_continuation_token {
+                Some(value) if page.is_truncated => token = Some(value),
+                _ => break
+            }
+        }
+
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::serialization::encode_hex;
+    use super::{canonical_query, canonical_request, hmac, signing_key, string_to_sign, EMPTY_PAYLOAD};
+
+    // The examples of the Amazon S3 documentation on signing requests with the Authorization header
+    const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+    const TIMESTAMP: &str = "20130524T000000Z";
+    const SCOPE: &str = "20130524/us-east-1/s3/aws4_request";
+
+    fn sign(path: &str, query: &[(&str, &str)], headers: &[(&str, &str)]) -> (String, String, String) {
+        let canonical = canonical_request("GET", path, &canonical_query(query), headers, EMPTY_PAYLOAD);
+        let signed = string_to_sign(TIMESTAMP, SCOPE, &canonical);
+        let key = signing_key(SECRET_KEY, "20130524", "us-east-1", "s3");
+        let signature = encode_hex(&hmac(&key, &signed));
+
+        (canonical, signed, signature)
+    }
+
+    #[test]
+    fn signing_key_matches_the_published_derivation() {
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+
+        assert_eq!(encode_hex(&key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+    }
+
+    #[test]
+    fn get_object_matches_the_published_example() {
+        let headers = [
+            ("host", "examplebucket.s3.amazonaws.com"),
+            ("range", "bytes=0-9"),
+            ("x-amz-content-sha256", EMPTY_PAYLOAD),
+            ("x-amz-date", TIMESTAMP)
+        ];
+
+        let (canonical, signed, signature) = sign("/test.txt", &[], &headers);
+
+        assert_eq!(canonical, format!(
+            "GET\n/test.txt\n\nhost:examplebucket.s3.amazonaws.com\nrange:bytes=0-9\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;range;x-amz-content-sha256;x-amz-date\n{}",
+            EMPTY_PAYLOAD, TIMESTAMP, EMPTY_PAYLOAD
+        ));
+        assert_eq!(signed, format!("AWS4-HMAC-SHA256\n{}\n{}\n7344ae5b7ee6c3e7e6b0fe0640412a37625d1fbfff95c48bbb2dc43964946972", TIMESTAMP, SCOPE));
+        assert_eq!(signature, "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41");
+    }
+
+    #[test]
+    fn query_parameters_match_the_published_examples() {
+        let headers = [
+            ("host", "examplebucket.s3.amazonaws.com"),
+            ("x-amz-content-sha256", EMPTY_PAYLOAD),
+            ("x-amz-date", TIMESTAMP)
+        ];
+
+        let (_, _, signature) = sign("/", &[("lifecycle", "")], &headers);
+        assert_eq!(signature, "fea454ca298b7da1c68078a5d1bdbfbbe0d65c699e0f91ac7a200a0136783543");
+
+        let (_, _, signature) = sign("/", &[("prefix", "J"), ("max-keys", "2")], &headers);
+        assert_eq!(signature, "34b48302e7b5fa45bde8084f4b7868a86f0a534bc59db6670ed5711ef69dc6f7");
+    }
+
+    #[test]
+    fn query_parameters_are_encoded_and_sorted() {
+        assert_eq!(canonical_query(&[]), "");
+        assert_eq!(canonical_query(&[("uploads", ""), ("prefix", "a b/c")]), "prefix=a%20b%2Fc&uploads=");
+    }
+}
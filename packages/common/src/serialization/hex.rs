@@ -0,0 +1,4 @@
+/// Encodes bytes as a lowercase hexadecimal string.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
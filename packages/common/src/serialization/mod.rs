@@ -0,0 +1,3 @@
+pub mod hex;
+
+pub use hex::*;
@@ -1,9 +1,200 @@
-use std::sync::Mutex;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use actix_web::rt::time::sleep;
+use actix_web::body::BoxBody;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
 
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CommonState {
+    /// The host name of this instance.
+    pub host: String,
+
     /// The number of seconds that this instance has been online.
-    pub uptime: Mutex<u64>
+    pub uptime: Mutex<u64>,
+
+    /// Whether this instance is shutting down and refusing new letters.
+    #[serde(skip)]
+    pub draining: AtomicBool,
+
+    /// The current maintenance window, during which new letters are refused.
+    #[serde(skip)]
+    pub maintenance: RwLock<Option<MaintenanceNotice>>,
+
+    /// The latest heartbeat of each background worker, by name.
+    #[serde(skip)]
+    pub heartbeats: RwLock<BTreeMap<String, Heartbeat>>,
+
+    /// The number of background passes currently running, which a shutdown waits for.
+    #[serde(skip)]
+    pub busy: AtomicUsize
+}
+
+/// Marks a background pass as running until it is dropped.
+pub struct Work<'a> {
+    state: &'a CommonState
+}
+
+impl Drop for Work<'_> {
+    fn drop(&mut self) {
+        self.state.busy.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Records that a background worker is still running.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Heartbeat {
+    /// When the worker last reported in.
+    pub at: DateTime<Utc>,
+
+    /// How many seconds may pass between heartbeats before the worker is considered stalled.
+    pub stale_after: u64
+}
+
+impl Heartbeat {
+    /// Returns true if the worker has not reported in time.
+    pub fn is_stale(&self) -> bool {
+        let elapsed = Utc::now().signed_duration_since(self.at).num_seconds();
+
+        elapsed > self.stale_after as i64
+    }
+}
+
+/// How many seconds clients are asked to wait before retrying while this instance is shutting down.
+pub const UNAVAILABLE_RETRY_AFTER: u64 = 60;
+
+/// Describes a maintenance window to clients.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MaintenanceNotice {
+    /// How many seconds clients are asked to wait before retrying.
+    pub retry_after: u64,
+
+    /// An optional explanation for clients.
+    pub message: Option<String>,
+
+    /// When maintenance started.
+    pub since: DateTime<Utc>
+}
+
+/// Why this instance is refusing to change any mail.
+#[derive(Debug, Clone)]
+pub enum Unavailable {
+    ShuttingDown,
+    Maintenance(MaintenanceNotice)
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Unavailable::ShuttingDown => {
+                write!(formatter, "This instance is shutting down")
+            },
+            Unavailable::Maintenance(notice) => match &notice.message {
+                Some(message) => write!(formatter, "This instance is under maintenance: {}", message),
+                None => write!(formatter, "This instance is under maintenance")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+impl ResponseError for Unavailable {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        let retry_after = match &self {
+            Unavailable::ShuttingDown => UNAVAILABLE_RETRY_AFTER,
+            Unavailable::Maintenance(notice) => notice.retry_after
+        };
+
+        HttpResponse::ServiceUnavailable()
+            .insert_header((RETRY_AFTER, retry_after.to_string()))
+            .body(self.to_string())
+    }
+}
+
+impl CommonState {
+    /// Stops accepting new letters ahead of a shutdown.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if this instance is shutting down.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Starts a background pass, or returns None once this instance is shutting down and the worker should stop.
+    ///
+    /// The pass is counted before draining is checked, so a shutdown that has started either
+    /// stops the worker here or waits for the pass in [`CommonState::idle`].
+    pub fn work(&self) -> Option<Work<'_>> {
+        self.busy.fetch_add(1, Ordering::SeqCst);
+
+        let work = Work { state: self };
+
+        if self.is_draining() {
+            return None;
+        }
+
+        Some(work)
+    }
+
+    /// Waits until no background pass is running.
+    pub async fn idle(&self) {
+        while self.busy.load(Ordering::SeqCst) > 0 {
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Returns the current maintenance window, if any.
+    pub fn maintenance(&self) -> Option<MaintenanceNotice> {
+        match self.maintenance.read() {
+            Ok(guard) => guard.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    /// Starts or ends a maintenance window.
+    pub fn set_maintenance(&self, notice: Option<MaintenanceNotice>) {
+        match self.maintenance.write() {
+            Ok(mut guard) => *guard = notice,
+            Err(poisoned) => *poisoned.into_inner() = notice
+        }
+    }
+
+    /// Records a heartbeat for a background worker that reports in at least every `stale_after` seconds.
+    pub fn beat(&self, worker: &str, stale_after: u64) {
+        let heartbeat = Heartbeat { at: Utc::now(), stale_after };
+
+        match self.heartbeats.write() {
+            Ok(mut guard) => guard.insert(worker.to_string(), heartbeat),
+            Err(poisoned) => poisoned.into_inner().insert(worker.to_string(), heartbeat)
+        };
+    }
+
+    /// Returns the latest heartbeat of every background worker.
+    pub fn heartbeats(&self) -> BTreeMap<String, Heartbeat> {
+        match self.heartbeats.read() {
+            Ok(guard) => guard.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    /// Returns an error if letters cannot currently be received, sent or deleted.
+    pub fn available(&self) -> Result<(), Unavailable> {
+        if self.is_draining() {
+            return Err(Unavailable::ShuttingDown);
+        }
+
+        match self.maintenance() {
+            Some(notice) => Err(Unavailable::Maintenance(notice)),
+            None => Ok(())
+        }
+    }
 }
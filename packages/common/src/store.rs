@@ -0,0 +1,954 @@
+use std::fmt;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, FileTimes};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use actix_files::NamedFile;
+use actix_web::body::SizedStream;
+use actix_web::rt::time::{interval, sleep};
+use actix_web::web::{block, Bytes, Data};
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures_util::future::{select, Either};
+use futures_util::stream::{unfold, Stream};
+use lazy_static::lazy_static;
+use log::{debug, error};
+use mobc_redis::redis::{RedisError, Script, AsyncCommands};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::database::redis::{get_connection, MobcPool, RedisDatabaseError};
+use crate::model::{Blob, Identifier};
+use crate::s3::{S3Bucket, S3Configuration, S3Error};
+use crate::serialization::encode_hex;
+use crate::state::CommonState;
+
+/// Takes references away from a blob, forgetting it entirely once none are left.
+const RELEASE_SCRIPT: &str = r"
+local count = redis.call('HINCRBY', KEYS[1], ARGV[1], -tonumber(ARGV[2]))
+if count <= 0 then
+    redis.call('HDEL', KEYS[1], ARGV[1])
+end
+return count
+";
+
+/// Adds references to a blob unless garbage collection is removing it, returning -1 in that case.
+const RETAIN_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[2]) == 1 then
+    return -1
+end
+return redis.call('HINCRBY', KEYS[1], ARGV[1], ARGV[2])
+";
+
+/// Marks a blob as being removed by garbage collection if it has no references, returning 1 if it was marked.
+const COLLECT_SCRIPT: &str = r"
+local count = tonumber(redis.call('HGET', KEYS[1], ARGV[1]) or '0')
+if count > 0 then
+    return 0
+end
+if redis.call('SET', KEYS[2], 1, 'NX', 'EX', ARGV[2]) then
+    return 1
+end
+return 0
+";
+
+/// The hash holding the number of references to each blob, keyed by digest.
+const BLOB_REFERENCES: &str = "BLOB_REFERENCES";
+
+/// The prefix of the keys that mark blobs garbage collection is removing from a bucket.
+const BLOB_COLLECTING: &str = "BLOB_COLLECTING";
+
+/// How long a blob waits between attempts to gain references while it is being removed, in milliseconds.
+const RETAIN_RETRY: u64 = 100;
+
+const TEMPORARY: &str = "tmp";
+const COLLECTING: &str = "collecting";
+
+/// The name the blob garbage collector reports its heartbeat under.
+const COLLECTOR: &str = "blob-gc";
+
+/// How much of a file is hashed at once.
+const READ_BUFFER_SIZE: usize = 1048576;
+
+lazy_static! {
+    static ref RELEASE: Script = Script::new(RELEASE_SCRIPT);
+    static ref RETAIN: Script = Script::new(RETAIN_SCRIPT);
+    static ref COLLECT: Script = Script::new(COLLECT_SCRIPT);
+}
+
+#[derive(Debug)]
+pub enum BlobStoreError {
+    NotFound(String),
+    InvalidDigest(String),
+    Io(io::Error),
+    S3(S3Error),
+    CreateRedisConnection(RedisDatabaseError),
+    Query(RedisError)
+}
+
+impl fmt::Display for BlobStoreError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlobStoreError::NotFound(digest) => write!(formatter, "No blob with the digest {} exists", digest),
+            BlobStoreError::InvalidDigest(digest) => write!(formatter, "{} is not a SHA-256 digest", digest),
+            BlobStoreError::Io(error) => write!(formatter, "{}", error),
+            BlobStoreError::S3(error) => write!(formatter, "{}", error),
+            BlobStoreError::CreateRedisConnection(error) => write!(formatter, "{}", error),
+            BlobStoreError::Query(error) => write!(formatter, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for BlobStoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            BlobStoreError::Io(ref error) => Some(error),
+            BlobStoreError::S3(ref error) => Some(error),
+            BlobStoreError::CreateRedisConnection(ref error) => Some(error),
+            BlobStoreError::Query(ref error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+/// The outcome of a garbage collection pass.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GarbageCollection {
+    /// The number of blobs that were looked at.
+    pub scanned: usize,
+
+    /// The number of unreferenced blobs that were removed.
+    pub removed: usize,
+
+    /// The number of bytes that were freed.
+    pub bytes: u64
+}
+
+/// Runs blocking file system work on the thread pool reserved for it.
+pub async fn blocking<F, R>(work: F) -> io::Result<R>
+where F: FnOnce() -> io::Result<R> + Send + 'static, R: Send + 'static {
+    block(work)
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Returns the hexadecimal SHA-256 digest of a file.
+pub fn digest_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => hasher.update(&buffer[..read])
+        }
+    }
+
+    Ok(encode_hex(&hasher.finalize()))
+}
+
+/// Returns true if a value looks like a digest produced by this store.
+fn is_digest(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|character| matches!(character, '0'..='9' | 'a'..='f'))
+}
+
+/// Marks a blob as recently used so that garbage collection leaves it alone for a while.
+fn touch(path: &Path) -> io::Result<()> {
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_times(FileTimes::new().set_modified(SystemTime::now()))
+}
+
+/// Reads a file in large chunks without blocking the server.
+fn read_chunks(file: File) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    unfold(Some(file), |state| async move {
+        let mut file = state?;
+
+        let result = blocking(move || {
+            let mut buffer = vec![0; READ_BUFFER_SIZE];
+            let read = file.read(&mut buffer)?;
+
+            buffer.truncate(read);
+
+            Ok((buffer, file))
+        })
+        .await;
+
+        match result {
+            Ok((buffer, _)) if buffer.is_empty() => None,
+            Ok((buffer, file)) => Some((Ok(Bytes::from(buffer)), Some(file))),
+            Err(error) => Some((Err(error), None))
+        }
+    })
+}
+
+/// Where the blobs of a store are kept.
+#[derive(Debug, Clone)]
+enum Backend {
+    /// A directory on the local file system.
+    Filesystem(PathBuf),
+
+    /// A bucket in an S3-compatible object storage.
+    S3(S3Bucket)
+}
+
+/// Stores blobs under the SHA-256 digest of their contents, so identical ciphertext is only kept once.
+///
+/// Redis counts the references to each blob. Blobs without references are removed by garbage
+/// collection once they are older than the grace period, which protects blobs that are being
+/// stored while a pass runs.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    backend: Backend,
+    grace: Duration
+}
+
+impl BlobStore {
+    /// Opens a blob store in a directory, creating it if necessary.
+    pub fn new(directory: &Path, grace: u64) -> io::Result<Self> {
+        create_dir_all(directory.join(TEMPORARY))?;
+
+        let store = BlobStore {
+            backend: Backend::Filesystem(directory.to_path_buf()),
+            grace: Duration::from_secs(grace)
+        };
+
+        Ok(store)
+    }
+
+    /// Opens a blob store in a bucket of an S3-compatible object storage.
+    pub fn s3(configuration: &S3Configuration, grace: u64) -> Result<Self, BlobStoreError> {
+        let store = BlobStore {
+            backend: Backend::S3(S3Bucket::new(configuration).map_err(BlobStoreError::S3)?),
+            grace: Duration::from_secs(grace)
+        };
+
+        Ok(store)
+    }
+
+    /// The name of the blob with the given digest, relative to the root of the store.
+    fn key(digest: &str) -> String {
+        format!("{}/{}", &digest[..2], digest)
+    }
+
+    /// Returns true if something last written at the given time is older than the grace period.
+    fn is_expired(&self, modified: DateTime<Utc>) -> bool {
+        Utc::now()
+            .signed_duration_since(modified)
+            .to_std()
+            .map(|age| age > self.grace)
+            .unwrap_or(false)
+    }
+
+    /// The key marking a blob that garbage collection is removing.
+    fn collecting_key(digest: &str) -> String {
+        format!("{}:{}", BLOB_COLLECTING, digest)
+    }
+
+    /// Adds references to a blob, returning the number of references it now has.
+    ///
+    /// Waits while garbage collection is removing the blob, so that it is written again afterwards
+    /// instead of being removed after it was referenced.
+    async fn retain(&self, pool: &MobcPool, digest: &str, references: u64) -> Result<u64, BlobStoreError> {
+        let mut connection = get_connection(pool)
+            .await
+            .map_err(BlobStoreError::CreateRedisConnection)?;
+
+        loop {
+            let count: i64 = RETAIN
+                .key(BLOB_REFERENCES)
+                .key(Self::collecting_key(digest))
+                .arg(digest)
+                .arg(references)
+                .invoke_async(&mut *connection)
+                .await
+                .map_err(BlobStoreError::Query)?;
+
+            if count >= 0 {
+                return Ok(count as u64);
+            }
+
+            sleep(Duration::from_millis(RETAIN_RETRY)).await;
+        }
+    }
+
+    /// Takes references away from a blob, leaving it for garbage collection once none are left.
+    pub async fn release(&self, pool: &MobcPool, digest: &str, references: u64) -> Result<(), BlobStoreError> {
+        let mut connection = get_connection(pool)
+            .await
+            .map_err(BlobStoreError::CreateRedisConnection)?;
+
+        RELEASE
+            .key(BLOB_REFERENCES)
+            .arg(digest)
+            .arg(references)
+            .invoke_async::<_, i64>(&mut *connection)
+            .await
+            .map_err(BlobStoreError::Query)?;
+
+        Ok(())
+    }
+
+    /// Returns the number of references to a blob.
+    async fn references(&self, pool: &MobcPool, digest: &str) -> Result<u64, BlobStoreError> {
+        let mut connection = get_connection(pool)
+            .await
+            .map_err(BlobStoreError::CreateRedisConnection)?;
+
+        let count: Option<i64> = connection
+            .hget(BLOB_REFERENCES, digest)
+            .await
+            .map_err(BlobStoreError::Query)?;
+
+        Ok(count.unwrap_or(0).max(0) as u64)
+    }
+
+    /// Stores data with the given number of references, returning its digest.
+    pub async fn put(&self, pool: &MobcPool, data: Blob, references: u64) -> Result<String, BlobStoreError> {
+        let digest = encode_hex(&Sha256::digest(data.as_ref()));
+
+        self.retain(pool, &digest, references).await?;
+
+        let result = match &self.backend {
+            Backend::Filesystem(directory) => {
+                let path = directory.join(Self::key(&digest));
+                let temporary = directory.join(TEMPORARY).join(Identifier::new().to_string());
+
+                blocking(move || {
+                    // A blob that is being collected is missing until it is put back, so it is written again
+                    if path.exists() && touch(&path).is_ok() {
+                        return Ok(());
+                    }
+
+                    create_dir_all(path.parent().unwrap_or(&path))?;
+
+                    let mut file = File::create(&temporary)?;
+
+                    file.write_all(data.as_ref())?;
+                    file.sync_all()?;
+
+                    rename(temporary, path)
+                })
+                .await
+                .map_err(BlobStoreError::Io)
+            },
+            // Writing the object again refreshes its modification time, which keeps garbage collection away
+            Backend::S3(bucket) => {
+                bucket.put_object(&Self::key(&digest), Bytes::from(data), &digest)
+                    .await
+                    .map_err(BlobStoreError::S3)
+            }
+        };
+
+        if let Err(error) = result {
+            self.release(pool, &digest, references).await?;
+
+            return Err(error);
+        }
+
+        Ok(digest)
+    }
+
+    /// Moves a file into the store with the given number of references.
+    ///
+    /// The digest must be the SHA-256 digest of the file, as verified by the caller. For a store
+    /// on the local file system, the file has to be on the same file system.
+    pub async fn put_file(&self, pool: &MobcPool, source: PathBuf, digest: &str, references: u64) -> Result<(), BlobStoreError> {
+        if !is_digest(digest) {
+            return Err(BlobStoreError::InvalidDigest(digest.to_string()));
+        }
+
+        self.retain(pool, digest, references).await?;
+
+        let result = match &self.backend {
+            Backend::Filesystem(directory) => {
+                let path = directory.join(Self::key(digest));
+
+                blocking(move || {
+                    if path.exists() && touch(&path).is_ok() {
+                        return remove_file(source);
+                    }
+
+                    create_dir_all(path.parent().unwrap_or(&path))?;
+
+                    rename(source, path)
+                })
+                .await
+                .map_err(BlobStoreError::Io)
+            },
+            Backend::S3(bucket) => self.upload_file(bucket, source, digest).await
+        };
+
+        if let Err(error) = result {
+            self.release(pool, digest, references).await?;
+
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Streams a file into a bucket, removing it once it has been stored.
+    async fn upload_file(&self, bucket: &S3Bucket, source: PathBuf, digest: &str) -> Result<(), BlobStoreError> {
+        let opened = source.clone();
+        let (file, size) = blocking(move || {
+            let file = File::open(opened)?;
+            let size = file.metadata()?.len();
+
+            Ok((file, size))
+        })
+        .await
+        .map_err(BlobStoreError::Io)?;
+
+        bucket.put_object(&Self::key(digest), SizedStream::new(size, Box::pin(read_chunks(file))), digest)
+            .await
+            .map_err(BlobStoreError::S3)?;
+
+        blocking(move || remove_file(source))
+            .await
+            .map_err(BlobStoreError::Io)
+    }
+
+    /// Reads a blob into memory.
+    pub async fn get(&self, digest: &str) -> Result<Blob, BlobStoreError> {
+        if !is_digest(digest) {
+            return Err(BlobStoreError::InvalidDigest(digest.to_string()));
+        }
+
+        match &self.backend {
+            Backend::Filesystem(directory) => {
+                let path = directory.join(Self::key(digest));
+
+                match blocking(move || std::fs::read(path)).await {
+                    Ok(data) => Ok(Blob::from(data)),
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => Err(BlobStoreError::NotFound(digest.to_string())),
+                    Err(error) => Err(BlobStoreError::Io(error))
+                }
+            },
+            Backend::S3(bucket) => {
+                match bucket.get_object(&Self::key(digest)).await {
+                    Ok(data) => Ok(Blob::from(data)),
+                    Err(S3Error::NotFound(_)) => Err(BlobStoreError::NotFound(digest.to_string())),
+                    Err(error) => Err(BlobStoreError::S3(error))
+                }
+            }
+        }
+    }
+
+    /// Responds to a request with the contents of a blob, honoring range requests so downloads can resume.
+    pub async fn serve(&self, request: &HttpRequest, digest: &str) -> Result<HttpResponse, BlobStoreError> {
+        if !is_digest(digest) {
+            return Err(BlobStoreError::InvalidDigest(digest.to_string()));
+        }
+
+        match &self.backend {
+            Backend::Filesystem(directory) => {
+                let file = NamedFile::open_async(directory.join(Self::key(digest)))
+                    .await
+                    .map_err(|error| match error.kind() {
+                        io::ErrorKind::NotFound => BlobStoreError::NotFound(digest.to_string()),
+                        _ => BlobStoreError::Io(error)
+                    })?
+                    .disable_content_disposition()
+                    .use_last_modified(false);
+
+                Ok(file.into_response(request))
+            },
+            Backend::S3(bucket) => {
+                bucket.serve_object(&Self::key(digest), request)
+                    .await
+                    .map_err(|error| match error {
+                        S3Error::NotFound(_) => BlobStoreError::NotFound(digest.to_string()),
+                        _ => BlobStoreError::S3(error)
+                    })
+            }
+        }
+    }
+
+    /// Returns every blob file with its size, along with whether it is older than the grace period.
+    fn list_files(&self, directory: &Path) -> io::Result<Vec<(String, PathBuf, u64, bool)>> {
+        let now = SystemTime::now();
+        let mut blobs = vec![];
+
+        for shard in read_dir(directory)? {
+            let shard = shard?;
+
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+
+            let temporary = shard.file_name() == TEMPORARY;
+
+            for entry in read_dir(shard.path())? {
+                let entry = entry?;
+                let path = entry.path();
+                let metadata = entry.metadata()?;
+                let expired = now
+                    .duration_since(metadata.modified()?)
+                    .map(|age| age > self.grace)
+                    .unwrap_or(false);
+
+                // Leftovers from an interrupted write or pass are cleaned up here
+                if temporary {
+                    if expired {
+                        remove_file(&path)?;
+                    }
+
+                    continue;
+                }
+
+                if path.extension().is_some_and(|extension| extension == COLLECTING) {
+                    rename(&path, path.with_extension(""))?;
+
+                    continue;
+                }
+
+                let name = entry.file_name().to_string_lossy().to_string();
+
+                if is_digest(&name) {
+                    blobs.push((name, path, metadata.len(), expired));
+                }
+            }
+        }
+
+        Ok(blobs)
+    }
+
+    /// Removes every blob that has no references and is older than the grace period.
+    pub async fn collect_garbage(&self, pool: &MobcPool) -> Result<GarbageCollection, BlobStoreError> {
+        match &self.backend {
+            Backend::Filesystem(directory) => self.collect_files(pool, directory).await,
+            Backend::S3(bucket) => self.collect_objects(pool, bucket).await
+        }
+    }
+
+    /// Collects the blobs in a directory.
+    ///
+    /// A candidate is moved aside before its references are checked again, so a blob that gains a
+    /// reference while a pass runs is put back instead of being lost.
+    async fn collect_files(&self, pool: &MobcPool, directory: &Path) -> Result<GarbageCollection, BlobStoreError> {
+        let store = self.clone();
+        let listed = directory.to_path_buf();
+        let blobs = blocking(move || store.list_files(&listed))
+            .await
+            .map_err(BlobStoreError::Io)?;
+
+        let mut collection = GarbageCollection {
+            scanned: blobs.len(),
+            ..Default::default()
+        };
+
+        for (digest, path, size, expired) in blobs {
+            if !expired || self.references(pool, &digest).await? > 0 {
+                continue;
+            }
+
+            let collecting = path.with_extension(COLLECTING);
+            let moved = collecting.clone();
+
+            blocking(move || rename(path, moved))
+                .await
+                .map_err(BlobStoreError::Io)?;
+
+            if self.finish_collecting_file(pool, directory, &digest, collecting).await? {
+                collection.removed += 1;
+                collection.bytes += size;
+            }
+        }
+
+        Ok(collection)
+    }
+
+    /// Removes a blob file that was moved aside for collection, or puts it back if the blob gained
+    /// references in the meantime. Returns true if the blob was removed.
+    async fn finish_collecting_file(&self, pool: &MobcPool, directory: &Path, digest: &str, collecting: PathBuf) -> Result<bool, BlobStoreError> {
+        let restore = self.references(pool, digest).await? > 0;
+        let path = directory.join(Self::key(digest));
+
+        blocking(move || match restore {
+            true => rename(collecting, path),
+            false => remove_file(collecting)
+        })
+        .await
+        .map_err(BlobStoreError::Io)?;
+
+        Ok(!restore)
+    }
+
+    /// Marks a blob without references as being collected, so that nothing can reference it until
+    /// it is gone. Returns false if the blob has references or is already being collected.
+    async fn start_collecting(&self, pool: &MobcPool, digest: &str, expiry: u64) -> Result<bool, BlobStoreError> {
+        let mut connection = get_connection(pool)
+            .await
+            .map_err(BlobStoreError::CreateRedisConnection)?;
+
+        COLLECT
+            .key(BLOB_REFERENCES)
+            .key(Self::collecting_key(digest))
+            .arg(digest)
+            .arg(expiry)
+            .invoke_async(&mut *connection)
+            .await
+            .map_err(BlobStoreError::Query)
+    }
+
+    /// Lets blobs that were marked as being collected gain references again.
+    async fn stop_collecting(&self, pool: &MobcPool, digest: &str) -> Result<(), BlobStoreError> {
+        let mut connection = get_connection(pool)
+            .await
+            .map_err(BlobStoreError::CreateRedisConnection)?;
+
+        connection
+            .del::<_, ()>(Self::collecting_key(digest))
+            .await
+            .map_err(BlobStoreError::Query)
+    }
+
+    /// Removes an object that is marked as being collected if it has not been written since the grace period.
+    async fn delete_expired(&self, bucket: &S3Bucket, key: &str) -> Result<bool, S3Error> {
+        let modified = bucket.last_modified(key).await?;
+
+        if !modified.is_some_and(|value| self.is_expired(value)) {
+            return Ok(false);
+        }
+
+        bucket.delete_object(key).await?;
+
+        Ok(true)
+    }
+
+    /// Collects the blobs in a bucket.
+    ///
+    /// Objects cannot be moved aside, so a candidate is marked in Redis before it is removed. Blobs
+    /// that are stored again while the mark is set wait for it to be lifted, which happens once the
+    /// object is gone or after three request timeouts if the pass is interrupted.
+    async fn collect_objects(&self, pool: &MobcPool, bucket: &S3Bucket) -> Result<GarbageCollection, BlobStoreError> {
+        let objects = bucket.list_objects()
+            .await
+            .map_err(BlobStoreError::S3)?;
+
+        let mut collection = GarbageCollection::default();
+
+        for object in objects {
+            let digest = object.key.rsplit('/').next().unwrap_or_default().to_string();
+
+            if !is_digest(&digest) || object.key != Self::key(&digest) {
+                continue;
+            }
+
+            collection.scanned += 1;
+
+            if !self.is_expired(object.last_modified) || self.references(pool, &digest).await? > 0 {
+                continue;
+            }
+
+            if !self.start_collecting(pool, &digest, bucket.timeout() * 3).await? {
+                continue;
+            }
+
+            let deleted = self.delete_expired(bucket, &object.key).await;
+
+            self.stop_collecting(pool, &digest).await?;
+
+            if deleted.map_err(BlobStoreError::S3)? {
+                collection.removed += 1;
+                collection.bytes += object.size;
+            }
+        }
+
+        Ok(collection)
+    }
+}
+
+/// Reports the heartbeat of the garbage collector every interval for as long as a pass runs, since
+/// a pass over a large bucket can take longer than the interval.
+async fn beat_while_collecting(state: &CommonState, every: u64) {
+    let mut timer = interval(Duration::from_secs(every));
+
+    loop {
+        timer.tick().await;
+        state.beat(COLLECTOR, every * 2);
+    }
+}
+
+/// Removes unreferenced blobs every interval until the server starts shutting down.
+pub async fn sweep_blobs(store: BlobStore, pool: Data<MobcPool>, state: Data<CommonState>, every: u64) {
+    let mut timer = interval(Duration::from_secs(every));
+
+    loop {
+        timer.tick().await;
+
+        let _work = match state.work() {
+            Some(value) => value,
+            None => return
+        };
+
+        let pass = select(Box::pin(store.collect_garbage(&pool)), Box::pin(beat_while_collecting(&state, every))).await;
+        let result = match pass {
+            Either::Left((value, _)) => value,
+            Either::Right(_) => continue
+        };
+
+        match result {
+            Ok(collection) if collection.removed == 0 => (),
+            Ok(collection) => debug!("Removed {} unreferenced blobs, freeing {} bytes", collection.removed, collection.bytes),
+            Err(error) => error!("Unable to collect unreferenced blobs: {}", error)
+        }
+
+        state.beat(COLLECTOR, every * 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File, FileTimes};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use actix_web::rt::time::timeout;
+    use sha2::{Digest, Sha256};
+    use tempfile::{tempdir, TempDir};
+
+    use crate::database::redis::{create_pool, MobcPool};
+    use crate::model::Blob;
+    use crate::serialization::encode_hex;
+    use super::{digest_file, is_digest, BlobStore, BlobStoreError, COLLECTING, TEMPORARY};
+
+    /// How many seconds blobs are protected from garbage collection in these tests.
+    const GRACE: u64 = 60;
+
+    /// Connects to the Redis database named by `REDIS_URL`, which the tests that count references need.
+    ///
+    /// They write to the database, so point it at a scratch one such as redis://localhost/15.
+    fn redis() -> Option<MobcPool> {
+        match std::env::var("REDIS_URL") {
+            Ok(url) => Some(create_pool(url.as_str()).expect("REDIS_URL is a valid Redis URL")),
+            Err(_) => {
+                eprintln!("Skipping a test that needs Redis, set REDIS_URL to run it");
+                None
+            }
+        }
+    }
+
+    fn store() -> (TempDir, BlobStore) {
+        let directory = tempdir().unwrap();
+        let store = BlobStore::new(directory.path(), GRACE).unwrap();
+
+        (directory, store)
+    }
+
+    /// Random contents, so that tests sharing a database never count references to the same blob.
+    fn contents() -> Vec<u8> {
+        rand::random::<[u8; 32]>().to_vec()
+    }
+
+    /// Makes a file look like it was last written well before the grace period.
+    fn age(path: &Path) {
+        let modified = SystemTime::now() - Duration::from_secs(GRACE * 10);
+
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(modified))
+            .unwrap();
+    }
+
+    fn blob_path(directory: &TempDir, digest: &str) -> std::path::PathBuf {
+        directory.path().join(BlobStore::key(digest))
+    }
+
+    #[test]
+    fn digests_are_lowercase_hexadecimal_sha256() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("abc");
+
+        fs::write(&path, b"abc").unwrap();
+
+        let digest = digest_file(&path).unwrap();
+
+        assert_eq!(digest, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert!(is_digest(&digest));
+        assert!(!is_digest(&digest.to_uppercase()));
+        assert!(!is_digest(&digest[1..]));
+        assert!(!is_digest("../../etc/passwd"));
+    }
+
+    #[test]
+    fn listing_cleans_up_after_interrupted_writes_and_passes() {
+        let (directory, store) = store();
+        let fresh = encode_hex(&Sha256::digest(b"fresh"));
+        let old = encode_hex(&Sha256::digest(b"old"));
+        let interrupted = encode_hex(&Sha256::digest(b"interrupted"));
+
+        for digest in [&fresh, &old, &interrupted] {
+            fs::create_dir_all(blob_path(&directory, digest).parent().unwrap()).unwrap();
+        }
+
+        fs::write(blob_path(&directory, &fresh), b"fresh").unwrap();
+        fs::write(blob_path(&directory, &old), b"old").unwrap();
+        age(&blob_path(&directory, &old));
+
+        // A pass that stopped after moving a blob aside must not lose it
+        let collecting = blob_path(&directory, &interrupted).with_extension(COLLECTING);
+        fs::write(&collecting, b"interrupted").unwrap();
+
+        let temporary = directory.path().join(TEMPORARY);
+        fs::write(temporary.join("written"), b"partial").unwrap();
+        fs::write(temporary.join("abandoned"), b"partial").unwrap();
+        age(&temporary.join("abandoned"));
+
+        fs::write(blob_path(&directory, &old).with_file_name("notes.txt"), b"not a blob").unwrap();
+
+        let mut blobs = store.list_files(directory.path()).unwrap();
+        blobs.sort();
+
+        let mut expected = vec![(fresh.clone(), false), (old.clone(), true)];
+        expected.sort();
+
+        let listed: Vec<(String, bool)> = blobs
+            .into_iter()
+            .map(|(digest, _, _, expired)| (digest, expired))
+            .collect();
+
+        assert_eq!(listed, expected);
+        assert!(!collecting.exists());
+        assert_eq!(fs::read(blob_path(&directory, &interrupted)).unwrap(), b"interrupted");
+        assert!(temporary.join("written").exists());
+        assert!(!temporary.join("abandoned").exists());
+    }
+
+    #[actix_web::test]
+    async fn blobs_are_read_back_by_digest() {
+        let (directory, store) = store();
+        let digest = encode_hex(&Sha256::digest(b"missing"));
+
+        assert!(matches!(store.get(&digest).await, Err(BlobStoreError::NotFound(_))));
+        assert!(matches!(store.get("../secret").await, Err(BlobStoreError::InvalidDigest(_))));
+
+        fs::create_dir_all(blob_path(&directory, &digest).parent().unwrap()).unwrap();
+        fs::write(blob_path(&directory, &digest), b"missing").unwrap();
+
+        assert_eq!(store.get(&digest).await.unwrap().as_ref(), b"missing");
+    }
+
+    #[actix_web::test]
+    async fn putting_an_existing_blob_adds_references_to_one_copy() {
+        let Some(pool) = redis() else { return };
+        let (directory, store) = store();
+        let data = contents();
+
+        let digest = store.put(&pool, Blob::from(data.clone()), 1).await.unwrap();
+        let path = blob_path(&directory, &digest);
+
+        age(&path);
+
+        assert_eq!(store.put(&pool, Blob::from(data.clone()), 2).await.unwrap(), digest);
+        assert_eq!(store.references(&pool, &digest).await.unwrap(), 3);
+        assert_eq!(store.get(&digest).await.unwrap().as_ref(), data.as_slice());
+
+        // Storing it again refreshes it, so a pass does not remove it right after it lost its references
+        assert!(!store.is_expired(fs::metadata(&path).unwrap().modified().unwrap().into()));
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+        assert_eq!(fs::read_dir(directory.path().join(TEMPORARY)).unwrap().count(), 0);
+
+        store.release(&pool, &digest, 3).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn blobs_released_to_zero_are_collected() {
+        let Some(pool) = redis() else { return };
+        let (directory, store) = store();
+        let data = contents();
+
+        let digest = store.put(&pool, Blob::from(data.clone()), 2).await.unwrap();
+        let path = blob_path(&directory, &digest);
+
+        age(&path);
+        store.release(&pool, &digest, 1).await.unwrap();
+
+        let collection = store.collect_garbage(&pool).await.unwrap();
+
+        assert_eq!((collection.scanned, collection.removed), (1, 0));
+        assert!(path.exists());
+
+        store.release(&pool, &digest, 1).await.unwrap();
+
+        assert_eq!(store.references(&pool, &digest).await.unwrap(), 0);
+
+        let collection = store.collect_garbage(&pool).await.unwrap();
+
+        assert_eq!((collection.scanned, collection.removed, collection.bytes), (1, 1, data.len() as u64));
+        assert!(!path.exists());
+        assert!(matches!(store.get(&digest).await, Err(BlobStoreError::NotFound(_))));
+    }
+
+    #[actix_web::test]
+    async fn unreferenced_blobs_within_the_grace_period_are_kept() {
+        let Some(pool) = redis() else { return };
+        let (directory, store) = store();
+
+        let digest = store.put(&pool, Blob::from(contents()), 1).await.unwrap();
+
+        store.release(&pool, &digest, 1).await.unwrap();
+
+        let collection = store.collect_garbage(&pool).await.unwrap();
+
+        assert_eq!(collection.removed, 0);
+        assert!(blob_path(&directory, &digest).exists());
+    }
+
+    #[actix_web::test]
+    async fn retaining_during_collection_restores_the_blob() {
+        let Some(pool) = redis() else { return };
+        let (directory, store) = store();
+        let data = contents();
+
+        let digest = store.put(&pool, Blob::from(data.clone()), 1).await.unwrap();
+        let path = blob_path(&directory, &digest);
+
+        store.release(&pool, &digest, 1).await.unwrap();
+
+        // The blob is referenced again after a pass moved it aside but before it was removed
+        let collecting = path.with_extension(COLLECTING);
+        fs::rename(&path, &collecting).unwrap();
+
+        assert_eq!(store.retain(&pool, &digest, 1).await.unwrap(), 1);
+        assert!(!store.finish_collecting_file(&pool, directory.path(), &digest, collecting.clone()).await.unwrap());
+        assert!(!collecting.exists());
+        assert_eq!(store.get(&digest).await.unwrap().as_ref(), data.as_slice());
+
+        // Without the reference, the same step removes it
+        store.release(&pool, &digest, 1).await.unwrap();
+        fs::rename(&path, &collecting).unwrap();
+
+        assert!(store.finish_collecting_file(&pool, directory.path(), &digest, collecting.clone()).await.unwrap());
+        assert!(!collecting.exists());
+        assert!(!path.exists());
+    }
+
+    #[actix_web::test]
+    async fn retaining_waits_while_a_blob_is_being_removed() {
+        let Some(pool) = redis() else { return };
+        let (_directory, store) = store();
+        let referenced = encode_hex(&Sha256::digest(contents()));
+        let digest = encode_hex(&Sha256::digest(contents()));
+
+        store.retain(&pool, &referenced, 1).await.unwrap();
+
+        assert!(!store.start_collecting(&pool, &referenced, GRACE).await.unwrap());
+        assert!(store.start_collecting(&pool, &digest, GRACE).await.unwrap());
+        assert!(!store.start_collecting(&pool, &digest, GRACE).await.unwrap());
+
+        assert!(timeout(Duration::from_millis(500), store.retain(&pool, &digest, 1)).await.is_err());
+        assert_eq!(store.references(&pool, &digest).await.unwrap(), 0);
+
+        store.stop_collecting(&pool, &digest).await.unwrap();
+
+        assert_eq!(store.retain(&pool, &digest, 1).await.unwrap(), 1);
+
+        store.release(&pool, &referenced, 1).await.unwrap();
+        store.release(&pool, &digest, 1).await.unwrap();
+    }
+}
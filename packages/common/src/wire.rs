@@ -0,0 +1,410 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use actix_web::body::BoxBody;
+use actix_web::dev::Payload;
+use actix_web::error::PayloadError;
+use actix_web::http::header::{self, Accept, Header, VARY};
+use actix_web::web::BytesMut;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use futures_util::{FutureExt, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The body limit used when no [`WireConfig`] is registered, the same as the actix JSON default.
+const DEFAULT_LIMIT: usize = 2_097_152;
+
+static DEFAULT_CONFIG: WireConfig = WireConfig {
+    limit: DEFAULT_LIMIT,
+    limiter: None,
+    error_handler: None
+};
+
+/// A format request and response bodies can be exchanged in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Cbor,
+    MessagePack
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Json => write!(formatter, "JSON"),
+            Format::Cbor => write!(formatter, "CBOR"),
+            Format::MessagePack => write!(formatter, "MessagePack")
+        }
+    }
+}
+
+impl Format {
+    /// Every supported format, most compact first.
+    pub const ALL: [Format; 3] = [Format::Cbor, Format::MessagePack, Format::Json];
+
+    /// Returns the media type bodies in this format are labelled with.
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Cbor => "application/cbor",
+            Format::MessagePack => "application/msgpack"
+        }
+    }
+
+    /// Returns the media types of every supported format, as advertised to other instances.
+    pub fn media_types() -> Vec<String> {
+        Format::ALL
+            .iter()
+            .map(|format| format.media_type().to_string())
+            .collect()
+    }
+
+    /// Parses a media type, ignoring any parameters and the older MessagePack spellings.
+    pub fn from_media_type(value: &str) -> Option<Format> {
+        let essence = value
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        match essence.as_str() {
+            "application/json" => Some(Format::Json),
+            "application/cbor" => Some(Format::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Format::MessagePack),
+            _ => None
+        }
+    }
+
+    /// Returns the format of a message body, treating a missing content type as JSON.
+    pub fn from_content_type<M: HttpMessage>(message: &M) -> Result<Format, WireError> {
+        let content_type = message.content_type();
+
+        if content_type.is_empty() {
+            return Ok(Format::Json);
+        }
+
+        Format::from_media_type(content_type)
+            .ok_or_else(|| WireError::UnsupportedMediaType(content_type.to_string()))
+    }
+
+    /// Picks the response format a client ranks highest, falling back to JSON.
+    pub fn negotiate(request: &HttpRequest) -> Format {
+        let accept = match Accept::parse(request) {
+            Ok(value) => value,
+            Err(_) => return Format::Json
+        };
+
+        for mime in accept.ranked() {
+            if let Some(format) = Format::from_media_type(mime.essence_str()) {
+                return format;
+            }
+
+            if mime.essence_str() == "*/*" || mime.essence_str() == "application/*" {
+                return Format::Json;
+            }
+        }
+
+        Format::Json
+    }
+
+    /// Picks the most compact format out of the media types another instance advertises.
+    pub fn preferred(advertised: &[String]) -> Format {
+        Format::ALL
+            .into_iter()
+            .find(|format| advertised.iter().any(|value| Format::from_media_type(value) == Some(*format)))
+            .unwrap_or(Format::Json)
+    }
+
+    /// Returns an Accept header value that ranks the formats from most to least compact.
+    pub fn accept() -> String {
+        format!(
+            "{}, {};q=0.9, {};q=0.8",
+            Format::Cbor.media_type(),
+            Format::MessagePack.media_type(),
+            Format::Json.media_type()
+        )
+    }
+
+    /// Serializes a value in this format.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, WireError> {
+        let encoded = match self {
+            Format::Json => serde_json::to_vec(value).map_err(|error| error.to_string()),
+            Format::Cbor => {
+                let mut buffer = vec![];
+
+                ciborium::ser::into_writer(value, &mut buffer)
+                    .map(|_| buffer)
+                    .map_err(|error| error.to_string())
+            },
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|error| error.to_string())
+        };
+
+        encoded.map_err(|error| WireError::Encode(*self, error))
+    }
+
+    /// Deserializes a value from data in this format.
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, WireError> {
+        let decoded = match self {
+            Format::Json => serde_json::from_slice(data).map_err(|error| error.to_string()),
+            Format::Cbor => ciborium::de::from_reader(data).map_err(describe_cbor_error),
+            Format::MessagePack => rmp_serde::from_slice(data).map_err(|error| error.to_string())
+        };
+
+        decoded.map_err(|error| WireError::Decode(*self, error))
+    }
+}
+
+/// Describes a CBOR decoding failure, whose own formatting is only meant for debugging.
+fn describe_cbor_error(error: ciborium::de::Error<std::io::Error>) -> String {
+    match error {
+        ciborium::de::Error::Io(error) => error.to_string(),
+        ciborium::de::Error::Syntax(offset) => format!("syntax error at byte {}", offset),
+        ciborium::de::Error::Semantic(_, message) => message,
+        ciborium::de::Error::RecursionLimitExceeded => "nested too deeply".to_string()
+    }
+}
+
+#[derive(Debug)]
+pub enum WireError {
+    UnsupportedMediaType(String),
+    Overflow(usize),
+    Payload(PayloadError),
+    Decode(Format, String),
+    Encode(Format, String)
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::UnsupportedMediaType(value) => write!(formatter, "Bodies of type {} are not supported, use one of {}", value, Format::media_types().join(", ")),
+            WireError::Overflow(limit) => write!(formatter, "The body must not exceed {} bytes", limit),
+            WireError::Payload(error) => write!(formatter, "{}", error),
+            WireError::Decode(format, error) => write!(formatter, "Unable to decode {} body: {}", format, error),
+            WireError::Encode(format, error) => write!(formatter, "Unable to encode {} body: {}", format, error)
+        }
+    }
+}
+
+impl ResponseError for WireError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match self {
+            WireError::UnsupportedMediaType(_) => HttpResponse::UnsupportedMediaType().body(self.to_string()),
+            WireError::Overflow(_) => HttpResponse::PayloadTooLarge().body(self.to_string()),
+            WireError::Payload(error) => error.error_response(),
+            WireError::Decode(_, _) => HttpResponse::BadRequest().body(self.to_string()),
+            WireError::Encode(_, _) => HttpResponse::InternalServerError().body(self.to_string())
+        }
+    }
+}
+
+type WireErrorHandler = Arc<dyn Fn(WireError, &HttpRequest) -> Error + Send + Sync>;
+
+type WireLimiter = Arc<dyn Fn(&HttpRequest) -> usize + Send + Sync>;
+
+/// Limits and error handling for [`Wire`] request bodies, registered as app data.
+#[derive(Clone)]
+pub struct WireConfig {
+    limit: usize,
+    limiter: Option<WireLimiter>,
+    error_handler: Option<WireErrorHandler>
+}
+
+impl WireConfig {
+    /// Sets the largest body in bytes that is read.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Sets a function that works out the largest body in bytes for each request, taking
+    /// precedence over a fixed limit, for limits that can change while the server runs.
+    pub fn limit_with<F>(mut self, limiter: F) -> Self
+    where F: Fn(&HttpRequest) -> usize + Send + Sync + 'static {
+        self.limiter = Some(Arc::new(limiter));
+        self
+    }
+
+    /// Sets a function that turns extraction failures into responses.
+    pub fn error_handler<F>(mut self, handler: F) -> Self
+    where F: Fn(WireError, &HttpRequest) -> Error + Send + Sync + 'static {
+        self.error_handler = Some(Arc::new(handler));
+        self
+    }
+
+    fn from_request(request: &HttpRequest) -> &Self {
+        request
+            .app_data::<Self>()
+            .unwrap_or(&DEFAULT_CONFIG)
+    }
+}
+
+impl Default for WireConfig {
+    fn default() -> Self {
+        DEFAULT_CONFIG.clone()
+    }
+}
+
+/// A request or response body in JSON, CBOR or MessagePack.
+///
+/// Request bodies are decoded according to their content type and responses are encoded in the
+/// format the Accept header ranks highest, so handlers work with the same models in every format.
+#[derive(Debug)]
+pub struct Wire<T>(pub T);
+
+impl<T> Wire<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Wire<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Wire<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// Reads a whole body, refusing it as soon as it is known to exceed the limit.
+async fn read_body(request: &HttpRequest, payload: &mut Payload, limit: usize) -> Result<BytesMut, WireError> {
+    let length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    if length.is_some_and(|value| value > limit) {
+        return Err(WireError::Overflow(limit));
+    }
+
+    let mut body = BytesMut::with_capacity(length.unwrap_or_default());
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(WireError::Payload)?;
+
+        if body.len() + chunk.len() > limit {
+            return Err(WireError::Overflow(limit));
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Wire<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let config = WireConfig::from_request(request);
+        let limit = match &config.limiter {
+            Some(limiter) => limiter(request),
+            None => config.limit
+        };
+        let error_handler = config.error_handler.clone();
+        let request = request.clone();
+        let mut payload = payload.take();
+
+        async move {
+            let result = match Format::from_content_type(&request) {
+                Ok(format) => match read_body(&request, &mut payload, limit).await {
+                    Ok(body) => format.decode(&body),
+                    Err(error) => Err(error)
+                },
+                Err(error) => Err(error)
+            };
+
+            result
+                .map(Wire)
+                .map_err(|error| match &error_handler {
+                    Some(handler) => handler(error, &request),
+                    None => error.into()
+                })
+        }
+        .boxed_local()
+    }
+}
+
+impl<T: Serialize> Responder for Wire<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, request: &HttpRequest) -> HttpResponse<Self::Body> {
+        let format = Format::negotiate(request);
+
+        match format.encode(&self.0) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type(format.media_type())
+                .insert_header((VARY, "accept"))
+                .body(body),
+            Err(error) => error.error_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::ACCEPT;
+    use actix_web::test::TestRequest;
+
+    use super::Format;
+
+    fn advertised(media_types: &[&str]) -> Vec<String> {
+        media_types
+            .iter()
+            .map(|value| value.to_string())
+            .collect()
+    }
+
+    fn negotiated(accept: Option<&str>) -> Format {
+        let request = match accept {
+            Some(value) => TestRequest::default().insert_header((ACCEPT, value)),
+            None => TestRequest::default()
+        };
+
+        Format::negotiate(&request.to_http_request())
+    }
+
+    #[test]
+    fn preferred_picks_the_most_compact_advertised_format() {
+        assert_eq!(Format::preferred(&advertised(&["application/json", "application/msgpack", "application/cbor"])), Format::Cbor);
+        assert_eq!(Format::preferred(&advertised(&["application/json", "application/msgpack"])), Format::MessagePack);
+        assert_eq!(Format::preferred(&advertised(&["application/x-msgpack"])), Format::MessagePack);
+        assert_eq!(Format::preferred(&advertised(&["APPLICATION/CBOR; charset=binary"])), Format::Cbor);
+        assert_eq!(Format::preferred(&Format::media_types()), Format::Cbor);
+    }
+
+    #[test]
+    fn preferred_falls_back_to_json() {
+        assert_eq!(Format::preferred(&[]), Format::Json);
+        assert_eq!(Format::preferred(&advertised(&["application/json"])), Format::Json);
+        assert_eq!(Format::preferred(&advertised(&["application/xml", "text/plain"])), Format::Json);
+    }
+
+    #[test]
+    fn negotiate_follows_the_ranking_of_the_client() {
+        assert_eq!(negotiated(Some("application/cbor")), Format::Cbor);
+        assert_eq!(negotiated(Some("application/vnd.msgpack")), Format::MessagePack);
+        assert_eq!(negotiated(Some("application/msgpack;q=0.5, application/json")), Format::Json);
+        assert_eq!(negotiated(Some("application/json;q=0.1, application/cbor;q=0.9")), Format::Cbor);
+        assert_eq!(negotiated(Some("text/html, application/msgpack")), Format::MessagePack);
+        assert_eq!(negotiated(Some(&Format::accept())), Format::Cbor);
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_json() {
+        assert_eq!(negotiated(None), Format::Json);
+        assert_eq!(negotiated(Some("*/*")), Format::Json);
+        assert_eq!(negotiated(Some("application/*, application/cbor;q=0.5")), Format::Json);
+        assert_eq!(negotiated(Some("text/html")), Format::Json);
+        assert_eq!(negotiated(Some("not a media type")), Format::Json);
+    }
+}
@@ -0,0 +1,134 @@
+//! Measures the time and peak memory it takes to turn the body of a received letter into a
+//! validated letter ready to be stored.
+//!
+//! Run with `cargo bench -p mail --bench receive`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use common::model::{Address, Blob, Identifier, Labels};
+use mail::configuration::MailConfiguration;
+use mail::model::{EmbeddedAttachment, LetterAttachments, SealedLetter, StoredLetter};
+use mail::route::validate_letter;
+
+/// Keeps track of the memory in use and the most that has been in use at once.
+struct CountingAllocator;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = System.alloc(layout);
+
+        if !pointer.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        System.dealloc(pointer, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const MEBIBYTE: usize = 1048576;
+const ATTACHMENTS: usize = 4;
+const ITERATIONS: u32 = 10;
+
+/// Builds a signed letter whose embedded attachments hold the given number of bytes in total.
+fn letter(size: usize) -> SealedLetter {
+    let attachment = |index: usize| {
+        let data = vec![index as u8; size / ATTACHMENTS];
+
+        EmbeddedAttachment {
+            id: Identifier::new(),
+            size: data.len() as u64,
+            labels: Labels::default(),
+            data: Blob::from(data),
+            signature: Some(Blob::from(vec![0; 64]))
+        }
+    };
+
+    let address = |host: &str| Address {
+        id: Identifier::new(),
+        host: host.to_string()
+    };
+
+    SealedLetter {
+        id: Identifier::new(),
+        sender: Some(address("sender.example")),
+        recipients: vec![address("recipient.example")],
+        attachments: Some(LetterAttachments {
+            embedded: (0..ATTACHMENTS).map(attachment).collect(),
+            remote: vec![]
+        }),
+        labels: Labels::default(),
+        subject: Some(Blob::from(vec![1; 256])),
+        body: Some(Blob::from(vec![2; 65536])),
+        sent_at: Some(Utc::now()),
+        signature: Some(Blob::from(vec![3; 64])),
+        stamp: None
+    }
+}
+
+/// Does what receiving a letter does with its body before anything is stored.
+fn receive(body: &[u8], configuration: &MailConfiguration) -> StoredLetter {
+    let letter: SealedLetter = serde_json::from_slice(body).expect("the letter is valid JSON");
+
+    validate_letter(&letter, Utc::now(), configuration).expect("the letter is valid");
+
+    StoredLetter {
+        received_at: Utc::now(),
+        request_id: None,
+        letter: letter.clone(),
+        local_attachments: vec![],
+        blobs: Default::default(),
+        blob_size: 0
+    }
+}
+
+fn main() {
+    let mut configuration = MailConfiguration::default();
+
+    configuration.limit.embedded_attachment_size = u64::MAX;
+
+    println!("{:>12} {:>12} {:>14} {:>10} {:>12}", "attachments", "body", "peak memory", "per byte", "time");
+
+    for size in [MEBIBYTE, 8 * MEBIBYTE, 32 * MEBIBYTE] {
+        let body = serde_json::to_vec(&letter(size)).expect("the letter can be serialized");
+        let mut peak = 0;
+        let mut elapsed = Duration::ZERO;
+
+        for _ in 0..ITERATIONS {
+            let baseline = CURRENT.load(Ordering::Relaxed);
+
+            PEAK.store(baseline, Ordering::Relaxed);
+
+            let started = Instant::now();
+            let stored = receive(&body, &configuration);
+
+            elapsed += started.elapsed();
+            peak = peak.max(PEAK.load(Ordering::Relaxed) - baseline);
+
+            drop(stored);
+        }
+
+        println!(
+            "{:>10} MiB {:>8.1} MiB {:>10.1} MiB {:>10.2} {:>9.1} ms",
+            size / MEBIBYTE,
+            body.len() as f64 / MEBIBYTE as f64,
+            peak as f64 / MEBIBYTE as f64,
+            peak as f64 / size as f64,
+            elapsed.as_secs_f64() * 1000.0 / ITERATIONS as f64
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::fmt;
+use actix_web::body::BoxBody;
+use actix_web::{HttpResponse, ResponseError};
+use lazy_static::lazy_static;
+use common::store::BlobStoreError;
+use common::database::redis::{get_connection, MobcPool, RedisDatabaseError};
+use common::model::Identifier;
+use mobc_redis::redis::{RedisError, Script, AsyncCommands};
+
+use crate::configuration::Quota;
+use crate::mailbox::usage_key;
+use crate::model::StoredAttachment;
+use crate::storage::AttachmentStorage;
+
+/// Stores an attachment provided that its identifier is unused and the owner has room.
+///
+/// Keys are the attachment and the usage of its owner. Bytes already reserved for the attachment
+/// by an upload are given as the last argument and do not count against the quota a second time.
+/// Returns zero on success, one if the quota would be exceeded or minus one if the attachment already exists.
+const STORE_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return -1
+end
+local size = tonumber(ARGV[3])
+local bytes = tonumber(redis.call('HGET', KEYS[2], 'bytes')) or 0
+if bytes - tonumber(ARGV[5]) + size > tonumber(ARGV[4]) then
+    return 1
+end
+redis.call('HSET', KEYS[1], 'owner', ARGV[1], 'attachment', ARGV[2], 'size', size)
+redis.call('HINCRBY', KEYS[2], 'bytes', size)
+return 0
+";
+
+/// Removes an attachment on behalf of its owner and releases the storage it was using.
+///
+/// Returns one on success, zero if the attachment does not exist or minus one if it belongs to someone else.
+const DELETE_SCRIPT: &str = r"
+local stored = redis.call('HMGET', KEYS[1], 'owner', 'size')
+if not stored[1] then
+    return 0
+end
+if stored[1] ~= ARGV[1] then
+    return -1
+end
+redis.call('DEL', KEYS[1])
+redis.call('HINCRBY', KEYS[2], 'bytes', -tonumber(stored[2]))
+return 1
+";
+
+const ATTACHMENT: &str = "ATTACHMENT";
+
+lazy_static! {
+    static ref STORE: Script = Script::new(STORE_SCRIPT);
+    static ref DELETE: Script = Script::new(DELETE_SCRIPT);
+}
+
+#[derive(Debug)]
+pub enum AttachmentError {
+    QuotaExceeded(Identifier),
+    Duplicate(Identifier),
+    NotFound(Identifier),
+    NotOwner(Identifier),
+    Serialize(serde_json::Error),
+    Blob(BlobStoreError),
+    CreateRedisConnection(RedisDatabaseError),
+    Query(RedisError)
+}
+
+impl fmt::Display for AttachmentError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttachmentError::QuotaExceeded(owner) => write!(formatter, "The storage of {} is full", owner),
+            AttachmentError::Duplicate(id) => write!(formatter, "An attachment with the identifier {} is already stored", id),
+            AttachmentError::NotFound(id) => write!(formatter, "No attachment with the identifier {} exists", id),
+            AttachmentError::NotOwner(id) => write!(formatter, "The attachment {} belongs to another user", id),
+            AttachmentError::Serialize(error) => write!(formatter, "{}", error),
+            AttachmentError::Blob(error) => write!(formatter, "{}", error),
+            AttachmentError::CreateRedisConnection(error) => write!(formatter, "{}", error),
+            AttachmentError::Query(error) => write!(formatter, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for AttachmentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            AttachmentError::Serialize(ref error) => Some(error),
+            AttachmentError::Blob(ref error) => Some(error),
+            AttachmentError::CreateRedisConnection(ref error) => Some(error),
+            AttachmentError::Query(ref error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+impl ResponseError for AttachmentError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            AttachmentError::QuotaExceeded(_) => {
+                HttpResponse::InsufficientStorage().body(self.to_string())
+            },
+            AttachmentError::Duplicate(_) => {
+                HttpResponse::Conflict().body(self.to_string())
+            },
+            AttachmentError::NotFound(_) => {
+                HttpResponse::NotFound().body(self.to_string())
+            },
+            AttachmentError::NotOwner(_) => {
+                HttpResponse::Forbidden().body(self.to_string())
+            },
+            _ => {
+                HttpResponse::InternalServerError().body(self.to_string())
+            }
+        }
+    }
+}
+
+/// The key of the hash holding an attachment and its owner.
+pub fn attachment_key(id: &Identifier) -> String {
+    format!("{}:{}", ATTACHMENT, id)
+}
+
+/// Stores an attachment whose data has been written to its upload path, counting its size
+/// towards the storage quota of its owner.
+///
+/// The data is only moved into the blob store once the attachment has been recorded, so it is
+/// left at the upload path if the attachment cannot be stored. `reserved` is how many bytes an
+/// upload has already reserved for the attachment, which the caller releases once it is stored.
+pub async fn store_attachment(
+    pool: &MobcPool,
+    storage: &AttachmentStorage,
+    stored: &StoredAttachment,
+    quota: &Quota,
+    reserved: u64
+) -> Result<(), AttachmentError> {
+    let id = stored.id;
+    let value = serde_json::to_string(stored).map_err(AttachmentError::Serialize)?;
+
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(AttachmentError::CreateRedisConnection)?;
+
+    let result: i64 = STORE
+        .key(attachment_key(&id))
+        .key(usage_key(&stored.owner))
+        .arg(stored.owner.to_string())
+        .arg(value)
+        .arg(stored.size)
+        .arg(quota.bytes)
+        .arg(reserved)
+        .invoke_async(&mut *connection)
+        .await
+        .map_err(AttachmentError::Query)?;
+
+    match result {
+        0 => (),
+        index if index < 0 => return Err(AttachmentError::Duplicate(id)),
+        _ => return Err(AttachmentError::QuotaExceeded(stored.owner))
+    }
+
+    let source = storage.upload_path(&id);
+
+    if let Err(error) = storage.blobs.put_file(pool, source, &stored.digest, 1).await {
+        // Without its data the attachment cannot be served, so give the storage back
+        DELETE
+            .key(attachment_key(&id))
+            .key(usage_key(&stored.owner))
+            .arg(stored.owner.to_string())
+            .invoke_async::<_, i64>(&mut *connection)
+            .await
+            .map_err(AttachmentError::Query)?;
+
+        return Err(AttachmentError::Blob(error));
+    }
+
+    Ok(())
+}
+
+/// Returns the details of a stored attachment.
+pub async fn load_attachment(pool: &MobcPool, id: &Identifier) -> Result<StoredAttachment, AttachmentError> {
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(AttachmentError::CreateRedisConnection)?;
+
+    let value: Option<String> = connection
+        .hget(attachment_key(id), "attachment")
+        .await
+        .map_err(AttachmentError::Query)?;
+
+    let value = value.ok_or(AttachmentError::NotFound(*id))?;
+
+    serde_json::from_str(&value).map_err(AttachmentError::Serialize)
+}
+
+/// Deletes an attachment if it belongs to the given user, releasing its data.
+pub async fn delete_attachment(
+    pool: &MobcPool,
+    storage: &AttachmentStorage,
+    owner: &Identifier,
+    id: &Identifier
+) -> Result<(), AttachmentError> {
+    let stored = load_attachment(pool, id).await?;
+
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(AttachmentError::CreateRedisConnection)?;
+
+    let result: i64 = DELETE
+        .key(attachment_key(id))
+        .key(usage_key(owner))
+        .arg(owner.to_string())
+        .invoke_async(&mut *connection)
+        .await
+        .map_err(AttachmentError::Query)?;
+
+    match result {
+        1 => (),
+        0 => return Err(AttachmentError::NotFound(*id)),
+        _ => return Err(AttachmentError::NotOwner(*id))
+    }
+
+    storage.blobs
+        .release(pool, &stored.digest, 1)
+        .await
+        .map_err(AttachmentError::Blob)
+}
@@ -1,7 +1,13 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, HashMap};
+use std::sync::{Arc, RwLock};
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+use common::model::{Blob, Identifier};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+/// The difficulty used for first contact stamps when none has been configured.
+pub const DEFAULT_STAMP_DIFFICULTY: u32 = 20;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
 pub struct MailAccept {
     /// Whether to accept letters without a return address.
     pub anomyous_sender: bool,
@@ -10,10 +16,30 @@ pub struct MailAccept {
     pub unsigned: bool,
 
     /// Whether to accept unsigned letter attachments.
-    pub unsigned_attachments: bool
+    pub unsigned_attachments: bool,
+
+    /// The number of leading zero bits a proof-of-work stamp must have for anonymous letters to be accepted.
+    #[serde(default)]
+    pub stamp_difficulty: Option<u32>,
+
+    /// Whether to require a proof-of-work stamp when a sender first contacts a recipient.
+    /// The sender of a letter is not authenticated, so a letter claiming to come from a known contact skips the stamp.
+    #[serde(default)]
+    pub first_contact_stamp: bool
+}
+
+impl MailAccept {
+    /// Returns the difficulty stamps are checked against, including the default for first contact stamps.
+    pub fn effective_stamp_difficulty(&self) -> Option<u32> {
+        match self.stamp_difficulty {
+            Some(value) => Some(value),
+            None if self.first_contact_stamp => Some(DEFAULT_STAMP_DIFFICULTY),
+            None => None
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
 pub struct MailRequire {
     /// Require letters to possess a subject line.
     pub subject: bool,
@@ -22,10 +48,15 @@ pub struct MailRequire {
     pub body: bool,
 
     /// Any letter labels that must be present.
-    pub labels: HashSet<String>
+    pub labels: HashSet<String>,
+
+    /// Require letters to possess a sent timestamp.
+    #[serde(default)]
+    pub sent_at: bool
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
 pub struct MailLimit {
     /// The maximum number of letter recipients.
     pub recipients: u64,
@@ -49,7 +80,40 @@ pub struct MailLimit {
     pub remote_attachment_size: u64,
 
     /// The maximum number of labels a letter can have.
-    pub labels: u64
+    pub labels: u64,
+
+    /// The maximum number of seconds a sent timestamp can be ahead of the local clock.
+    pub clock_skew: u64,
+
+    /// The maximum age in seconds of a letter according to its sent timestamp.
+    pub age: u64
+}
+
+/// Room for the identifiers, signature, stamp and JSON around the encoded parts of a letter.
+const LETTER_OVERHEAD: u64 = 65536;
+
+/// Room for the identifier, address, labels and signature of an attachment.
+const ATTACHMENT_OVERHEAD: u64 = 4096;
+
+/// Room for a recipient address.
+const RECIPIENT_OVERHEAD: u64 = 512;
+
+/// Room for a label and its value.
+const LABEL_OVERHEAD: u64 = 256;
+
+impl MailLimit {
+    /// Returns the size in bytes of the JSON of the largest letter these limits allow.
+    pub fn letter_size(&self) -> u64 {
+        let embedded = Blob::encoded_len(self.embedded_attachment_size).saturating_add(ATTACHMENT_OVERHEAD);
+
+        LETTER_OVERHEAD
+            .saturating_add(Blob::encoded_len(self.subject_size))
+            .saturating_add(Blob::encoded_len(self.body_size))
+            .saturating_add(self.embedded_attachments.saturating_mul(embedded))
+            .saturating_add(self.remote_attachments.saturating_mul(ATTACHMENT_OVERHEAD))
+            .saturating_add(self.recipients.saturating_mul(RECIPIENT_OVERHEAD))
+            .saturating_add(self.labels.saturating_mul(LABEL_OVERHEAD))
+    }
 }
 
 /// Attempt to set reasonable default limits.
@@ -63,12 +127,161 @@ impl Default for MailLimit {
             embedded_attachment_size: 262144,
             remote_attachments: 100,
             remote_attachment_size: 536870912,
-            labels: 1000
+            labels: 1000,
+            clock_skew: 300,
+            age: 604800
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct RateLimit {
+    /// The maximum number of requests that can be made in a burst.
+    pub capacity: u64,
+
+    /// The number of requests regained per second.
+    pub refill: f64
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct MailRate {
+    /// The rate limit for each client IP address.
+    pub client: Option<RateLimit>,
+
+    /// The header holding the client IP address, such as X-Forwarded-For behind a proxy on a Unix socket, where requests have no peer address.
+    /// Clients can send the header themselves, so it must only be set behind proxies that append to or overwrite it.
+    pub client_header: Option<String>,
+
+    /// The number of trusted proxies in front of the server that append an address to client_header.
+    /// The address that many entries from the right is used, as the ones before it are written by the client.
+    #[serde(default = "default_client_hops")]
+    pub client_hops: usize,
+
+    /// The rate limit for each sending host.
+    pub host: Option<RateLimit>,
+
+    /// The rate limit for each sender address.
+    pub sender: Option<RateLimit>,
+
+    /// The rate limit for each authenticated user.
+    pub user: Option<RateLimit>
+}
+
+fn default_client_hops() -> usize {
+    1
+}
+
+/// No rate limits by default.
+impl Default for MailRate {
+    fn default() -> Self {
+        Self {
+            client: None,
+            client_header: None,
+            client_hops: default_client_hops(),
+            host: None,
+            sender: None,
+            user: None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct MailReplay {
+    /// The number of seconds a received letter identifier is remembered for.
+    pub expiry: u64
+}
+
+/// Remember letters for a week by default.
+impl Default for MailReplay {
+    fn default() -> Self {
+        Self {
+            expiry: 604800
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct MailFetch {
+    /// Whether to download the remote attachments of received letters in the background so they outlive their origin.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// The maximum number of seconds to spend downloading one remote attachment.
+    #[serde(default = "default_fetch_timeout")]
+    pub timeout: u64,
+
+    /// The maximum number of remote attachments downloaded at once.
+    #[serde(default = "default_fetch_concurrency")]
+    pub concurrency: usize
+}
+
+fn default_fetch_timeout() -> u64 {
+    30
+}
+
+fn default_fetch_concurrency() -> usize {
+    4
+}
+
+impl Default for MailFetch {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout: default_fetch_timeout(),
+            concurrency: default_fetch_concurrency()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Quota {
+    /// The maximum total size in bytes of stored letters and uploaded attachments.
+    pub bytes: u64,
+
+    /// The maximum number of stored letters.
+    pub letters: u64,
+
+    /// The maximum number of unfinished uploads open at once, whose declared sizes are reserved against the bytes.
+    #[serde(default = "default_quota_uploads")]
+    pub uploads: u64
+}
+
+fn default_quota_uploads() -> u64 {
+    8
+}
+
+/// Attempt to set a reasonable default quota.
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            bytes: 1073741824,
+            letters: 10000,
+            uploads: default_quota_uploads()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct MailQuota {
+    /// The quota for users without an override.
+    #[serde(default)]
+    pub default: Quota,
+
+    /// Quota overrides keyed by user identifier.
+    #[serde(default)]
+    pub users: HashMap<String, Quota>
+}
+
+impl MailQuota {
+    /// Returns the quota that applies to a user.
+    pub fn quota(&self, user: &Identifier) -> &Quota {
+        self.users
+            .get(&user.to_string())
+            .unwrap_or(&self.default)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
 pub struct MailConfiguration {
     /// Accepted mail data.
     pub accept: MailAccept,
@@ -77,5 +290,53 @@ pub struct MailConfiguration {
     pub require: MailRequire,
 
     /// Limitations for letters.
-    pub limit: MailLimit
+    pub limit: MailLimit,
+
+    /// Rate limits for sending and receiving letters.
+    #[serde(default)]
+    pub rate: MailRate,
+
+    /// Duplicate letter detection.
+    #[serde(default)]
+    pub replay: MailReplay,
+
+    /// Mailbox storage quotas.
+    #[serde(default)]
+    pub quota: MailQuota,
+
+    /// Fetching of remote attachments on receipt.
+    #[serde(default)]
+    pub fetch: MailFetch
+}
+
+/// A mail configuration that can be replaced while the server is running.
+#[derive(Debug, Default)]
+pub struct LiveMailConfiguration {
+    current: RwLock<Arc<MailConfiguration>>
+}
+
+impl LiveMailConfiguration {
+    pub fn new(configuration: MailConfiguration) -> Self {
+        LiveMailConfiguration {
+            current: RwLock::new(Arc::new(configuration))
+        }
+    }
+
+    /// Returns the configuration in effect, which stays consistent for as long as it is held.
+    pub fn current(&self) -> Arc<MailConfiguration> {
+        match self.current.read() {
+            Ok(guard) => guard.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    /// Atomically replaces the configuration for all subsequent requests.
+    pub fn replace(&self, configuration: MailConfiguration) {
+        let configuration = Arc::new(configuration);
+
+        match self.current.write() {
+            Ok(mut guard) => *guard = configuration,
+            Err(poisoned) => *poisoned.into_inner() = configuration
+        }
+    }
 }
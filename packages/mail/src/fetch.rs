@@ -0,0 +1,532 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{remove_file, File};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+use actix_tls::connect::{Connector as TcpConnector, Resolve, Resolver};
+use actix_web::rt::time::interval;
+use actix_web::web::Data;
+use awc::{Client, Connector};
+use awc::http::StatusCode;
+use futures_util::future::LocalBoxFuture;
+use futures_util::{stream, StreamExt};
+use log::{debug, error, warn};
+use common::database::redis::{get_connection, MobcPool, RedisDatabaseError};
+use common::model::{Blob, Identifier};
+use common::request::{RequestId, REQUEST_ID_HEADER};
+use common::state::CommonState;
+use common::store::{blocking, digest_file, BlobStoreError};
+use mobc_redis::redis::{AsyncCommands, RedisError};
+
+use crate::configuration::{LiveMailConfiguration, MailConfiguration};
+use crate::mailbox::attach_fetched;
+use crate::model::{FetchJob, LocalAttachment, RemoteAttachment, SealedLetter};
+use crate::storage::AttachmentStorage;
+use crate::upload::{receive, UploadError};
+
+/// Where attachments are served on other instances, relative to their host.
+const ATTACHMENT_PATH: &str = "v1/mail/attachment";
+
+/// The list of letters whose remote attachments are waiting to be fetched, oldest first.
+const FETCH_QUEUE: &str = "FETCH_QUEUE";
+
+/// How often the fetch queue is checked for letters when it is empty, in seconds.
+const POLL_INTERVAL: u64 = 5;
+
+/// The name the attachment fetcher reports its heartbeat under.
+const FETCHER: &str = "attachment-fetcher";
+
+#[derive(Debug)]
+pub enum FetchError {
+    TooLarge(Identifier, u64),
+    Forbidden(Identifier, String),
+    Request(Identifier, String),
+    Status(Identifier, StatusCode),
+    Body(Identifier, String),
+    LongerThanDeclared(Identifier, u64),
+    SizeMismatch(Identifier, u64, u64),
+    Io(Identifier, io::Error),
+    Store(Identifier, BlobStoreError),
+    Serialize(serde_json::Error),
+    CreateRedisConnection(RedisDatabaseError),
+    Query(RedisError)
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::TooLarge(id, limit) => write!(formatter, "Attachment {} is declared larger than the limit of {} bytes", id, limit),
+            FetchError::Forbidden(id, host) => write!(formatter, "Attachment {} is on {}, which is not a public address", id, host),
+            FetchError::Request(id, error) => write!(formatter, "Unable to request attachment {}: {}", id, error),
+            FetchError::Status(id, status) => write!(formatter, "Attachment {} could not be downloaded: {}", id, status),
+            FetchError::Body(id, error) => write!(formatter, "Unable to download attachment {}: {}", id, error),
+            FetchError::LongerThanDeclared(id, size) => write!(formatter, "Attachment {} is longer than its declared {} bytes", id, size),
+            FetchError::SizeMismatch(id, declared, actual) => write!(formatter, "Attachment {} was declared as {} bytes but is {} bytes", id, declared, actual),
+            FetchError::Io(id, error) => write!(formatter, "Unable to write attachment {}: {}", id, error),
+            FetchError::Store(id, error) => write!(formatter, "Unable to store attachment {}: {}", id, error),
+            FetchError::Serialize(error) => write!(formatter, "{}", error),
+            FetchError::CreateRedisConnection(error) => write!(formatter, "{}", error),
+            FetchError::Query(error) => write!(formatter, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for FetchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            FetchError::Io(_, ref error) => Some(error),
+            FetchError::Store(_, ref error) => Some(error),
+            FetchError::Serialize(ref error) => Some(error),
+            FetchError::CreateRedisConnection(ref error) => Some(error),
+            FetchError::Query(ref error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+/// Returns true if an IPv4 address is reachable on the public internet.
+fn is_public_v4(ip: &Ipv4Addr) -> bool {
+    let [first, second, third, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || first == 0
+        || first >= 240
+        || (first == 100 && (second & 0xc0) == 64)
+        || (first == 192 && second == 0 && third == 0)
+        || (first == 198 && (second & 0xfe) == 18))
+}
+
+/// Returns the IPv4 address embedded in an IPv6 address by a translation or tunnelling scheme,
+/// which is what a connection to it ends up reaching.
+fn embedded_v4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+
+    match segments {
+        // IPv4-mapped and the deprecated IPv4-compatible addresses, ::ffff:0:0/96 and ::/96
+        [0, 0, 0, 0, 0, 0xffff | 0, _, _] => ip.to_ipv4(),
+        // The well-known NAT64 prefix, 64:ff9b::/96
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+        // 6to4, 2002::/16, which carries the address right after the prefix
+        [0x2002, high, low, ..] => Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+        _ => None
+    }
+}
+
+/// Returns true if an IP address is reachable on the public internet, so that fetching from it
+/// cannot reach this instance or the private network it runs in.
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(embedded) = embedded_v4(ip) {
+                return is_public_v4(&embedded);
+            }
+
+            let [first, second, ..] = ip.segments();
+
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                // Deprecated site-local addresses, fec0::/10
+                || (first & 0xffc0) == 0xfec0
+                // Local-use NAT64, 64:ff9b:1::/48, whose translation is up to the network
+                || (first == 0x64 && second == 0xff9b)
+                // Teredo, 2001::/32, which tunnels to an obfuscated IPv4 address
+                || (first == 0x2001 && second == 0)
+                // Documentation, 2001:db8::/32
+                || (first == 0x2001 && second == 0x0db8))
+        }
+    }
+}
+
+/// Returns the address of a host if it is written as an IP address, with or without a port.
+fn literal_address(host: &str) -> Option<IpAddr> {
+    if let Ok(ip) = host.parse() {
+        return Some(ip);
+    }
+
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+
+    host.rsplit_once(':')?.0.parse().ok()
+}
+
+/// Resolves host names to their public addresses only, refusing hosts that have none.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn lookup<'a>(&'a self, host: &'a str, port: u16) -> LocalBoxFuture<'a, Result<Vec<SocketAddr>, Box<dyn Error>>> {
+        let name = (host.to_string(), port);
+
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = blocking(move || name.to_socket_addrs().map(Iterator::collect::<Vec<_>>))
+                .await?
+                .into_iter()
+                .filter(|address: &SocketAddr| is_public(&address.ip()))
+                .collect();
+
+            if addresses.is_empty() {
+                let error = io::Error::new(io::ErrorKind::PermissionDenied, format!("{} has no public address", host));
+
+                return Err(Box::new(error) as Box<dyn Error>);
+            }
+
+            Ok(addresses)
+        })
+    }
+}
+
+/// Builds a client that only connects to public addresses and does not follow redirects, since
+/// the hosts it is pointed at are chosen by senders.
+fn fetch_client(timeout: u64) -> Client {
+    let connector = Connector::new()
+        .connector(TcpConnector::new(Resolver::custom(PublicResolver)).service());
+
+    Client::builder()
+        .connector(connector)
+        .timeout(Duration::from_secs(timeout))
+        .disable_redirects()
+        .finish()
+}
+
+/// Returns the URL the data of a remote attachment is served at.
+pub fn attachment_url(attachment: &RemoteAttachment) -> String {
+    format!("https://{}/{}/{}/data", attachment.address.host, ATTACHMENT_PATH, attachment.address.id)
+}
+
+/// Removes a partly fetched file, which is fine to leave for the upload sweep if that fails.
+async fn discard(path: PathBuf) {
+    let _ = blocking(move || remove_file(path)).await;
+}
+
+/// Downloads a remote attachment to disk and moves it into the blob store with the given number
+/// of references, making sure that it is exactly as large as declared.
+///
+/// Returns the local copy along with the digest of its data.
+pub async fn fetch_attachment(
+    client: &Client,
+    storage: &AttachmentStorage,
+    pool: &MobcPool,
+    attachment: &RemoteAttachment,
+    limit: u64,
+    references: u64,
+    request_id: Option<&str>
+) -> Result<(LocalAttachment, String), FetchError> {
+    let id = attachment.id;
+
+    if attachment.size > limit {
+        return Err(FetchError::TooLarge(id, limit));
+    }
+
+    // Hosts written as IP addresses are connected to without being resolved
+    if literal_address(&attachment.address.host).is_some_and(|ip| !is_public(&ip)) {
+        return Err(FetchError::Forbidden(id, attachment.address.host.clone()));
+    }
+
+    let mut request = client.get(attachment_url(attachment));
+
+    if let Some(value) = request_id {
+        request = request.insert_header((REQUEST_ID_HEADER, value));
+    }
+
+    let response = request
+        .send()
+        .await
+        .map_err(|error| FetchError::Request(id, error.to_string()))?;
+
+    if !response.status().is_success() {
+        return Err(FetchError::Status(id, response.status()));
+    }
+
+    let path = storage.upload_path(&Identifier::new());
+    let created = path.clone();
+    let file = blocking(move || File::create(created))
+        .await
+        .map_err(|error| FetchError::Io(id, error))?;
+
+    if let Err(error) = receive(file, Box::pin(response), attachment.size).await {
+        discard(path).await;
+
+        return Err(match error {
+            UploadError::Overflow(size) => FetchError::LongerThanDeclared(id, size),
+            UploadError::Truncated(size, received) => FetchError::SizeMismatch(id, size, received),
+            UploadError::Io(error) => FetchError::Io(id, error),
+            _ => FetchError::Body(id, error.to_string())
+        });
+    }
+
+    let hashed = path.clone();
+    let digest = match blocking(move || digest_file(&hashed)).await {
+        Ok(value) => value,
+        Err(error) => {
+            discard(path).await;
+
+            return Err(FetchError::Io(id, error));
+        }
+    };
+
+    if let Err(error) = storage.blobs.put_file(pool, path.clone(), &digest, references).await {
+        discard(path).await;
+
+        return Err(FetchError::Store(id, error));
+    }
+
+    let local = LocalAttachment {
+        id,
+        address: attachment.address.clone(),
+        size: attachment.size,
+        labels: attachment.labels.clone(),
+        data: Blob::default(),
+        signature: attachment.signature.clone()
+    };
+
+    Ok((local, digest))
+}
+
+/// Queues the remote attachments of a stored letter to be fetched in the background for its local recipients.
+pub async fn queue_fetch(
+    pool: &MobcPool,
+    letter: &SealedLetter,
+    recipients: &[Identifier],
+    request_id: Option<&RequestId>
+) -> Result<(), FetchError> {
+    let attachments = match &letter.attachments {
+        Some(value) if !value.remote.is_empty() => value.remote.clone(),
+        _ => return Ok(())
+    };
+
+    if recipients.is_empty() {
+        return Ok(());
+    }
+
+    let job = FetchJob {
+        letter: letter.id,
+        sender: letter.sender_name(),
+        recipients: recipients.to_vec(),
+        attachments,
+        request_id: request_id.map(RequestId::to_string)
+    };
+
+    let value = serde_json::to_string(&job).map_err(FetchError::Serialize)?;
+
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(FetchError::CreateRedisConnection)?;
+
+    connection
+        .rpush::<_, _, ()>(FETCH_QUEUE, value)
+        .await
+        .map_err(FetchError::Query)
+}
+
+/// Takes the oldest letter off the fetch queue.
+async fn next_job(pool: &MobcPool) -> Result<Option<FetchJob>, FetchError> {
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(FetchError::CreateRedisConnection)?;
+
+    let value: Option<String> = connection
+        .lpop(FETCH_QUEUE, None)
+        .await
+        .map_err(FetchError::Query)?;
+
+    match value {
+        Some(value) => serde_json::from_str(&value).map(Some).map_err(FetchError::Serialize),
+        None => Ok(None)
+    }
+}
+
+/// Fetches the remote attachments of a letter a few at a time and adds the copies to the letter
+/// of every recipient that still has it and has room for them.
+///
+/// Attachments that cannot be fetched are left for recipients to download from their origin.
+async fn run_job(storage: &AttachmentStorage, pool: &MobcPool, configuration: &MailConfiguration, job: FetchJob) {
+    let client = fetch_client(configuration.fetch.timeout);
+    let limit = configuration.limit.remote_attachment_size;
+    let references = job.recipients.len() as u64;
+    let request_id = job.request_id.as_deref();
+
+    let fetched: Vec<(LocalAttachment, String)> = stream::iter(&job.attachments)
+        .map(|attachment| fetch_attachment(&client, storage, pool, attachment, limit, references, request_id))
+        .buffer_unordered(configuration.fetch.concurrency.max(1))
+        .filter_map(|result| async move {
+            match result {
+                Ok(value) => Some(value),
+                Err(error) => {
+                    warn!("{}", error);
+
+                    None
+                }
+            }
+        })
+        .collect()
+        .await;
+
+    debug!("Fetched {} of {} remote attachments of letter {}", fetched.len(), job.attachments.len(), job.letter);
+
+    if fetched.is_empty() {
+        return;
+    }
+
+    for recipient in &job.recipients {
+        let quota = configuration.quota.quota(recipient);
+        let attached = match attach_fetched(pool, recipient, &job.sender, &job.letter, &fetched, quota).await {
+            Ok(value) => value,
+            Err(error) => {
+                error!("Unable to add fetched attachments to letter {} for {}: {}", job.letter, recipient, error);
+
+                false
+            }
+        };
+
+        if !attached {
+            debug!("Leaving the attachments of letter {} remote for {}", job.letter, recipient);
+
+            for (_, digest) in &fetched {
+                if let Err(error) = storage.blobs.release(pool, digest, 1).await {
+                    error!("Unable to release blob {}: {}", digest, error);
+                }
+            }
+        }
+    }
+}
+
+/// Fetches the remote attachments of queued letters until the server starts shutting down.
+///
+/// Letters are handled one at a time, so at most `fetch.concurrency` downloads run at once.
+pub async fn fetch_remote_attachments(
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>,
+    configuration: Data<LiveMailConfiguration>,
+    state: Data<CommonState>
+) {
+    let mut timer = interval(Duration::from_secs(POLL_INTERVAL));
+
+    loop {
+        timer.tick().await;
+        state.beat(FETCHER, POLL_INTERVAL * 2);
+
+        loop {
+            let _work = match state.work() {
+                Some(value) => value,
+                None => return
+            };
+
+            let configuration = configuration.current();
+
+            if !configuration.fetch.enabled {
+                break;
+            }
+
+            let job = match next_job(&pool).await {
+                Ok(Some(value)) => value,
+                Ok(None) => break,
+                Err(error) => {
+                    error!("Unable to take a letter off the fetch queue: {}", error);
+
+                    break;
+                }
+            };
+
+            // Downloads in a letter run in batches of the configured concurrency, each bounded by the timeout
+            let batches = (job.attachments.len() as u64).div_ceil(configuration.fetch.concurrency.max(1) as u64);
+
+            state.beat(FETCHER, POLL_INTERVAL * 2 + batches * configuration.fetch.timeout);
+
+            run_job(&storage, &pool, &configuration, job).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{is_public, literal_address};
+
+    fn public(address: &str) -> bool {
+        is_public(&address.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        for address in ["1.1.1.1", "8.8.8.8", "93.184.216.34", "2606:4700:4700::1111", "2a00:1450:4001:82b::200e"] {
+            assert!(public(address), "{} should be public", address);
+        }
+    }
+
+    #[test]
+    fn private_ipv4_addresses_are_refused() {
+        let addresses = [
+            "0.0.0.0", "0.1.2.3", "127.0.0.1", "10.0.0.1", "172.16.0.1", "172.31.255.255", "192.168.1.1",
+            "169.254.169.254", "100.64.0.1", "100.127.255.255", "192.0.0.1", "192.0.2.1", "198.18.0.1",
+            "198.19.255.255", "198.51.100.1", "203.0.113.1", "224.0.0.1", "240.0.0.1", "255.255.255.255"
+        ];
+
+        for address in addresses {
+            assert!(!public(address), "{} should not be public", address);
+        }
+    }
+
+    #[test]
+    fn private_ipv6_addresses_are_refused() {
+        let addresses = [
+            "::", "::1", "fc00::1", "fd12:3456::1", "fe80::1", "fec0::1", "feff::1", "ff02::1",
+            "2001:db8::1", "2001:0:4136:e378::1", "64:ff9b:1::a00:1"
+        ];
+
+        for address in addresses {
+            assert!(!public(address), "{} should not be public", address);
+        }
+    }
+
+    #[test]
+    fn embedded_ipv4_addresses_are_checked() {
+        let refused = [
+            "::ffff:127.0.0.1", "::ffff:10.0.0.1", "::127.0.0.1", "64:ff9b::7f00:1", "64:ff9b::a9fe:a9fe",
+            "64:ff9b::192.168.0.1", "2002:7f00:1::", "2002:a00:1::1", "2002:c0a8:101::"
+        ];
+
+        for address in refused {
+            assert!(!public(address), "{} should not be public", address);
+        }
+
+        for address in ["::ffff:1.1.1.1", "64:ff9b::101:101", "2002:808:808::1"] {
+            assert!(public(address), "{} should be public", address);
+        }
+    }
+
+    #[test]
+    fn literal_addresses_are_recognized() {
+        let cases = [
+            ("127.0.0.1", "127.0.0.1"),
+            ("127.0.0.1:8443", "127.0.0.1"),
+            ("::1", "::1"),
+            ("[::1]", "::1"),
+            ("[::1]:8443", "::1"),
+            ("[fe80::1]:443", "fe80::1")
+        ];
+
+        for (host, expected) in cases {
+            assert_eq!(literal_address(host), Some(expected.parse().unwrap()), "{}", host);
+        }
+    }
+
+    #[test]
+    fn host_names_are_not_literal_addresses() {
+        for host in ["example.com", "example.com:8443", "127.0.0.1.nip.io", "[example.com]", "localhost"] {
+            assert_eq!(literal_address(host), None, "{}", host);
+        }
+    }
+}
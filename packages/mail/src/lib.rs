@@ -2,3 +2,12 @@ pub mod route;
 pub mod model;
 pub mod state;
 pub mod configuration;
+pub mod rate;
+pub mod stamp;
+pub mod replay;
+pub mod mailbox;
+pub mod attachment;
+pub mod storage;
+pub mod upload;
+pub mod fetch;
+pub mod user;
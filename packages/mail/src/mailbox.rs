@@ -0,0 +1,457 @@
+use std::fmt;
+use actix_web::body::BoxBody;
+use actix_web::{HttpResponse, ResponseError};
+use lazy_static::lazy_static;
+use log::error;
+use serde::Serialize;
+use common::database::redis::{get_connection, MobcPool, RedisDatabaseError};
+use common::model::{Address, Identifier};
+use common::store::{BlobStore, BlobStoreError};
+use mobc_redis::redis::{RedisError, Script, AsyncCommands};
+
+use crate::configuration::{MailQuota, Quota};
+use crate::model::{LocalAttachment, SealedLetter, StoredLetter};
+
+/// Stores a letter for every recipient at once, provided that none of them
+/// already has a letter with the same identifier and all of them have room.
+///
+/// Keys are grouped in threes per recipient: the letter, the mailbox and the usage.
+/// Mailboxes hold letter references, which pair the sender with the letter identifier.
+/// Attachment data kept in the blob store counts towards the quota of every recipient.
+/// Returns zero on success, the recipient index if a quota would be exceeded,
+/// or the negated recipient index if the letter already exists.
+const STORE_SCRIPT: &str = r"
+local size = string.len(ARGV[1]) + tonumber(ARGV[4])
+local count = #KEYS / 3
+for i = 1, count do
+    local base = (i - 1) * 3
+    if redis.call('EXISTS', KEYS[base + 1]) == 1 then
+        return -i
+    end
+    local usage = redis.call('HMGET', KEYS[base + 3], 'bytes', 'letters')
+    local bytes = tonumber(usage[1]) or 0
+    local letters = tonumber(usage[2]) or 0
+    if bytes + size > tonumber(ARGV[3 + i * 2]) or letters + 1 > tonumber(ARGV[4 + i * 2]) then
+        return i
+    end
+end
+for i = 1, count do
+    local base = (i - 1) * 3
+    redis.call('SET', KEYS[base + 1], ARGV[1])
+    redis.call('ZADD', KEYS[base + 2], ARGV[3], ARGV[2])
+    redis.call('HINCRBY', KEYS[base + 3], 'bytes', size)
+    redis.call('HINCRBY', KEYS[base + 3], 'letters', 1)
+end
+return 0
+";
+
+/// Removes a letter from a mailbox and releases the storage it was using,
+/// including the size of its attachment data given as the second argument.
+const DELETE_SCRIPT: &str = r"
+local size = redis.call('STRLEN', KEYS[1]) + tonumber(ARGV[2])
+if redis.call('DEL', KEYS[1]) == 0 then
+    return 0
+end
+redis.call('ZREM', KEYS[2], ARGV[1])
+redis.call('HINCRBY', KEYS[3], 'bytes', -size)
+redis.call('HINCRBY', KEYS[3], 'letters', -1)
+return 1
+";
+
+/// Replaces a stored letter with a copy that holds fetched attachments, provided that it has not
+/// changed since it was read and its recipient has room for the added data.
+///
+/// Keys are the letter and the usage of its recipient. Returns zero on success, one if the quota
+/// would be exceeded or minus one if the letter has changed or is gone.
+const ATTACH_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return -1
+end
+local size = string.len(ARGV[2]) - string.len(ARGV[1]) + tonumber(ARGV[3])
+local bytes = tonumber(redis.call('HGET', KEYS[2], 'bytes')) or 0
+if bytes + size > tonumber(ARGV[4]) then
+    return 1
+end
+redis.call('SET', KEYS[1], ARGV[2])
+redis.call('HINCRBY', KEYS[2], 'bytes', size)
+return 0
+";
+
+/// How many times a letter that keeps changing is read again before fetched attachments are given up on.
+const ATTACH_ATTEMPTS: usize = 3;
+
+const LETTER: &str = "LETTER";
+const MAILBOX: &str = "MAILBOX";
+const USAGE: &str = "USAGE";
+
+lazy_static! {
+    static ref STORE: Script = Script::new(STORE_SCRIPT);
+    static ref DELETE: Script = Script::new(DELETE_SCRIPT);
+    static ref ATTACH: Script = Script::new(ATTACH_SCRIPT);
+}
+
+#[derive(Debug)]
+pub enum MailboxError {
+    QuotaExceeded(Address),
+    Duplicate(Identifier),
+    Serialize(serde_json::Error),
+    Blob(BlobStoreError),
+    CreateRedisConnection(RedisDatabaseError),
+    Query(RedisError)
+}
+
+impl fmt::Display for MailboxError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MailboxError::QuotaExceeded(address) => write!(formatter, "The mailbox of {} is full", address),
+            MailboxError::Duplicate(id) => write!(formatter, "A letter with the identifier {} is already stored", id),
+            MailboxError::Serialize(error) => write!(formatter, "{}", error),
+            MailboxError::Blob(error) => write!(formatter, "{}", error),
+            MailboxError::CreateRedisConnection(error) => write!(formatter, "{}", error),
+            MailboxError::Query(error) => write!(formatter, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for MailboxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            MailboxError::Serialize(ref error) => Some(error),
+            MailboxError::Blob(ref error) => Some(error),
+            MailboxError::CreateRedisConnection(ref error) => Some(error),
+            MailboxError::Query(ref error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+impl ResponseError for MailboxError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            MailboxError::QuotaExceeded(_) => {
+                HttpResponse::InsufficientStorage().body(self.to_string())
+            },
+            MailboxError::Duplicate(_) => {
+                HttpResponse::Conflict().body(self.to_string())
+            },
+            _ => {
+                HttpResponse::InternalServerError().body(self.to_string())
+            }
+        }
+    }
+}
+
+/// The storage used by a mailbox compared to its quota.
+#[derive(Serialize, Debug)]
+pub struct QuotaUsage {
+    /// The total size in bytes of stored letters and their attachment data.
+    pub bytes: u64,
+
+    /// The number of stored letters.
+    pub letters: u64,
+
+    /// The quota that applies to the mailbox.
+    pub quota: Quota
+}
+
+/// Identifies a letter within a mailbox, so that senders cannot take identifiers from each other.
+///
+/// The sender is an address or the placeholder for anonymous letters, as from [`SealedLetter::sender_name`].
+pub fn letter_reference(sender: &str, id: &Identifier) -> String {
+    format!("{}:{}", sender, id)
+}
+
+/// The key of a letter stored for a recipient.
+pub fn letter_key(recipient: &Identifier, sender: &str, id: &Identifier) -> String {
+    format!("{}:{}:{}", LETTER, recipient, letter_reference(sender, id))
+}
+
+/// The key of the sorted set of letter references for a recipient, scored by time of receipt.
+pub fn mailbox_key(recipient: &Identifier) -> String {
+    format!("{}:{}", MAILBOX, recipient)
+}
+
+/// The key of the hash holding the storage used by a recipient.
+pub fn usage_key(recipient: &Identifier) -> String {
+    format!("{}:{}", USAGE, recipient)
+}
+
+/// Returns each distinct recipient of a letter that belongs to this instance.
+pub fn local_recipients<'a>(letter: &'a SealedLetter, host: &str) -> Vec<&'a Address> {
+    let mut recipients: Vec<&Address> = letter.recipients
+        .iter()
+        .filter(|recipient| recipient.host == host)
+        .collect();
+
+    recipients.sort_by_key(|recipient| recipient.id.to_string());
+    recipients.dedup_by_key(|recipient| recipient.id.to_string());
+
+    recipients
+}
+
+/// Moves the data of the attachments of a letter into the blob store, with a reference for each recipient.
+async fn offload_attachments(
+    pool: &MobcPool,
+    blobs: &BlobStore,
+    stored: &mut StoredLetter,
+    references: u64
+) -> Result<(), BlobStoreError> {
+    let mut data = vec![];
+
+    if let Some(attachments) = &mut stored.letter.attachments {
+        for attachment in &mut attachments.embedded {
+            data.push((attachment.id, std::mem::take(&mut attachment.data)));
+        }
+    }
+
+    for attachment in &mut stored.local_attachments {
+        data.push((attachment.id, std::mem::take(&mut attachment.data)));
+    }
+
+    for (id, blob) in data {
+        let size = blob.len() as u64;
+
+        match blobs.put(pool, blob, references).await {
+            Ok(digest) => {
+                stored.blobs.insert(id.to_string(), digest);
+                stored.blob_size += size;
+            },
+            Err(error) => {
+                release_attachments(pool, blobs, stored, references).await;
+
+                return Err(error);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Takes references away from the attachment data of a letter, logging any failures
+/// since the data is left for garbage collection either way.
+async fn release_attachments(pool: &MobcPool, blobs: &BlobStore, stored: &StoredLetter, references: u64) {
+    for digest in stored.blobs.values() {
+        if let Err(error) = blobs.release(pool, digest, references).await {
+            error!("Unable to release blob {}: {}", digest, error);
+        }
+    }
+}
+
+/// Stores a letter in the mailbox of every local recipient if all of them are within their quota.
+///
+/// The attachment data of the letter is kept once in the blob store for all of them.
+pub async fn store_letter(
+    pool: &MobcPool,
+    blobs: &BlobStore,
+    host: &str,
+    mut stored: StoredLetter,
+    quota: &MailQuota
+) -> Result<(), MailboxError> {
+    let recipients: Vec<Address> = local_recipients(&stored.letter, host)
+        .into_iter()
+        .cloned()
+        .collect();
+
+    if recipients.is_empty() {
+        return Ok(());
+    }
+
+    let references = recipients.len() as u64;
+
+    offload_attachments(pool, blobs, &mut stored, references)
+        .await
+        .map_err(MailboxError::Blob)?;
+
+    let result = insert_letter(pool, &stored, &recipients, quota).await;
+
+    if result.is_err() {
+        release_attachments(pool, blobs, &stored, references).await;
+    }
+
+    result
+}
+
+/// Runs the store script for a letter whose attachment data is already in the blob store.
+async fn insert_letter(
+    pool: &MobcPool,
+    stored: &StoredLetter,
+    recipients: &[Address],
+    quota: &MailQuota
+) -> Result<(), MailboxError> {
+    let letter = &stored.letter;
+    let sender = letter.sender_name();
+    let value = serde_json::to_string(stored).map_err(MailboxError::Serialize)?;
+
+    let mut invocation = STORE.prepare_invoke();
+
+    invocation
+        .arg(value)
+        .arg(letter_reference(&sender, &letter.id))
+        .arg(stored.received_at.timestamp())
+        .arg(stored.blob_size);
+
+    for recipient in recipients {
+        let limit = quota.quota(&recipient.id);
+
+        invocation
+            .key(letter_key(&recipient.id, &sender, &letter.id))
+            .key(mailbox_key(&recipient.id))
+            .key(usage_key(&recipient.id))
+            .arg(limit.bytes)
+            .arg(limit.letters);
+    }
+
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(MailboxError::CreateRedisConnection)?;
+
+    let result: i64 = invocation
+        .invoke_async(&mut *connection)
+        .await
+        .map_err(MailboxError::Query)?;
+
+    match result {
+        0 => Ok(()),
+        index if index < 0 => Err(MailboxError::Duplicate(letter.id)),
+        index => {
+            let recipient = recipients[index as usize - 1].clone();
+
+            Err(MailboxError::QuotaExceeded(recipient))
+        }
+    }
+}
+
+/// Deletes a letter from the mailbox of a recipient, returning false if it did not exist.
+///
+/// The sender is the one the letter was stored under, as from [`SealedLetter::sender_name`].
+///
+/// The references the letter held to its attachment data are released.
+pub async fn delete_letter(
+    pool: &MobcPool,
+    blobs: &BlobStore,
+    recipient: &Identifier,
+    sender: &str,
+    id: &Identifier
+) -> Result<bool, MailboxError> {
+    let key = letter_key(recipient, sender, id);
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(MailboxError::CreateRedisConnection)?;
+
+    let value: Option<String> = connection
+        .get(&key)
+        .await
+        .map_err(MailboxError::Query)?;
+
+    let stored: StoredLetter = match value {
+        Some(value) => serde_json::from_str(&value).map_err(MailboxError::Serialize)?,
+        None => return Ok(false)
+    };
+
+    let deleted: bool = DELETE
+        .key(&key)
+        .key(mailbox_key(recipient))
+        .key(usage_key(recipient))
+        .arg(letter_reference(sender, id))
+        .arg(stored.blob_size)
+        .invoke_async(&mut *connection)
+        .await
+        .map_err(MailboxError::Query)?;
+
+    if deleted {
+        for digest in stored.blobs.values() {
+            blobs.release(pool, digest, 1)
+                .await
+                .map_err(MailboxError::Blob)?;
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Adds fetched copies of remote attachments, whose data is already in the blob store, to a letter stored for a recipient.
+///
+/// Each attachment comes with its blob store digest and its data counts towards the quota of the
+/// recipient. Returns false if the letter is gone or the recipient has no room, in which case the
+/// caller still holds the references to the data that were meant for the recipient.
+pub async fn attach_fetched(
+    pool: &MobcPool,
+    recipient: &Identifier,
+    sender: &str,
+    id: &Identifier,
+    fetched: &[(LocalAttachment, String)],
+    quota: &Quota
+) -> Result<bool, MailboxError> {
+    let key = letter_key(recipient, sender, id);
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(MailboxError::CreateRedisConnection)?;
+
+    for _ in 0..ATTACH_ATTEMPTS {
+        let value: Option<String> = connection
+            .get(&key)
+            .await
+            .map_err(MailboxError::Query)?;
+
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(false)
+        };
+
+        let mut stored: StoredLetter = serde_json::from_str(&value).map_err(MailboxError::Serialize)?;
+        let mut added = 0;
+
+        // A letter that already holds any of the copies keeps what it has
+        if fetched.iter().any(|(attachment, _)| stored.blobs.contains_key(&attachment.id.to_string())) {
+            return Ok(false);
+        }
+
+        for (attachment, digest) in fetched {
+            stored.blobs.insert(attachment.id.to_string(), digest.clone());
+            stored.local_attachments.push(attachment.clone());
+            stored.blob_size += attachment.size;
+            added += attachment.size;
+        }
+
+        let updated = serde_json::to_string(&stored).map_err(MailboxError::Serialize)?;
+
+        let result: i64 = ATTACH
+            .key(&key)
+            .key(usage_key(recipient))
+            .arg(value)
+            .arg(updated)
+            .arg(added)
+            .arg(quota.bytes)
+            .invoke_async(&mut *connection)
+            .await
+            .map_err(MailboxError::Query)?;
+
+        match result {
+            0 => return Ok(true),
+            1 => return Ok(false),
+            _ => continue
+        }
+    }
+
+    Ok(false)
+}
+
+/// Returns the storage used by the mailbox of a recipient.
+pub async fn quota_usage(pool: &MobcPool, recipient: &Identifier, quota: &MailQuota) -> Result<QuotaUsage, MailboxError> {
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(MailboxError::CreateRedisConnection)?;
+
+    let (bytes, letters): (Option<u64>, Option<u64>) = connection
+        .hget(usage_key(recipient), &["bytes", "letters"])
+        .await
+        .map_err(MailboxError::Query)?;
+
+    let usage = QuotaUsage {
+        bytes: bytes.unwrap_or(0),
+        letters: letters.unwrap_or(0),
+        quota: quota.quota(recipient).clone()
+    };
+
+    Ok(usage)
+}
@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
 
 use common::model::{Identifier, Address, Labels, Blob};
 
@@ -75,3 +76,136 @@ pub struct LetterAttachments {
     #[serde(default)]
     pub remote: Vec<RemoteAttachment>
 }
+
+/// An attachment uploaded by a local user so that letters can refer to it by address.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttachmentUpload {
+    /// The declared size of the attachment.
+    pub size: u64,
+
+    /// Any attachment labels.
+    #[serde(default)]
+    pub labels: Labels,
+
+    /// The encrypted attachment data.
+    pub data: Blob,
+
+    /// A digital signature for the attachment.
+    pub signature: Option<Blob>
+}
+
+/// An attachment kept on this instance along with who uploaded it, while its data is kept in the blob store.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredAttachment {
+    /// The locally unique identifier of the attachment.
+    pub id: Identifier,
+
+    /// The user that uploaded the attachment and is allowed to delete it.
+    pub owner: Identifier,
+
+    /// When the attachment was uploaded to this instance.
+    pub uploaded_at: DateTime<Utc>,
+
+    /// The identifier of the request that uploaded the attachment.
+    #[serde(default)]
+    pub request_id: Option<String>,
+
+    /// The size of the attachment data.
+    pub size: u64,
+
+    /// The SHA-256 digest the attachment data is kept under in the blob store.
+    pub digest: String,
+
+    /// Any attachment labels.
+    #[serde(default)]
+    pub labels: Labels,
+
+    /// A digital signature for the attachment.
+    pub signature: Option<Blob>
+}
+
+impl StoredAttachment {
+    /// Combines the stored details with the attachment data.
+    pub fn embed(self, data: Blob) -> EmbeddedAttachment {
+        EmbeddedAttachment {
+            id: self.id,
+            size: self.size,
+            labels: self.labels,
+            data,
+            signature: self.signature
+        }
+    }
+}
+
+/// Starts an upload of an attachment that is sent in binary chunks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadRequest {
+    /// The declared size of the attachment.
+    pub size: u64,
+
+    /// The hexadecimal SHA-256 digest of the attachment data.
+    pub sha256: String,
+
+    /// Any attachment labels.
+    #[serde(default)]
+    pub labels: Labels,
+
+    /// A digital signature for the attachment.
+    pub signature: Option<Blob>
+}
+
+/// An unfinished upload.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadSession {
+    /// The identifier the attachment will have once the upload is finalized.
+    pub id: Identifier,
+
+    /// The user that started the upload.
+    pub owner: Identifier,
+
+    /// When the upload was started.
+    pub created_at: DateTime<Utc>,
+
+    /// The identifier of the request that started the upload.
+    #[serde(default)]
+    pub request_id: Option<String>,
+
+    /// The declared upload.
+    pub request: UploadRequest
+}
+
+/// How much of an upload has been received.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadProgress {
+    /// The identifier of the upload.
+    pub id: Identifier,
+
+    /// The number of bytes received, which is where the next range must start.
+    pub offset: u64,
+
+    /// The declared size of the attachment.
+    pub size: u64,
+
+    /// When the upload is discarded unless more data is received.
+    pub expires_at: DateTime<Utc>
+}
+
+/// A received letter whose remote attachments are waiting to be copied to this instance.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchJob {
+    /// The identifier of the letter.
+    pub letter: Identifier,
+
+    /// The sender the letter is stored under, an address or the anonymous placeholder.
+    pub sender: String,
+
+    /// The local recipients the letter was stored for.
+    pub recipients: Vec<Identifier>,
+
+    /// The remote attachments to fetch.
+    pub attachments: Vec<RemoteAttachment>,
+
+    /// The identifier of the request that delivered the letter.
+    #[serde(default)]
+    pub request_id: Option<String>
+}
@@ -1,7 +1,12 @@
+use std::collections::BTreeMap;
 use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
 use common::model::{Identifier, Address, Labels, Blob};
 
-use super::LetterAttachments;
+use super::{LetterAttachments, LocalAttachment};
+
+/// Stands in for the sender of anonymous letters in keys that are scoped by sender.
+pub const ANONYMOUS: &str = "ANONYMOUS";
 
 /// A letter that has been partially encrypted by the client.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -28,6 +33,155 @@ pub struct SealedLetter {
     /// The encrypted body of the letter.
     pub body: Option<Blob>,
 
+    /// When the letter was sent according to the sender, covered by the letter signature.
+    #[serde(default)]
+    pub sent_at: Option<DateTime<Utc>>,
+
     /// A digital signature for the letter.
-    pub signature: Option<Blob>
+    pub signature: Option<Blob>,
+
+    /// A proof-of-work stamp bound to the identifier and recipients of the letter.
+    #[serde(default)]
+    pub stamp: Option<String>
+}
+
+impl SealedLetter {
+    /// Returns the sender address, or a placeholder for anonymous letters, for scoping keys by sender.
+    pub fn sender_name(&self) -> String {
+        match &self.sender {
+            Some(value) => value.to_string(),
+            None => String::from(ANONYMOUS)
+        }
+    }
+}
+
+/// A letter that has been received and stored for a local recipient.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredLetter {
+    /// When the letter was received by this instance.
+    pub received_at: DateTime<Utc>,
+
+    /// The identifier of the request that delivered the letter.
+    #[serde(default)]
+    pub request_id: Option<String>,
+
+    /// The received letter.
+    pub letter: SealedLetter,
+
+    /// Copies of the remote attachments of the letter that were fetched when it was received.
+    #[serde(default)]
+    pub local_attachments: Vec<LocalAttachment>,
+
+    /// The blob store digest of the data of each attachment, keyed by attachment identifier.
+    ///
+    /// The data of these attachments is left empty in the stored letter.
+    #[serde(default)]
+    pub blobs: BTreeMap<String, String>,
+
+    /// The total size of the attachment data kept in the blob store.
+    #[serde(default)]
+    pub blob_size: u64
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use common::model::{Address, Blob, Identifier};
+    use common::wire::Format;
+
+    use crate::model::{EmbeddedAttachment, LetterAttachments, RemoteAttachment};
+    use super::SealedLetter;
+
+    fn address() -> Address {
+        Address {
+            id: Identifier::new(),
+            host: String::from("example.com")
+        }
+    }
+
+    fn letter() -> SealedLetter {
+        let attachments = LetterAttachments {
+            embedded: vec![
+                EmbeddedAttachment {
+                    id: Identifier::new(),
+                    size: 4,
+                    labels: Default::default(),
+                    data: Blob::from(vec![0, 1, 254, 255]),
+                    signature: Some(Blob::from(vec![7; 64]))
+                }
+            ],
+            remote: vec![
+                RemoteAttachment {
+                    id: Identifier::new(),
+                    address: address(),
+                    size: 1048576,
+                    labels: Default::default(),
+                    signature: None
+                }
+            ]
+        };
+
+        SealedLetter {
+            id: Identifier::new(),
+            sender: Some(address()),
+            recipients: vec![address(), address()],
+            attachments: Some(attachments),
+            labels: Default::default(),
+            subject: Some(Blob::from(vec![1, 2, 3])),
+            body: Some(Blob::from(vec![42; 300])),
+            sent_at: Some(Utc.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap()),
+            signature: Some(Blob::from(vec![9; 64])),
+            stamp: Some(String::from("stamp"))
+        }
+    }
+
+    /// Encodes a letter in a format and decodes it again, comparing the results as JSON.
+    fn round_trip(format: Format) -> Vec<u8> {
+        let letter = letter();
+        let encoded = format.encode(&letter).unwrap();
+        let decoded: SealedLetter = format.decode(&encoded).unwrap();
+
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&letter).unwrap());
+
+        encoded
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn letters_round_trip_through_cbor_with_byte_strings() {
+        let encoded = round_trip(Format::Cbor);
+
+        // A byte string of three bytes, rather than the base64 text JSON uses
+        assert!(contains(&encoded, &[0x43, 1, 2, 3]));
+        assert!(!contains(&encoded, b"AQID"));
+    }
+
+    #[test]
+    fn letters_round_trip_through_message_pack_with_byte_strings() {
+        let encoded = round_trip(Format::MessagePack);
+
+        // A bin 8 of three bytes, rather than the base64 text JSON uses
+        assert!(contains(&encoded, &[0xc4, 3, 1, 2, 3]));
+        assert!(!contains(&encoded, b"AQID"));
+    }
+
+    #[test]
+    fn letters_round_trip_through_json_with_base64() {
+        let encoded = round_trip(Format::Json);
+
+        assert!(contains(&encoded, b"\"AQID\""));
+    }
+
+    #[test]
+    fn binary_formats_are_smaller_than_json() {
+        let json = Format::Json.encode(&letter()).unwrap();
+
+        assert!(Format::Cbor.encode(&letter()).unwrap().len() < json.len());
+        assert!(Format::MessagePack.encode(&letter()).unwrap().len() < json.len());
+    }
 }
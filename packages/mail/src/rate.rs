@@ -0,0 +1,185 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use actix_web::body::BoxBody;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use lazy_static::lazy_static;
+use log::warn;
+use common::database::redis::{get_connection, MobcPool, RedisDatabaseError};
+use mobc_redis::redis::{RedisError, Script};
+
+use crate::configuration::{RateLimit, MailConfiguration};
+use crate::user::authenticated_user;
+
+/// A token bucket that is refilled using the clock of the Redis server, so
+/// that every server process sharing the database observes the same buckets.
+///
+/// Returns whether a token was taken and how many seconds to wait otherwise.
+const TOKEN_BUCKET_SCRIPT: &str = r"
+local capacity = tonumber(ARGV[1])
+local refill = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'timestamp')
+local tokens = tonumber(bucket[1]) or capacity
+local timestamp = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - timestamp) * refill)
+local allowed = 0
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+else
+    wait = math.ceil((1 - tokens) / refill)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'timestamp', tostring(now))
+redis.call('EXPIRE', KEYS[1], math.ceil(capacity / refill) + 1)
+return {allowed, wait}
+";
+
+const RATE_LIMIT: &str = "RATE_LIMIT";
+
+/// Whether requests without a client address have been reported, which is only done once.
+static UNADDRESSED_REPORTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref TOKEN_BUCKET: Script = Script::new(TOKEN_BUCKET_SCRIPT);
+}
+
+/// The subject that a rate limit is applied to.
+pub enum RateLimitKey<'a> {
+    Client(&'a str),
+    Host(&'a str),
+    Sender(&'a str),
+    User(&'a str)
+}
+
+impl fmt::Display for RateLimitKey<'_> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RateLimitKey::Client(value) => write!(formatter, "{}:CLIENT:{}", RATE_LIMIT, value),
+            RateLimitKey::Host(value) => write!(formatter, "{}:HOST:{}", RATE_LIMIT, value),
+            RateLimitKey::Sender(value) => write!(formatter, "{}:SENDER:{}", RATE_LIMIT, value),
+            RateLimitKey::User(value) => write!(formatter, "{}:USER:{}", RATE_LIMIT, value)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RateLimitError {
+    Exceeded(u64),
+    CreateRedisConnection(RedisDatabaseError),
+    Script(RedisError)
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RateLimitError::Exceeded(seconds) => {
+                write!(formatter, "Rate limit exceeded, retry after {} seconds", seconds)
+            },
+            RateLimitError::CreateRedisConnection(error) => {
+                write!(formatter, "{}", error)
+            },
+            RateLimitError::Script(error) => {
+                write!(formatter, "{}", error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RateLimitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            RateLimitError::Exceeded(_) => None,
+            RateLimitError::CreateRedisConnection(ref error) => Some(error),
+            RateLimitError::Script(ref error) => Some(error)
+        }
+    }
+}
+
+impl ResponseError for RateLimitError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            RateLimitError::Exceeded(seconds) => {
+                HttpResponse::TooManyRequests()
+                    .insert_header((RETRY_AFTER, seconds.to_string()))
+                    .body(self.to_string())
+            },
+            _ => {
+                HttpResponse::InternalServerError().body(self.to_string())
+            }
+        }
+    }
+}
+
+/// Takes a token from the bucket for the key if a limit has been configured.
+pub async fn throttle(pool: &MobcPool, key: RateLimitKey<'_>, limit: &Option<RateLimit>) -> Result<(), RateLimitError> {
+    let limit = match limit {
+        Some(value) => value,
+        None => return Ok(())
+    };
+
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(RateLimitError::CreateRedisConnection)?;
+
+    let (allowed, wait) = TOKEN_BUCKET
+        .key(key.to_string())
+        .arg(limit.capacity)
+        .arg(limit.refill)
+        .invoke_async::<_, (u64, u64)>(&mut *connection)
+        .await
+        .map_err(RateLimitError::Script)?;
+
+    if allowed == 0 {
+        return Err(RateLimitError::Exceeded(wait));
+    }
+
+    Ok(())
+}
+
+/// Returns the address a request comes from, taken from the configured header if there is one.
+///
+/// The header is read from the right, skipping the entries of the proxies in front of the one
+/// that saw the client, since anything further left was written by the client.
+fn client_address(request: &HttpRequest, configuration: &MailConfiguration) -> Option<String> {
+    match &configuration.rate.client_header {
+        Some(header) => request
+            .headers()
+            .get(header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                let entries: Vec<&str> = value.split(',').collect();
+                let hops = configuration.rate.client_hops.clamp(1, entries.len());
+
+                entries.get(entries.len() - hops).copied()
+            })
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty()),
+        None => request
+            .peer_addr()
+            .map(|address| address.ip().to_string())
+    }
+}
+
+/// Applies the rate limits for the client IP address and authenticated user of a request.
+pub async fn throttle_request(pool: &MobcPool, request: &HttpRequest, configuration: &MailConfiguration) -> Result<(), RateLimitError> {
+    match client_address(request, configuration) {
+        Some(address) => {
+            throttle(pool, RateLimitKey::Client(&address), &configuration.rate.client).await?;
+        },
+        None if configuration.rate.client.is_some() && !UNADDRESSED_REPORTED.swap(true, Ordering::Relaxed) => {
+            warn!("Requests without a client address, such as over Unix sockets, are not limited by mail.rate.client unless mail.rate.client_header is set");
+        },
+        None => {}
+    }
+
+    if let Some(user) = authenticated_user(request) {
+        let id = user.id.to_string();
+
+        throttle(pool, RateLimitKey::User(&id), &configuration.rate.user).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use actix_web::body::BoxBody;
+use actix_web::{HttpResponse, ResponseError};
+use common::database::redis::{get_connection, MobcPool, RedisDatabaseError};
+use common::model::Identifier;
+use common::serialization::encode_hex;
+use mobc_redis::redis::{cmd, AsyncCommands, RedisError};
+use sha2::{Digest, Sha256};
+
+use crate::configuration::MailReplay;
+use crate::model::SealedLetter;
+
+const RECEIVED: &str = "RECEIVED";
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Conflict(Identifier),
+    Serialize(serde_json::Error),
+    CreateRedisConnection(RedisDatabaseError),
+    Query(RedisError)
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Conflict(id) => {
+                write!(formatter, "A different letter with the identifier {} has already been received", id)
+            },
+            ReplayError::Serialize(error) => {
+                write!(formatter, "{}", error)
+            },
+            ReplayError::CreateRedisConnection(error) => {
+                write!(formatter, "{}", error)
+            },
+            ReplayError::Query(error) => {
+                write!(formatter, "{}", error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ReplayError::Conflict(_) => None,
+            ReplayError::Serialize(ref error) => Some(error),
+            ReplayError::CreateRedisConnection(ref error) => Some(error),
+            ReplayError::Query(ref error) => Some(error)
+        }
+    }
+}
+
+impl ResponseError for ReplayError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            ReplayError::Conflict(_) => {
+                HttpResponse::Conflict().body(self.to_string())
+            },
+            _ => {
+                HttpResponse::InternalServerError().body(self.to_string())
+            }
+        }
+    }
+}
+
+/// The outcome of claiming a letter identifier.
+pub enum Claim {
+    /// The letter has not been seen before and the key now records it.
+    New(String),
+
+    /// The exact same letter has already been received.
+    Retry
+}
+
+/// Hashes the canonical JSON representation of a letter.
+///
+/// Converting to a value first sorts object keys, so label order does not matter.
+fn fingerprint(letter: &SealedLetter) -> Result<String, ReplayError> {
+    let value = serde_json::to_value(letter).map_err(ReplayError::Serialize)?;
+    let bytes = serde_json::to_vec(&value).map_err(ReplayError::Serialize)?;
+    let digest = Sha256::digest(bytes);
+
+    Ok(encode_hex(&digest))
+}
+
+/// Records the identifier of a letter for its sender unless it has been received before.
+pub async fn claim_letter(pool: &MobcPool, letter: &SealedLetter, replay: &MailReplay) -> Result<Claim, ReplayError> {
+    let key = format!("{}:{}:{}", RECEIVED, letter.sender_name(), letter.id);
+    let fingerprint = fingerprint(letter)?;
+
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(ReplayError::CreateRedisConnection)?;
+
+    let claimed: Option<String> = cmd("SET")
+        .arg(&key)
+        .arg(&fingerprint)
+        .arg("NX")
+        .arg("EX")
+        .arg(replay.expiry)
+        .query_async(&mut *connection)
+        .await
+        .map_err(ReplayError::Query)?;
+
+    if claimed.is_some() {
+        return Ok(Claim::New(key));
+    }
+
+    let existing: Option<String> = connection
+        .get(&key)
+        .await
+        .map_err(ReplayError::Query)?;
+
+    match existing {
+        Some(value) if value == fingerprint => Ok(Claim::Retry),
+        _ => Err(ReplayError::Conflict(letter.id))
+    }
+}
+
+/// Forgets a claimed letter identifier so that the letter can be sent again.
+pub async fn release_letter(pool: &MobcPool, key: &str) -> Result<(), ReplayError> {
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(ReplayError::CreateRedisConnection)?;
+
+    connection
+        .del::<&str, ()>(key)
+        .await
+        .map_err(ReplayError::Query)?;
+
+    Ok(())
+}
@@ -0,0 +1,352 @@
+use std::fmt;
+use std::fs::{remove_file, write};
+use std::io;
+use actix_web::body::BoxBody;
+use actix_web::error::PayloadError;
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::web::{BytesMut, Data, Path, Payload};
+use actix_web::{delete, get, post, HttpRequest, HttpResponse, Responder, Result, ResponseError};
+use chrono::Utc;
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE;
+use common::model::{Address, Blob, Identifier, TypeConversionError};
+use common::request::{request_id, RequestId};
+use common::state::{CommonState, Unavailable};
+use common::serialization::encode_hex;
+use common::store::{blocking, BlobStoreError};
+use common::database::redis::MobcPool;
+use common::wire::{Format, Wire, WireError};
+
+use crate::attachment::{store_attachment, load_attachment, delete_attachment, AttachmentError};
+use crate::configuration::LiveMailConfiguration;
+use crate::model::{AttachmentUpload, StoredAttachment};
+use crate::rate::{throttle_request, RateLimitError};
+use crate::storage::AttachmentStorage;
+use crate::user::authenticated_user;
+
+/// The header carrying the signature of an attachment that is downloaded as binary data.
+pub const ATTACHMENT_SIGNATURE_HEADER: &str = "x-attachment-signature";
+
+/// Room for the labels, the signature and the JSON around the encoded attachment data.
+const UPLOAD_OVERHEAD: usize = 65536;
+
+#[derive(Debug)]
+pub enum UploadAttachmentError {
+    Unavailable(Unavailable),
+    Unauthorized,
+    RateLimit(RateLimitError),
+    Payload(PayloadError),
+    TooLarge(usize),
+    Body(WireError),
+    SizeMismatch(u64, usize),
+    Unsigned,
+    Io(io::Error),
+    Store(AttachmentError)
+}
+
+impl fmt::Display for UploadAttachmentError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadAttachmentError::Unavailable(error) => {
+                write!(formatter, "{}", error)
+            },
+            UploadAttachmentError::Unauthorized => {
+                write!(formatter, "Authentication is required")
+            },
+            UploadAttachmentError::RateLimit(error) => {
+                write!(formatter, "{}", error)
+            },
+            UploadAttachmentError::Payload(error) => {
+                write!(formatter, "{}", error)
+            },
+            UploadAttachmentError::TooLarge(limit) => {
+                write!(formatter, "Attachments uploaded at once must not exceed {} bytes, larger ones are sent in ranges through /upload", limit)
+            },
+            UploadAttachmentError::Body(error) => {
+                write!(formatter, "{}", error)
+            },
+            UploadAttachmentError::SizeMismatch(declared, actual) => {
+                write!(formatter, "The attachment was declared as {} bytes but is {} bytes", declared, actual)
+            },
+            UploadAttachmentError::Unsigned => {
+                write!(formatter, "Unsigned attachments are forbidden")
+            },
+            UploadAttachmentError::Io(error) => {
+                write!(formatter, "{}", error)
+            },
+            UploadAttachmentError::Store(error) => {
+                write!(formatter, "{}", error)
+            }
+        }
+    }
+}
+
+impl ResponseError for UploadAttachmentError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            UploadAttachmentError::Unavailable(error) => {
+                error.error_response()
+            },
+            UploadAttachmentError::Unauthorized => {
+                HttpResponse::Unauthorized().body(self.to_string())
+            },
+            UploadAttachmentError::RateLimit(error) => {
+                error.error_response()
+            },
+            UploadAttachmentError::Payload(error) => {
+                error.error_response()
+            },
+            UploadAttachmentError::TooLarge(_) => {
+                HttpResponse::PayloadTooLarge().body(self.to_string())
+            },
+            UploadAttachmentError::Body(error) => {
+                error.error_response()
+            },
+            UploadAttachmentError::Io(_) => {
+                HttpResponse::InternalServerError().body(self.to_string())
+            },
+            UploadAttachmentError::Store(error) => {
+                error.error_response()
+            },
+            _ => {
+                HttpResponse::BadRequest().body(self.to_string())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AttachmentRequestError {
+    Unavailable(Unavailable),
+    Unauthorized,
+    InvalidIdentifier(TypeConversionError),
+    TooLargeToEmbed(Identifier, u64),
+    Io(io::Error),
+    Blob(BlobStoreError),
+    Attachment(AttachmentError)
+}
+
+impl fmt::Display for AttachmentRequestError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttachmentRequestError::Unavailable(error) => {
+                write!(formatter, "{}", error)
+            },
+            AttachmentRequestError::Unauthorized => {
+                write!(formatter, "Authentication is required")
+            },
+            AttachmentRequestError::InvalidIdentifier(error) => {
+                write!(formatter, "{}", error)
+            },
+            AttachmentRequestError::TooLargeToEmbed(id, limit) => {
+                write!(formatter, "Attachment {} is larger than {} bytes and is only served from /attachment/{}/data", id, limit, id)
+            },
+            AttachmentRequestError::Io(error) => {
+                write!(formatter, "{}", error)
+            },
+            AttachmentRequestError::Blob(error) => {
+                write!(formatter, "{}", error)
+            },
+            AttachmentRequestError::Attachment(error) => {
+                write!(formatter, "{}", error)
+            }
+        }
+    }
+}
+
+impl ResponseError for AttachmentRequestError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            AttachmentRequestError::Unavailable(error) => {
+                error.error_response()
+            },
+            AttachmentRequestError::Unauthorized => {
+                HttpResponse::Unauthorized().body(self.to_string())
+            },
+            AttachmentRequestError::InvalidIdentifier(_) => {
+                HttpResponse::BadRequest().body(self.to_string())
+            },
+            AttachmentRequestError::TooLargeToEmbed(_, _) => {
+                HttpResponse::PayloadTooLarge().body(self.to_string())
+            },
+            AttachmentRequestError::Io(_) | AttachmentRequestError::Blob(_) => {
+                HttpResponse::InternalServerError().body(self.to_string())
+            },
+            AttachmentRequestError::Attachment(error) => {
+                error.error_response()
+            }
+        }
+    }
+}
+
+/// Reads a request body, giving up as soon as it grows beyond the limit.
+async fn read_body(mut payload: Payload, limit: usize) -> Result<BytesMut, UploadAttachmentError> {
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(UploadAttachmentError::Payload)?;
+
+        if body.len() + chunk.len() > limit {
+            return Err(UploadAttachmentError::TooLarge(limit));
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+#[post("/attachment")]
+pub async fn upload_attachment(
+    request: HttpRequest,
+    payload: Payload,
+    configuration: Data<LiveMailConfiguration>,
+    state: Data<CommonState>,
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    state.available().map_err(UploadAttachmentError::Unavailable)?;
+
+    let user = authenticated_user(&request).ok_or(UploadAttachmentError::Unauthorized)?;
+    let configuration = configuration.current();
+
+    throttle_request(&pool, &request, &configuration)
+        .await
+        .map_err(UploadAttachmentError::RateLimit)?;
+
+    // Anything larger than a letter could embed goes through a resumable upload, which is not held in memory
+    let limit = configuration.limit.embedded_attachment_size;
+    let body = read_body(payload, Blob::encoded_len(limit) as usize + UPLOAD_OVERHEAD).await?;
+    let upload: AttachmentUpload = Format::from_content_type(&request)
+        .and_then(|format| format.decode(&body))
+        .map_err(UploadAttachmentError::Body)?;
+
+    if upload.data.len() as u64 > limit {
+        return Err(UploadAttachmentError::TooLarge(limit as usize).into());
+    }
+
+    if upload.data.len() as u64 != upload.size {
+        return Err(UploadAttachmentError::SizeMismatch(upload.size, upload.data.len()).into());
+    }
+
+    if upload.signature.is_none() && !configuration.accept.unsigned_attachments {
+        return Err(UploadAttachmentError::Unsigned.into());
+    }
+
+    let stored = StoredAttachment {
+        id: Identifier::new(),
+        owner: user.id,
+        uploaded_at: Utc::now(),
+        request_id: request_id(&request).as_ref().map(RequestId::to_string),
+        size: upload.size,
+        digest: encode_hex(&Sha256::digest(upload.data.as_ref())),
+        labels: upload.labels,
+        signature: upload.signature
+    };
+
+    let path = storage.upload_path(&stored.id);
+    let written = path.clone();
+
+    blocking(move || write(written, upload.data))
+        .await
+        .map_err(UploadAttachmentError::Io)?;
+
+    if let Err(error) = store_attachment(&pool, &storage, &stored, configuration.quota.quota(&user.id), 0).await {
+        blocking(move || remove_file(path))
+            .await
+            .map_err(UploadAttachmentError::Io)?;
+
+        return Err(UploadAttachmentError::Store(error).into());
+    }
+
+    let address = Address {
+        id: stored.id,
+        host: state.host.clone()
+    };
+
+    Ok(Wire(address).customize().with_status(StatusCode::CREATED))
+}
+
+/// Responds with an attachment and its data encoded inside, for attachments no larger than a letter could embed.
+#[get("/attachment/{id}")]
+pub async fn download_attachment(
+    path: Path<String>,
+    configuration: Data<LiveMailConfiguration>,
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    let id = Identifier::try_from(path.into_inner()).map_err(AttachmentRequestError::InvalidIdentifier)?;
+
+    let stored = load_attachment(&pool, &id)
+        .await
+        .map_err(AttachmentRequestError::Attachment)?;
+
+    // Larger attachments would be read into memory and encoded in full, so they are only streamed
+    let limit = configuration.current().limit.embedded_attachment_size;
+
+    if stored.size > limit {
+        return Err(AttachmentRequestError::TooLargeToEmbed(id, limit).into());
+    }
+
+    let data = storage.blobs
+        .get(&stored.digest)
+        .await
+        .map_err(AttachmentRequestError::Blob)?;
+
+    Ok(Wire(stored.embed(data)))
+}
+
+/// Streams the data of an attachment without encoding it, honoring range requests so downloads can resume.
+#[get("/attachment/{id}/data")]
+pub async fn download_attachment_data(
+    request: HttpRequest,
+    path: Path<String>,
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    let id = Identifier::try_from(path.into_inner()).map_err(AttachmentRequestError::InvalidIdentifier)?;
+
+    let stored = load_attachment(&pool, &id)
+        .await
+        .map_err(AttachmentRequestError::Attachment)?;
+
+    let mut response = storage.blobs
+        .serve(&request, &stored.digest)
+        .await
+        .map_err(AttachmentRequestError::Blob)?;
+
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+
+    if let Some(signature) = &stored.signature {
+        let value = URL_SAFE.encode(signature);
+
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().insert(HeaderName::from_static(ATTACHMENT_SIGNATURE_HEADER), value);
+        }
+    }
+
+    Ok(response)
+}
+
+#[delete("/attachment/{id}")]
+pub async fn remove_attachment(
+    request: HttpRequest,
+    path: Path<String>,
+    state: Data<CommonState>,
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    state.available().map_err(AttachmentRequestError::Unavailable)?;
+
+    let user = authenticated_user(&request).ok_or(AttachmentRequestError::Unauthorized)?;
+    let id = Identifier::try_from(path.into_inner()).map_err(AttachmentRequestError::InvalidIdentifier)?;
+
+    delete_attachment(&pool, &storage, &user.id, &id)
+        .await
+        .map_err(AttachmentRequestError::Attachment)?;
+
+    Ok(HttpResponse::NoContent())
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use actix_web::body::BoxBody;
+use actix_web::web::{Data, Path, Query};
+use actix_web::{delete, get, HttpRequest, HttpResponse, Responder, Result, ResponseError};
+use serde::Deserialize;
+use common::model::{Address, Identifier, ParseError, TypeConversionError};
+use common::database::redis::MobcPool;
+use common::state::{CommonState, Unavailable};
+use common::wire::Wire;
+
+use crate::configuration::LiveMailConfiguration;
+use crate::model::ANONYMOUS;
+use crate::mailbox::{delete_letter, quota_usage, MailboxError};
+use crate::storage::AttachmentStorage;
+use crate::user::authenticated_user;
+
+#[derive(Debug)]
+pub enum DeleteMailError {
+    Unavailable(Unavailable),
+    Unauthorized,
+    InvalidIdentifier(TypeConversionError),
+    InvalidSender(ParseError),
+    NotFound(Identifier),
+    Mailbox(MailboxError)
+}
+
+impl fmt::Display for DeleteMailError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeleteMailError::Unavailable(error) => {
+                write!(formatter, "{}", error)
+            },
+            DeleteMailError::Unauthorized => {
+                write!(formatter, "Authentication is required")
+            },
+            DeleteMailError::InvalidIdentifier(error) => {
+                write!(formatter, "{}", error)
+            },
+            DeleteMailError::InvalidSender(error) => {
+                write!(formatter, "{}", error)
+            },
+            DeleteMailError::NotFound(id) => {
+                write!(formatter, "No letter with the identifier {} exists", id)
+            },
+            DeleteMailError::Mailbox(error) => {
+                write!(formatter, "{}", error)
+            }
+        }
+    }
+}
+
+impl ResponseError for DeleteMailError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            DeleteMailError::Unavailable(error) => {
+                error.error_response()
+            },
+            DeleteMailError::Unauthorized => {
+                HttpResponse::Unauthorized().body(self.to_string())
+            },
+            DeleteMailError::InvalidIdentifier(_) | DeleteMailError::InvalidSender(_) => {
+                HttpResponse::BadRequest().body(self.to_string())
+            },
+            DeleteMailError::NotFound(_) => {
+                HttpResponse::NotFound().body(self.to_string())
+            },
+            DeleteMailError::Mailbox(error) => {
+                error.error_response()
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MailQuotaError {
+    Unauthorized,
+    Mailbox(MailboxError)
+}
+
+impl fmt::Display for MailQuotaError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MailQuotaError::Unauthorized => {
+                write!(formatter, "Authentication is required")
+            },
+            MailQuotaError::Mailbox(error) => {
+                write!(formatter, "{}", error)
+            }
+        }
+    }
+}
+
+impl ResponseError for MailQuotaError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            MailQuotaError::Unauthorized => {
+                HttpResponse::Unauthorized().body(self.to_string())
+            },
+            MailQuotaError::Mailbox(error) => {
+                error.error_response()
+            }
+        }
+    }
+}
+
+#[get("/quota")]
+pub async fn mail_quota(
+    request: HttpRequest,
+    configuration: Data<LiveMailConfiguration>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    let user = authenticated_user(&request).ok_or(MailQuotaError::Unauthorized)?;
+    let configuration = configuration.current();
+
+    let usage = quota_usage(&pool, &user.id, &configuration.quota)
+        .await
+        .map_err(MailQuotaError::Mailbox)?;
+
+    Ok(Wire(usage))
+}
+
+/// Identifies which sender a letter to delete came from, since identifiers are only unique per sender.
+#[derive(Deserialize, Debug)]
+pub struct DeleteMailQuery {
+    /// The address of the sender, left out for anonymous letters.
+    pub sender: Option<String>
+}
+
+#[delete("/{id}")]
+pub async fn delete_mail(
+    request: HttpRequest,
+    path: Path<String>,
+    query: Query<DeleteMailQuery>,
+    state: Data<CommonState>,
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    state.available().map_err(DeleteMailError::Unavailable)?;
+
+    let user = authenticated_user(&request).ok_or(DeleteMailError::Unauthorized)?;
+    let id = Identifier::try_from(path.into_inner()).map_err(DeleteMailError::InvalidIdentifier)?;
+    let sender = match &query.sender {
+        Some(value) => Address::try_from(value.as_str()).map_err(DeleteMailError::InvalidSender)?.to_string(),
+        None => String::from(ANONYMOUS)
+    };
+
+    let deleted = delete_letter(&pool, &storage.blobs, &user.id, &sender, &id)
+        .await
+        .map_err(DeleteMailError::Mailbox)?;
+
+    if !deleted {
+        return Err(DeleteMailError::NotFound(id).into());
+    }
+
+    Ok(HttpResponse::NoContent())
+}
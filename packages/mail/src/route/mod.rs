@@ -1,7 +1,14 @@
 pub mod send;
 pub mod receive;
 pub mod admin;
+pub mod policy;
+pub mod mailbox;
+pub mod attachment;
+pub mod upload;
 
 pub use send::*;
 pub use receive::*;
-pub use admin::*;
+pub use policy::*;
+pub use mailbox::*;
+pub use attachment::*;
+pub use upload::*;
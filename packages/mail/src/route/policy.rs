@@ -0,0 +1,41 @@
+use actix_web::web::Data;
+use actix_web::{get, Responder};
+use serde::{Serialize, Deserialize};
+use common::wire::{Format, Wire};
+
+use crate::configuration::{LiveMailConfiguration, MailAccept, MailRequire, MailLimit};
+
+/// The mail policy of this instance as advertised to senders.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MailPolicy {
+    /// Accepted mail data.
+    pub accept: MailAccept,
+
+    /// Required mail data.
+    pub require: MailRequire,
+
+    /// Limitations for letters.
+    pub limit: MailLimit,
+
+    /// Media types letters can be sent in, which instances that predate binary formats leave out.
+    #[serde(default)]
+    pub formats: Vec<String>
+}
+
+#[get("/policy")]
+pub async fn mail_policy(configuration: Data<LiveMailConfiguration>) -> impl Responder {
+    let configuration = configuration.current();
+    let mut accept = configuration.accept.clone();
+
+    // Senders need the difficulty that is actually enforced to mint stamps, even where it is left at the default
+    accept.stamp_difficulty = accept.effective_stamp_difficulty();
+
+    let policy = MailPolicy {
+        accept,
+        require: configuration.require.clone(),
+        limit: configuration.limit.clone(),
+        formats: Format::media_types()
+    };
+
+    Wire(policy)
+}
@@ -1,25 +1,49 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt;
-use log::debug;
-use actix_web::web::{Data, Json};
+use log::{debug, warn};
+use actix_web::web::Data;
 use actix_web::body::BoxBody;
-use actix_web::{post, HttpResponse, Responder, Result, ResponseError};
-use common::model::Labels;
+use actix_web::{post, HttpRequest, HttpResponse, Responder, Result, ResponseError};
+use chrono::{DateTime, Utc};
+use common::model::{Address, Identifier, Labels};
+use common::state::{CommonState, Unavailable};
+use common::request::{request_id, RequestId};
+use common::wire::{Wire, WireConfig, WireError};
 use common::database::redis::{get_connection, MobcPool, RedisDatabaseError};
 use mobc_redis::redis::{AsyncCommands, RedisError};
 
-use crate::model::{SealedLetter, LetterAttachments};
-use crate::configuration::MailConfiguration;
+use crate::model::{SealedLetter, StoredLetter, LetterAttachments};
+use crate::configuration::{MailConfiguration, MailLimit, LiveMailConfiguration};
+use crate::rate::{throttle_request, throttle, RateLimitKey, RateLimitError};
+use crate::stamp::{check_stamp, release_stamp, record_contact, StampError};
+use crate::replay::{claim_letter, release_letter, Claim, ReplayError};
+use crate::mailbox::{local_recipients, store_letter, MailboxError};
+use crate::storage::AttachmentStorage;
+use crate::fetch::queue_fetch;
 
 #[derive(Debug)]
 pub enum ReceiveMailError {
+    Unavailable(Unavailable),
+    TooLarge(u64),
+    Body(WireError),
     NoRecipients,
     AnonymousSender,
     Unsigned,
     UnsignedAttachments,
+    AttachmentSizeMismatch(Identifier, u64, usize),
+    RemoteAttachmentTooLarge(Identifier, u64),
+    TooManyRemoteAttachments(u64),
     NoSubject,
     NoBody,
+    NoSentAt,
+    SentInFuture,
+    Expired,
     MissingLabels(HashSet<String>),
+    RateLimit(RateLimitError),
+    Stamp(StampError),
+    Replay(ReplayError),
+    QuotaExceeded(Address),
+    Store(MailboxError),
     CreateRedisConnection(RedisDatabaseError),
     Increment(RedisError)
 }
@@ -27,6 +51,15 @@ pub enum ReceiveMailError {
 impl fmt::Display for ReceiveMailError {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            ReceiveMailError::Unavailable(error) => {
+                write!(formatter, "{}", error)
+            },
+            ReceiveMailError::TooLarge(limit) => {
+                write!(formatter, "Letters must not exceed {} bytes, which leaves room for the largest subject, body and embedded attachments this instance accepts", limit)
+            },
+            ReceiveMailError::Body(error) => {
+                write!(formatter, "{}", error)
+            },
             ReceiveMailError::NoRecipients => {
                 write!(formatter, "At least one recipient must be provided")
             },
@@ -39,15 +72,48 @@ impl fmt::Display for ReceiveMailError {
             ReceiveMailError::UnsignedAttachments => {
                 write!(formatter, "Unsigned attachments are forbidden")
             },
+            ReceiveMailError::AttachmentSizeMismatch(id, declared, actual) => {
+                write!(formatter, "Attachment {} was declared as {} bytes but is {} bytes", id, declared, actual)
+            },
+            ReceiveMailError::RemoteAttachmentTooLarge(id, limit) => {
+                write!(formatter, "Attachment {} is declared larger than the limit of {} bytes", id, limit)
+            },
+            ReceiveMailError::TooManyRemoteAttachments(limit) => {
+                write!(formatter, "Letters must not have more than {} remote attachments", limit)
+            },
             ReceiveMailError::NoSubject => {
                 write!(formatter, "A letter subject is required")
             },
             ReceiveMailError::NoBody => {
                 write!(formatter, "A letter body is required")
             },
+            ReceiveMailError::NoSentAt => {
+                write!(formatter, "A letter sent timestamp is required")
+            },
+            ReceiveMailError::SentInFuture => {
+                write!(formatter, "The letter sent timestamp is too far in the future")
+            },
+            ReceiveMailError::Expired => {
+                write!(formatter, "The letter is too old")
+            },
             ReceiveMailError::MissingLabels(value) => {
                 write!(formatter, "The following labels are required: {:#?}", value)
             },
+            ReceiveMailError::RateLimit(error) => {
+                write!(formatter, "{}", error)
+            },
+            ReceiveMailError::Stamp(error) => {
+                write!(formatter, "{}", error)
+            },
+            ReceiveMailError::Replay(error) => {
+                write!(formatter, "{}", error)
+            },
+            ReceiveMailError::QuotaExceeded(address) => {
+                write!(formatter, "The mailbox of {} is full", address)
+            },
+            ReceiveMailError::Store(error) => {
+                write!(formatter, "{}", error)
+            },
             ReceiveMailError::CreateRedisConnection(error) => {
                 write!(formatter, "{}", error)
             },
@@ -61,6 +127,36 @@ impl fmt::Display for ReceiveMailError {
 impl ResponseError for ReceiveMailError {
     fn error_response(&self) -> HttpResponse<BoxBody> {
         match &self {
+            ReceiveMailError::Unavailable(error) => {
+                error.error_response()
+            },
+            ReceiveMailError::TooLarge(_) => {
+                HttpResponse::PayloadTooLarge().body(self.to_string())
+            },
+            ReceiveMailError::Body(error) => {
+                error.error_response()
+            },
+            ReceiveMailError::RateLimit(error) => {
+                error.error_response()
+            },
+            ReceiveMailError::Stamp(error) => {
+                error.error_response()
+            },
+            ReceiveMailError::Replay(error) => {
+                error.error_response()
+            },
+            ReceiveMailError::AttachmentSizeMismatch(_, _, _) => {
+                HttpResponse::BadRequest().body(self.to_string())
+            },
+            ReceiveMailError::RemoteAttachmentTooLarge(_, _) | ReceiveMailError::TooManyRemoteAttachments(_) => {
+                HttpResponse::PayloadTooLarge().body(self.to_string())
+            },
+            ReceiveMailError::QuotaExceeded(_) => {
+                HttpResponse::InsufficientStorage().body(self.to_string())
+            },
+            ReceiveMailError::Store(error) => {
+                error.error_response()
+            },
             ReceiveMailError::CreateRedisConnection(_) | ReceiveMailError::Increment(_) => {
                 HttpResponse::InternalServerError().body(self.to_string())
             },
@@ -71,6 +167,27 @@ impl ResponseError for ReceiveMailError {
     }
 }
 
+/// Returns the body extractor configuration for received letters, capping bodies at the size
+/// of the largest letter the limits allow so that oversized letters are refused before parsing.
+/// The cap follows the limits currently in effect, so reloaded limits apply right away.
+///
+/// The cap is sized for JSON, which leaves binary formats without base64 overhead more room.
+pub fn letter_wire_config() -> WireConfig {
+    WireConfig::default()
+        .limit_with(|request| {
+            let size = match request.app_data::<Data<LiveMailConfiguration>>() {
+                Some(configuration) => configuration.current().limit.letter_size(),
+                None => MailLimit::default().letter_size()
+            };
+
+            size.try_into().unwrap_or(usize::MAX)
+        })
+        .error_handler(|error, _| match error {
+            WireError::Overflow(limit) => ReceiveMailError::TooLarge(limit as u64).into(),
+            _ => ReceiveMailError::Body(error).into()
+        })
+}
+
 /// The result of label validation.
 #[derive(PartialEq)]
 enum LabelsValidationResult {
@@ -80,7 +197,7 @@ enum LabelsValidationResult {
 }
 
 /// Returns true if all required labels are present, otherwise returns false.
-fn validate_labels(required_labels: &HashSet<String>, labels: Labels) -> LabelsValidationResult {
+fn validate_labels(required_labels: &HashSet<String>, labels: &Labels) -> LabelsValidationResult {
     if required_labels.is_empty() && labels.is_empty() {
         return LabelsValidationResult::None;
     }
@@ -103,12 +220,11 @@ enum AttachmentValidationResult {
     Valid
 }
 
-fn validate_attachments(attachments: Option<LetterAttachments>) -> AttachmentValidationResult {
-    if attachments.is_none() {
-        return AttachmentValidationResult::None;
-    }
-
-    let unwrapped = attachments.unwrap();
+fn validate_attachments(attachments: &Option<LetterAttachments>) -> AttachmentValidationResult {
+    let unwrapped = match attachments {
+        Some(value) => value,
+        None => return AttachmentValidationResult::None
+    };
 
     if unwrapped.embedded.is_empty() && unwrapped.remote.is_empty() {
         return AttachmentValidationResult::None;
@@ -125,12 +241,62 @@ fn validate_attachments(attachments: Option<LetterAttachments>) -> AttachmentVal
     AttachmentValidationResult::Valid
 }
 
-fn validate_letter(letter: SealedLetter, configuration: Data<MailConfiguration>) -> Result<(), ReceiveMailError> {
+/// Checks that embedded attachments are as large as declared and that remote attachments are within the limits.
+fn validate_attachment_sizes(attachments: &Option<LetterAttachments>, limit: &MailLimit) -> Result<(), ReceiveMailError> {
+    let attachments = match attachments {
+        Some(value) => value,
+        None => return Ok(())
+    };
+
+    for attachment in &attachments.embedded {
+        if attachment.data.len() as u64 != attachment.size {
+            return Err(ReceiveMailError::AttachmentSizeMismatch(attachment.id, attachment.size, attachment.data.len()));
+        }
+    }
+
+    if attachments.remote.len() as u64 > limit.remote_attachments {
+        return Err(ReceiveMailError::TooManyRemoteAttachments(limit.remote_attachments));
+    }
+
+    for attachment in &attachments.remote {
+        if attachment.size > limit.remote_attachment_size {
+            return Err(ReceiveMailError::RemoteAttachmentTooLarge(attachment.id, limit.remote_attachment_size));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the sent timestamp of a letter against the time it was received.
+fn validate_sent_at(sent_at: Option<DateTime<Utc>>, received_at: DateTime<Utc>, configuration: &MailConfiguration) -> Result<(), ReceiveMailError> {
+    let sent_at = match sent_at {
+        Some(value) => value,
+        None if configuration.require.sent_at => return Err(ReceiveMailError::NoSentAt),
+        None => return Ok(())
+    };
+
+    let age = received_at.signed_duration_since(sent_at).num_seconds();
+
+    if age < 0 && age.unsigned_abs() > configuration.limit.clock_skew {
+        return Err(ReceiveMailError::SentInFuture);
+    }
+
+    if age > 0 && age.unsigned_abs() > configuration.limit.age {
+        return Err(ReceiveMailError::Expired);
+    }
+
+    Ok(())
+}
+
+/// Checks a letter against the acceptance rules and requirements of this instance.
+pub fn validate_letter(letter: &SealedLetter, received_at: DateTime<Utc>, configuration: &MailConfiguration) -> Result<(), ReceiveMailError> {
     if letter.recipients.is_empty() {
         return Err(ReceiveMailError::NoRecipients);
     }
 
-    if !configuration.accept.anomyous_sender && letter.sender.is_none() {
+    let stamped = configuration.accept.stamp_difficulty.is_some();
+
+    if !configuration.accept.anomyous_sender && !stamped && letter.sender.is_none() {
         return Err(ReceiveMailError::AnonymousSender);
     }
 
@@ -138,10 +304,10 @@ fn validate_letter(letter: SealedLetter, configuration: Data<MailConfiguration>)
         return Err(ReceiveMailError::Unsigned);
     }
 
-    let attachments = letter.attachments.clone();
+    validate_attachment_sizes(&letter.attachments, &configuration.limit)?;
 
     if !configuration.accept.unsigned_attachments {
-        let result = validate_attachments(attachments);
+        let result = validate_attachments(&letter.attachments);
 
         if result == AttachmentValidationResult::Invalid {
             return Err(ReceiveMailError::UnsignedAttachments);
@@ -156,14 +322,14 @@ fn validate_letter(letter: SealedLetter, configuration: Data<MailConfiguration>)
         return Err(ReceiveMailError::NoBody);
     }
 
-    let required_labels = configuration.require.labels.clone();
+    validate_sent_at(letter.sent_at, received_at, configuration)?;
 
-    if !required_labels.is_empty() {
-        let labels = letter.labels;
+    let required_labels = &configuration.require.labels;
 
-        return match validate_labels(&required_labels, labels) {
+    if !required_labels.is_empty() {
+        return match validate_labels(required_labels, &letter.labels) {
             LabelsValidationResult::None => {
-                Err(ReceiveMailError::MissingLabels(required_labels))
+                Err(ReceiveMailError::MissingLabels(required_labels.clone()))
             },
             LabelsValidationResult::Invalid(complement) => {
                 Err(ReceiveMailError::MissingLabels(complement))
@@ -192,22 +358,128 @@ async fn increment(pool: &MobcPool) -> Result<(), ReceiveMailError> {
     Ok(())
 }
 
+/// Applies the rate limits for the sending host and address of a letter.
+async fn throttle_sender(pool: &MobcPool, letter: &SealedLetter, configuration: &MailConfiguration) -> Result<(), RateLimitError> {
+    if let Some(sender) = &letter.sender {
+        let address = sender.to_string();
+
+        throttle(pool, RateLimitKey::Host(&sender.host), &configuration.rate.host).await?;
+        throttle(pool, RateLimitKey::Sender(&address), &configuration.rate.sender).await?;
+    }
+
+    Ok(())
+}
+
+/// Performs the checks and bookkeeping for a letter that has not been received before.
+///
+/// Only fails if the letter was not stored, in which case the claim on it can be released.
+async fn accept_letter(
+    pool: &MobcPool,
+    storage: &AttachmentStorage,
+    letter: &SealedLetter,
+    received_at: DateTime<Utc>,
+    request_id: Option<&RequestId>,
+    configuration: &MailConfiguration,
+    state: &CommonState
+) -> Result<(), ReceiveMailError> {
+    let stamp = check_stamp(pool, letter, &configuration.accept, &state.host)
+        .await
+        .map_err(ReceiveMailError::Stamp)?;
+
+    let stored = StoredLetter {
+        received_at,
+        request_id: request_id.map(RequestId::to_string),
+        letter: letter.clone(),
+        local_attachments: vec![],
+        blobs: BTreeMap::new(),
+        blob_size: 0
+    };
+
+    if let Err(error) = store_letter(pool, &storage.blobs, &state.host, stored, &configuration.quota).await {
+        // The letter was not stored, so a retry must be able to pay with the same stamp
+        if let Some(key) = stamp {
+            if let Err(release_error) = release_stamp(pool, &key).await {
+                warn!("Failed to release the stamp of letter {}: {}", letter.id, release_error);
+            }
+        }
+
+        return Err(match error {
+            MailboxError::QuotaExceeded(address) => ReceiveMailError::QuotaExceeded(address),
+            _ => ReceiveMailError::Store(error)
+        });
+    }
+
+    // Remote attachments are copied in the background, the letter is usable without them in the meantime
+    if configuration.fetch.enabled {
+        let recipients: Vec<Identifier> = local_recipients(letter, &state.host)
+            .into_iter()
+            .map(|recipient| recipient.id)
+            .collect();
+
+        if let Err(error) = queue_fetch(pool, letter, &recipients, request_id).await {
+            warn!("Unable to queue the remote attachments of letter {} for fetching: {}", letter.id, error);
+        }
+    }
+
+    // The letter is stored, so failing now would turn the retry of the sender into a duplicate
+    if let Err(error) = record_contact(pool, letter, &configuration.accept, &state.host).await {
+        warn!("Unable to record the sender of letter {} as a contact: {}", letter.id, error);
+    }
+
+    if let Err(error) = increment(pool).await {
+        warn!("Unable to count letter {} as received: {}", letter.id, error);
+    }
+
+    Ok(())
+}
+
 #[post("")]
 pub async fn receive_mail(
-    json: Json<SealedLetter>,
-    configuration: Data<MailConfiguration>,
+    request: HttpRequest,
+    body: Wire<SealedLetter>,
+    configuration: Data<LiveMailConfiguration>,
+    state: Data<CommonState>,
+    storage: Data<AttachmentStorage>,
     pool: Data<MobcPool>
 ) -> Result<impl Responder> {
-    let letter = json.into_inner();
+    state.available().map_err(ReceiveMailError::Unavailable)?;
 
-    validate_letter(letter.clone(), configuration)?;
+    let letter = body.into_inner();
+    let configuration = configuration.current();
+
+    throttle_request(&pool, &request, &configuration)
+        .await
+        .map_err(ReceiveMailError::RateLimit)?;
+    throttle_sender(&pool, &letter, &configuration)
+        .await
+        .map_err(ReceiveMailError::RateLimit)?;
+
+    let received_at = Utc::now();
+    let request_id = request_id(&request);
+
+    validate_letter(&letter, received_at, &configuration)?;
+
+    let key = match claim_letter(&pool, &letter, &configuration.replay).await.map_err(ReceiveMailError::Replay)? {
+        Claim::New(value) => value,
+        Claim::Retry => {
+            debug!("Received a retry of letter {}", letter.id);
+
+            return Ok(HttpResponse::Ok());
+        }
+    };
+
+    if let Err(error) = accept_letter(&pool, &storage, &letter, received_at, request_id.as_ref(), &configuration, &state).await {
+        if let Err(release_error) = release_letter(&pool, &key).await {
+            warn!("Failed to release letter {}: {}", letter.id, release_error);
+        }
+
+        return Err(error.into());
+    }
 
     match letter.sender {
         Some(value) => debug!("Received a letter from {}", value),
         None => debug!("Received an anonymous letter")
     }
 
-    increment(&pool).await?;
-
     Ok(HttpResponse::Ok())
 }
@@ -1,17 +1,24 @@
 use std::fmt;
+use chrono::Utc;
 use actix_web::body::BoxBody;
-use actix_web::web::{Data, Json};
-use actix_web::{get, Responder, Result, ResponseError, HttpResponse};
+use actix_web::web::Data;
+use actix_web::{get, HttpRequest, Responder, Result, ResponseError, HttpResponse};
 use common::model::{Identifier, Address};
+use common::state::{CommonState, Unavailable};
+use common::wire::Wire;
 use common::database::redis::{get_connection, MobcPool, RedisDatabaseError};
 use mobc_redis::redis::{AsyncCommands, RedisError};
 
 use crate::model::{SealedLetter, LetterAttachments, EmbeddedAttachment, RemoteAttachment};
+use crate::configuration::LiveMailConfiguration;
+use crate::rate::{throttle_request, RateLimitError};
 
 const TOTAL_SENT_LETTERS: &str = "TOTAL_SENT_LETTERS";
 
 #[derive(Debug)]
 pub enum SendMailError {
+    Unavailable(Unavailable),
+    RateLimit(RateLimitError),
     CreateRedisConnection(RedisDatabaseError),
     Increment(RedisError)
 }
@@ -19,6 +26,12 @@ pub enum SendMailError {
 impl fmt::Display for SendMailError {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            SendMailError::Unavailable(error) => {
+                write!(formatter, "{}", error)
+            },
+            SendMailError::RateLimit(error) => {
+                write!(formatter, "{}", error)
+            },
             SendMailError::CreateRedisConnection(error) => {
                 write!(formatter, "{}", error)
             },
@@ -31,7 +44,17 @@ impl fmt::Display for SendMailError {
 
 impl ResponseError for SendMailError {
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        HttpResponse::InternalServerError().body(self.to_string())
+        match &self {
+            SendMailError::Unavailable(error) => {
+                error.error_response()
+            },
+            SendMailError::RateLimit(error) => {
+                error.error_response()
+            },
+            _ => {
+                HttpResponse::InternalServerError().body(self.to_string())
+            }
+        }
     }
 }
 
@@ -48,9 +71,21 @@ async fn increment(pool: &MobcPool) -> Result<(), SendMailError> {
     Ok(())
 }
 
-
 #[get("")]
-pub async fn send_mail(pool: Data<MobcPool>) -> Result<impl Responder> {
+pub async fn send_mail(
+    request: HttpRequest,
+    configuration: Data<LiveMailConfiguration>,
+    state: Data<CommonState>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    state.available().map_err(SendMailError::Unavailable)?;
+
+    let configuration = configuration.current();
+
+    throttle_request(&pool, &request, &configuration)
+        .await
+        .map_err(SendMailError::RateLimit)?;
+
     increment(&pool).await?;
 
     let sender = Address {
@@ -104,12 +139,14 @@ pub async fn send_mail(pool: Data<MobcPool>) -> Result<impl Responder> {
         body: Some(
             vec![].into()
         ),
+        sent_at: Some(Utc::now()),
         signature: Some(
             vec![].into()
-        )
+        ),
+        stamp: None
     };
 
-    let response = Json(letter);
+    let response = Wire(letter);
 
     Ok(response)
 }
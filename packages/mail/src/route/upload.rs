@@ -0,0 +1,311 @@
+use std::fmt;
+use actix_web::body::BoxBody;
+use actix_web::http::header::{ContentRange, ContentRangeSpec, Header};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Path, Payload};
+use actix_web::{delete, get, post, put, HttpRequest, HttpResponse, Responder, Result, ResponseError};
+use chrono::Utc;
+use common::model::{Address, Identifier, TypeConversionError};
+use common::request::{request_id, RequestId};
+use common::state::{CommonState, Unavailable};
+use common::database::redis::MobcPool;
+use common::wire::Wire;
+
+use crate::attachment::{store_attachment, AttachmentError};
+use crate::configuration::LiveMailConfiguration;
+use crate::model::{StoredAttachment, UploadRequest, UploadSession};
+use crate::rate::{throttle_request, RateLimitError};
+use crate::storage::AttachmentStorage;
+use crate::upload::{create_upload, load_upload, upload_progress, write_range, upload_offset, upload_digest, discard_upload, UploadError};
+use crate::user::authenticated_user;
+
+#[derive(Debug)]
+pub enum AttachmentUploadError {
+    Unavailable(Unavailable),
+    Unauthorized,
+    RateLimit(RateLimitError),
+    InvalidIdentifier(TypeConversionError),
+    TooLarge(u64),
+    InvalidDigest,
+    Unsigned,
+    InvalidRange,
+    Incomplete(u64, u64),
+    DigestMismatch,
+    Upload(UploadError),
+    Attachment(AttachmentError)
+}
+
+impl fmt::Display for AttachmentUploadError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttachmentUploadError::Unavailable(error) => {
+                write!(formatter, "{}", error)
+            },
+            AttachmentUploadError::Unauthorized => {
+                write!(formatter, "Authentication is required")
+            },
+            AttachmentUploadError::RateLimit(error) => {
+                write!(formatter, "{}", error)
+            },
+            AttachmentUploadError::InvalidIdentifier(error) => {
+                write!(formatter, "{}", error)
+            },
+            AttachmentUploadError::TooLarge(limit) => {
+                write!(formatter, "Attachments must not exceed {} bytes", limit)
+            },
+            AttachmentUploadError::InvalidDigest => {
+                write!(formatter, "The SHA-256 digest must be 64 hexadecimal characters")
+            },
+            AttachmentUploadError::Unsigned => {
+                write!(formatter, "Unsigned attachments are forbidden")
+            },
+            AttachmentUploadError::InvalidRange => {
+                write!(formatter, "A Content-Range header such as bytes 0-1023/4096 that fits the declared size is required")
+            },
+            AttachmentUploadError::Incomplete(received, size) => {
+                write!(formatter, "Only {} of {} bytes have been received", received, size)
+            },
+            AttachmentUploadError::DigestMismatch => {
+                write!(formatter, "The received data does not match the declared SHA-256 digest and has been discarded")
+            },
+            AttachmentUploadError::Upload(error) => {
+                write!(formatter, "{}", error)
+            },
+            AttachmentUploadError::Attachment(error) => {
+                write!(formatter, "{}", error)
+            }
+        }
+    }
+}
+
+impl ResponseError for AttachmentUploadError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            AttachmentUploadError::Unavailable(error) => {
+                error.error_response()
+            },
+            AttachmentUploadError::Unauthorized => {
+                HttpResponse::Unauthorized().body(self.to_string())
+            },
+            AttachmentUploadError::RateLimit(error) => {
+                error.error_response()
+            },
+            AttachmentUploadError::TooLarge(_) => {
+                HttpResponse::PayloadTooLarge().body(self.to_string())
+            },
+            AttachmentUploadError::InvalidRange => {
+                HttpResponse::RangeNotSatisfiable().body(self.to_string())
+            },
+            AttachmentUploadError::Incomplete(_, _) => {
+                HttpResponse::Conflict().body(self.to_string())
+            },
+            AttachmentUploadError::Upload(error) => {
+                error.error_response()
+            },
+            AttachmentUploadError::Attachment(error) => {
+                error.error_response()
+            },
+            _ => {
+                HttpResponse::BadRequest().body(self.to_string())
+            }
+        }
+    }
+}
+
+/// Returns the first and last byte of the range sent with a request, if it fits within the size.
+fn requested_range(request: &HttpRequest, size: u64) -> Option<(u64, u64)> {
+    match ContentRange::parse(request).ok()?.0 {
+        ContentRangeSpec::Bytes { range: Some((first, last)), instance_length } => {
+            let valid = first <= last && last < size && instance_length.is_none_or(|length| length == size);
+
+            valid.then_some((first, last))
+        },
+        _ => None
+    }
+}
+
+#[post("/upload")]
+pub async fn start_upload(
+    request: HttpRequest,
+    upload: Wire<UploadRequest>,
+    configuration: Data<LiveMailConfiguration>,
+    state: Data<CommonState>,
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    state.available().map_err(AttachmentUploadError::Unavailable)?;
+
+    let user = authenticated_user(&request).ok_or(AttachmentUploadError::Unauthorized)?;
+    let configuration = configuration.current();
+
+    throttle_request(&pool, &request, &configuration)
+        .await
+        .map_err(AttachmentUploadError::RateLimit)?;
+
+    let mut upload = upload.into_inner();
+    let limit = configuration.limit.remote_attachment_size;
+
+    if upload.size > limit {
+        return Err(AttachmentUploadError::TooLarge(limit).into());
+    }
+
+    if upload.sha256.len() != 64 || !upload.sha256.chars().all(|character| character.is_ascii_hexdigit()) {
+        return Err(AttachmentUploadError::InvalidDigest.into());
+    }
+
+    if upload.signature.is_none() && !configuration.accept.unsigned_attachments {
+        return Err(AttachmentUploadError::Unsigned.into());
+    }
+
+    upload.sha256 = upload.sha256.to_ascii_lowercase();
+
+    let session = UploadSession {
+        id: Identifier::new(),
+        owner: user.id,
+        created_at: Utc::now(),
+        request_id: request_id(&request).as_ref().map(RequestId::to_string),
+        request: upload
+    };
+
+    let progress = create_upload(&pool, &storage, &session, configuration.quota.quota(&user.id))
+        .await
+        .map_err(AttachmentUploadError::Upload)?;
+
+    Ok(Wire(progress).customize().with_status(StatusCode::CREATED))
+}
+
+#[get("/upload/{id}")]
+pub async fn get_upload(
+    request: HttpRequest,
+    path: Path<String>,
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    let user = authenticated_user(&request).ok_or(AttachmentUploadError::Unauthorized)?;
+    let id = Identifier::try_from(path.into_inner()).map_err(AttachmentUploadError::InvalidIdentifier)?;
+
+    let progress = upload_progress(&pool, &storage, &user.id, &id)
+        .await
+        .map_err(AttachmentUploadError::Upload)?;
+
+    Ok(Wire(progress))
+}
+
+#[put("/upload/{id}")]
+pub async fn upload_range(
+    request: HttpRequest,
+    path: Path<String>,
+    payload: Payload,
+    state: Data<CommonState>,
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    state.available().map_err(AttachmentUploadError::Unavailable)?;
+
+    let user = authenticated_user(&request).ok_or(AttachmentUploadError::Unauthorized)?;
+    let id = Identifier::try_from(path.into_inner()).map_err(AttachmentUploadError::InvalidIdentifier)?;
+
+    let session = load_upload(&pool, &user.id, &id)
+        .await
+        .map_err(AttachmentUploadError::Upload)?;
+
+    let (first, last) = requested_range(&request, session.request.size).ok_or(AttachmentUploadError::InvalidRange)?;
+
+    let progress = write_range(&pool, &storage, &session, first, last - first + 1, payload)
+        .await
+        .map_err(AttachmentUploadError::Upload)?;
+
+    Ok(Wire(progress))
+}
+
+#[post("/upload/{id}/finalize")]
+pub async fn finalize_upload(
+    request: HttpRequest,
+    path: Path<String>,
+    configuration: Data<LiveMailConfiguration>,
+    state: Data<CommonState>,
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    state.available().map_err(AttachmentUploadError::Unavailable)?;
+
+    let user = authenticated_user(&request).ok_or(AttachmentUploadError::Unauthorized)?;
+    let id = Identifier::try_from(path.into_inner()).map_err(AttachmentUploadError::InvalidIdentifier)?;
+    let configuration = configuration.current();
+
+    let session = load_upload(&pool, &user.id, &id)
+        .await
+        .map_err(AttachmentUploadError::Upload)?;
+
+    let _guard = storage.claim(&id).ok_or(AttachmentUploadError::Upload(UploadError::Busy(id)))?;
+
+    let received = upload_offset(&storage, &id)
+        .await
+        .map_err(AttachmentUploadError::Upload)?;
+
+    if received != session.request.size {
+        return Err(AttachmentUploadError::Incomplete(received, session.request.size).into());
+    }
+
+    let digest = upload_digest(&storage, &id)
+        .await
+        .map_err(AttachmentUploadError::Upload)?;
+
+    if digest != session.request.sha256 {
+        discard_upload(&pool, &storage, &session)
+            .await
+            .map_err(AttachmentUploadError::Upload)?;
+
+        return Err(AttachmentUploadError::DigestMismatch.into());
+    }
+
+    let stored = StoredAttachment {
+        id,
+        owner: user.id,
+        uploaded_at: Utc::now(),
+        request_id: session.request_id.clone(),
+        size: session.request.size,
+        digest,
+        labels: session.request.labels.clone(),
+        signature: session.request.signature.clone()
+    };
+
+    // The upload already reserved its size, which is given back once the attachment holds it instead
+    store_attachment(&pool, &storage, &stored, configuration.quota.quota(&user.id), session.request.size)
+        .await
+        .map_err(AttachmentUploadError::Attachment)?;
+
+    discard_upload(&pool, &storage, &session)
+        .await
+        .map_err(AttachmentUploadError::Upload)?;
+
+    let address = Address {
+        id,
+        host: state.host.clone()
+    };
+
+    Ok(Wire(address).customize().with_status(StatusCode::CREATED))
+}
+
+#[delete("/upload/{id}")]
+pub async fn cancel_upload(
+    request: HttpRequest,
+    path: Path<String>,
+    storage: Data<AttachmentStorage>,
+    pool: Data<MobcPool>
+) -> Result<impl Responder> {
+    let user = authenticated_user(&request).ok_or(AttachmentUploadError::Unauthorized)?;
+    let id = Identifier::try_from(path.into_inner()).map_err(AttachmentUploadError::InvalidIdentifier)?;
+
+    let session = load_upload(&pool, &user.id, &id)
+        .await
+        .map_err(AttachmentUploadError::Upload)?;
+
+    let _guard = storage.claim(&id).ok_or(AttachmentUploadError::Upload(UploadError::Busy(id)))?;
+
+    discard_upload(&pool, &storage, &session)
+        .await
+        .map_err(AttachmentUploadError::Upload)?;
+
+    Ok(HttpResponse::NoContent())
+}
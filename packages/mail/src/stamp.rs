@@ -0,0 +1,239 @@
+use std::fmt;
+use actix_web::body::BoxBody;
+use actix_web::{HttpResponse, ResponseError};
+use lazy_static::lazy_static;
+use common::database::redis::{get_connection, MobcPool, RedisDatabaseError};
+use common::model::Address;
+use common::serialization::encode_hex;
+use mobc_redis::redis::{pipe, AsyncCommands, RedisError, Script};
+use sha2::{Digest, Sha256};
+
+use crate::configuration::{MailAccept, DEFAULT_STAMP_DIFFICULTY};
+use crate::mailbox::local_recipients;
+use crate::model::SealedLetter;
+
+/// Adds a sender to the contacts of a recipient, forgetting random contacts beyond the cap and
+/// every contact once the recipient has not been written to for the expiry.
+const CONTACT_SCRIPT: &str = r"
+redis.call('SADD', KEYS[1], ARGV[1])
+local excess = redis.call('SCARD', KEYS[1]) - tonumber(ARGV[2])
+if excess > 0 then
+    redis.call('SPOP', KEYS[1], excess)
+end
+redis.call('EXPIRE', KEYS[1], ARGV[3])
+return 0
+";
+
+/// How long a redeemed stamp is remembered for in seconds.
+const STAMP_EXPIRY: u64 = 2592000;
+
+/// How long the contacts of a recipient are remembered after their last letter in seconds.
+const CONTACT_EXPIRY: u64 = 7776000;
+
+/// The largest number of contacts remembered for each recipient.
+const MAX_CONTACTS: u64 = 10000;
+
+const STAMP: &str = "STAMP";
+const CONTACTS: &str = "CONTACTS";
+
+lazy_static! {
+    static ref CONTACT: Script = Script::new(CONTACT_SCRIPT);
+}
+
+#[derive(Debug)]
+pub enum StampError {
+    Required(u32),
+    Insufficient(u32),
+    Reused,
+    CreateRedisConnection(RedisDatabaseError),
+    Query(RedisError)
+}
+
+impl fmt::Display for StampError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StampError::Required(difficulty) => {
+                write!(formatter, "A proof-of-work stamp with {} leading zero bits is required", difficulty)
+            },
+            StampError::Insufficient(difficulty) => {
+                write!(formatter, "The proof-of-work stamp does not have {} leading zero bits", difficulty)
+            },
+            StampError::Reused => {
+                write!(formatter, "The proof-of-work stamp has already been used")
+            },
+            StampError::CreateRedisConnection(error) => {
+                write!(formatter, "{}", error)
+            },
+            StampError::Query(error) => {
+                write!(formatter, "{}", error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StampError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            StampError::CreateRedisConnection(ref error) => Some(error),
+            StampError::Query(ref error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+impl ResponseError for StampError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            StampError::CreateRedisConnection(_) | StampError::Query(_) => {
+                HttpResponse::InternalServerError().body(self.to_string())
+            },
+            _ => {
+                HttpResponse::Forbidden().body(self.to_string())
+            }
+        }
+    }
+}
+
+/// Hashes a stamp together with the identifier and recipients of a letter.
+///
+/// Recipients are sorted so that the digest does not depend on their order.
+pub fn stamp_digest(letter: &SealedLetter, stamp: &str) -> [u8; 32] {
+    let mut recipients: Vec<String> = letter.recipients
+        .iter()
+        .map(Address::to_string)
+        .collect();
+
+    recipients.sort();
+
+    let resource = format!("{}:{}:{}", letter.id, recipients.join(","), stamp);
+
+    Sha256::digest(resource.as_bytes()).into()
+}
+
+fn leading_zero_bits(digest: &[u8]) -> u32 {
+    let mut bits = 0;
+
+    for byte in digest {
+        bits += byte.leading_zeros();
+
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    bits
+}
+
+/// Returns true if the sender has not yet sent a letter to every recipient.
+async fn is_first_contact(pool: &MobcPool, sender: &Address, recipients: &[&Address]) -> Result<bool, StampError> {
+    if recipients.is_empty() {
+        return Ok(false);
+    }
+
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(StampError::CreateRedisConnection)?;
+
+    let mut pipeline = pipe();
+
+    for recipient in recipients {
+        pipeline.sismember(format!("{}:{}", CONTACTS, recipient), sender.to_string());
+    }
+
+    let known: Vec<bool> = pipeline
+        .query_async(&mut *connection)
+        .await
+        .map_err(StampError::Query)?;
+
+    Ok(known.iter().any(|value| !value))
+}
+
+/// Remembers that the sender of a letter has contacted its local recipients, if first contacts need a stamp.
+pub async fn record_contact(pool: &MobcPool, letter: &SealedLetter, accept: &MailAccept, host: &str) -> Result<(), StampError> {
+    let sender = match &letter.sender {
+        Some(value) if accept.first_contact_stamp => value.to_string(),
+        _ => return Ok(())
+    };
+
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(StampError::CreateRedisConnection)?;
+
+    for recipient in local_recipients(letter, host) {
+        CONTACT
+            .key(format!("{}:{}", CONTACTS, recipient))
+            .arg(&sender)
+            .arg(MAX_CONTACTS)
+            .arg(CONTACT_EXPIRY)
+            .invoke_async::<_, ()>(&mut *connection)
+            .await
+            .map_err(StampError::Query)?;
+    }
+
+    Ok(())
+}
+
+/// Marks a stamp as used, failing if it has been used before, and returns the key that records it.
+async fn redeem(pool: &MobcPool, digest: &[u8]) -> Result<String, StampError> {
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(StampError::CreateRedisConnection)?;
+
+    let key = format!("{}:{}", STAMP, encode_hex(digest));
+    let redeemed: bool = connection
+        .set_nx(&key, 1)
+        .await
+        .map_err(StampError::Query)?;
+
+    if !redeemed {
+        return Err(StampError::Reused);
+    }
+
+    connection
+        .expire::<&str, ()>(&key, STAMP_EXPIRY as usize)
+        .await
+        .map_err(StampError::Query)?;
+
+    Ok(key)
+}
+
+/// Makes a redeemed stamp usable again, for when the letter it paid for could not be stored.
+pub async fn release_stamp(pool: &MobcPool, key: &str) -> Result<(), StampError> {
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(StampError::CreateRedisConnection)?;
+
+    connection
+        .del::<&str, ()>(key)
+        .await
+        .map_err(StampError::Query)?;
+
+    Ok(())
+}
+
+/// Verifies and redeems the stamp of a letter if the sender is required to provide one.
+///
+/// Only the local recipients of a letter have contacts, so only they are checked for first contact.
+/// Returns the key recording the redeemed stamp, if any, so that it can be released when the letter is not stored.
+pub async fn check_stamp(pool: &MobcPool, letter: &SealedLetter, accept: &MailAccept, host: &str) -> Result<Option<String>, StampError> {
+    let required = match &letter.sender {
+        Some(sender) => accept.first_contact_stamp && is_first_contact(pool, sender, &local_recipients(letter, host)).await?,
+        None => !accept.anomyous_sender
+    };
+
+    if !required {
+        return Ok(None);
+    }
+
+    let difficulty = accept.effective_stamp_difficulty().unwrap_or(DEFAULT_STAMP_DIFFICULTY);
+    let stamp = letter.stamp
+        .as_ref()
+        .ok_or(StampError::Required(difficulty))?;
+    let digest = stamp_digest(letter, stamp);
+
+    if leading_zero_bits(&digest) < difficulty {
+        return Err(StampError::Insufficient(difficulty));
+    }
+
+    redeem(pool, &digest).await.map(Some)
+}
@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+use std::fs::{create_dir_all, read_dir, remove_file};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use common::model::Identifier;
+use common::store::BlobStore;
+
+const UPLOADS: &str = "uploads";
+
+/// Where unfinished uploads are kept on disk, along with the blob store holding attachment data.
+#[derive(Debug)]
+pub struct AttachmentStorage {
+    directory: PathBuf,
+
+    /// The store holding the data of attachments and letters.
+    pub blobs: BlobStore,
+
+    /// How many seconds an unfinished upload is kept after it last received data.
+    pub upload_expiry: u64,
+
+    /// Uploads that are currently being written to or finalized.
+    busy: Mutex<HashSet<String>>
+}
+
+/// Releases an upload when the request working on it ends.
+pub struct UploadGuard<'a> {
+    storage: &'a AttachmentStorage,
+    id: String
+}
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        match self.storage.busy.lock() {
+            Ok(mut guard) => guard.remove(&self.id),
+            Err(poisoned) => poisoned.into_inner().remove(&self.id)
+        };
+    }
+}
+
+impl AttachmentStorage {
+    /// Opens the storage directory, creating it if necessary.
+    pub fn new(directory: &str, upload_expiry: u64, blobs: BlobStore) -> io::Result<Self> {
+        let directory = PathBuf::from(directory);
+
+        create_dir_all(directory.join(UPLOADS))?;
+
+        let storage = AttachmentStorage {
+            directory,
+            blobs,
+            upload_expiry,
+            busy: Mutex::new(HashSet::new())
+        };
+
+        Ok(storage)
+    }
+
+    /// The path of the data received so far for an upload.
+    pub fn upload_path(&self, id: &Identifier) -> PathBuf {
+        self.directory.join(UPLOADS).join(id.to_string())
+    }
+
+    /// Claims an upload for the current request, returning None if another request is working on it.
+    pub fn claim(&self, id: &Identifier) -> Option<UploadGuard<'_>> {
+        let id = id.to_string();
+        let claimed = match self.busy.lock() {
+            Ok(mut guard) => guard.insert(id.clone()),
+            Err(poisoned) => poisoned.into_inner().insert(id.clone())
+        };
+
+        match claimed {
+            true => Some(UploadGuard { storage: self, id }),
+            false => None
+        }
+    }
+
+    /// Removes unfinished uploads that have not received any data within the expiry, returning how many were removed.
+    pub fn sweep_uploads(&self) -> io::Result<usize> {
+        let expiry = Duration::from_secs(self.upload_expiry);
+        let now = SystemTime::now();
+        let mut removed = 0;
+
+        for entry in read_dir(self.directory.join(UPLOADS))? {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            let stale = now
+                .duration_since(modified)
+                .map(|age| age > expiry)
+                .unwrap_or(false);
+
+            if stale && !self.is_busy(&entry.file_name().to_string_lossy()) {
+                remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn is_busy(&self, id: &str) -> bool {
+        match self.busy.lock() {
+            Ok(guard) => guard.contains(id),
+            Err(poisoned) => poisoned.into_inner().contains(id)
+        }
+    }
+}
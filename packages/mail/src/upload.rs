@@ -0,0 +1,492 @@
+use std::fmt;
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{self, Write};
+use actix_web::body::BoxBody;
+use actix_web::error::PayloadError;
+use actix_web::rt::time::interval;
+use actix_web::web::{Bytes, Data, Payload};
+use actix_web::{HttpResponse, ResponseError};
+use chrono::{Duration, Utc};
+use lazy_static::lazy_static;
+use log::{debug, error};
+use futures_util::{Stream, StreamExt};
+use common::store::{blocking, digest_file};
+use common::database::redis::{get_connection, MobcPool, RedisDatabaseError};
+use common::model::Identifier;
+use common::state::CommonState;
+use mobc_redis::redis::{pipe, RedisError, Script, AsyncCommands};
+
+use crate::configuration::Quota;
+use crate::mailbox::usage_key;
+use crate::model::{UploadProgress, UploadSession};
+use crate::storage::AttachmentStorage;
+
+/// Opens an upload and reserves its declared size against the storage quota of its owner,
+/// provided that the owner has room and fewer unfinished uploads than allowed.
+///
+/// Keys are the upload, the usage of its owner, the unfinished uploads of its owner and the reservations.
+/// Returns zero on success, one if the storage quota would be exceeded or two if too many uploads are open.
+const CREATE_SCRIPT: &str = r"
+local size = tonumber(ARGV[3])
+if redis.call('SCARD', KEYS[3]) >= tonumber(ARGV[5]) then
+    return 2
+end
+local bytes = tonumber(redis.call('HGET', KEYS[2], 'bytes')) or 0
+if bytes + size > tonumber(ARGV[4]) then
+    return 1
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+redis.call('HINCRBY', KEYS[2], 'bytes', size)
+redis.call('SADD', KEYS[3], ARGV[6])
+redis.call('ZADD', KEYS[4], ARGV[7], ARGV[8])
+return 0
+";
+
+/// Forgets an upload and gives back the storage reserved for it, only ever once.
+///
+/// Keys are the same as for creation. If the last argument is set, an upload that has not
+/// expired yet is left alone. Returns one if the reservation was released.
+const RELEASE_SCRIPT: &str = r"
+if ARGV[4] == '1' and redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+if redis.call('ZREM', KEYS[4], ARGV[3]) == 0 then
+    return 0
+end
+redis.call('HINCRBY', KEYS[2], 'bytes', -tonumber(ARGV[1]))
+redis.call('SREM', KEYS[3], ARGV[2])
+return 1
+";
+
+const UPLOAD: &str = "UPLOAD";
+const UPLOADS: &str = "UPLOADS";
+
+/// The sorted set of upload reservations, scored by when they expire unless more data is received.
+const RESERVATIONS: &str = "UPLOAD_RESERVATIONS";
+
+lazy_static! {
+    static ref CREATE: Script = Script::new(CREATE_SCRIPT);
+    static ref RELEASE: Script = Script::new(RELEASE_SCRIPT);
+}
+
+/// How often unfinished uploads are checked for expiry, in seconds.
+const SWEEP_INTERVAL: u64 = 600;
+
+/// The name the upload sweeper reports its heartbeat under.
+const SWEEPER: &str = "upload-sweeper";
+
+/// How much data is gathered before it is written to disk.
+const WRITE_BUFFER_SIZE: usize = 1048576;
+
+#[derive(Debug)]
+pub enum UploadError {
+    QuotaExceeded(Identifier),
+    TooManyUploads(u64),
+    NotFound(Identifier),
+    NotOwner(Identifier),
+    Busy(Identifier),
+    WrongOffset(u64),
+    Overflow(u64),
+    Truncated(u64, u64),
+    Payload(PayloadError),
+    Serialize(serde_json::Error),
+    Io(io::Error),
+    CreateRedisConnection(RedisDatabaseError),
+    Query(RedisError)
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadError::QuotaExceeded(owner) => write!(formatter, "The storage of {} is full", owner),
+            UploadError::TooManyUploads(limit) => write!(formatter, "No more than {} unfinished uploads can be open at once", limit),
+            UploadError::NotFound(id) => write!(formatter, "No upload with the identifier {} exists", id),
+            UploadError::NotOwner(id) => write!(formatter, "The upload {} belongs to another user", id),
+            UploadError::Busy(id) => write!(formatter, "The upload {} is already being written to", id),
+            UploadError::WrongOffset(offset) => write!(formatter, "The next range must start at byte {}", offset),
+            UploadError::Overflow(length) => write!(formatter, "The body is longer than the {} bytes of its range", length),
+            UploadError::Truncated(length, received) => write!(formatter, "The range is {} bytes but only {} were received", length, received),
+            UploadError::Payload(error) => write!(formatter, "{}", error),
+            UploadError::Serialize(error) => write!(formatter, "{}", error),
+            UploadError::Io(error) => write!(formatter, "{}", error),
+            UploadError::CreateRedisConnection(error) => write!(formatter, "{}", error),
+            UploadError::Query(error) => write!(formatter, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for UploadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            UploadError::Payload(ref error) => Some(error),
+            UploadError::Serialize(ref error) => Some(error),
+            UploadError::Io(ref error) => Some(error),
+            UploadError::CreateRedisConnection(ref error) => Some(error),
+            UploadError::Query(ref error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+impl ResponseError for UploadError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            UploadError::QuotaExceeded(_) => {
+                HttpResponse::InsufficientStorage().body(self.to_string())
+            },
+            UploadError::TooManyUploads(_) => {
+                HttpResponse::TooManyRequests().body(self.to_string())
+            },
+            UploadError::NotFound(_) => {
+                HttpResponse::NotFound().body(self.to_string())
+            },
+            UploadError::NotOwner(_) => {
+                HttpResponse::Forbidden().body(self.to_string())
+            },
+            UploadError::Busy(_) | UploadError::WrongOffset(_) => {
+                HttpResponse::Conflict().body(self.to_string())
+            },
+            UploadError::Overflow(_) | UploadError::Truncated(_, _) => {
+                HttpResponse::BadRequest().body(self.to_string())
+            },
+            UploadError::Payload(error) => {
+                error.error_response()
+            },
+            _ => {
+                HttpResponse::InternalServerError().body(self.to_string())
+            }
+        }
+    }
+}
+
+/// The key of an unfinished upload.
+pub fn upload_key(id: &Identifier) -> String {
+    format!("{}:{}", UPLOAD, id)
+}
+
+/// The key of the set of unfinished uploads of a user.
+fn uploads_key(owner: &Identifier) -> String {
+    format!("{}:{}", UPLOADS, owner)
+}
+
+/// Describes the reservation of an upload, so that it can be released after the upload itself has expired.
+fn reservation(id: &Identifier, owner: &Identifier, size: u64) -> String {
+    format!("{}:{}:{}", id, owner, size)
+}
+
+/// Reads the upload identifier, owner and size back from a reservation.
+fn parse_reservation(value: &str) -> Option<(Identifier, Identifier, u64)> {
+    let mut parts = value.splitn(3, ':');
+    let id = Identifier::try_from(parts.next()?).ok()?;
+    let owner = Identifier::try_from(parts.next()?).ok()?;
+    let size = parts.next()?.parse().ok()?;
+
+    Some((id, owner, size))
+}
+
+/// When a reservation expires unless more data is received.
+fn reservation_expiry(storage: &AttachmentStorage) -> i64 {
+    Utc::now().timestamp() + storage.upload_expiry as i64
+}
+
+/// Runs the release script for an upload, returning whether a reservation was released.
+async fn release(pool: &MobcPool, id: &Identifier, owner: &Identifier, size: u64, expired: bool) -> Result<bool, UploadError> {
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(UploadError::CreateRedisConnection)?;
+
+    RELEASE
+        .key(upload_key(id))
+        .key(usage_key(owner))
+        .key(uploads_key(owner))
+        .key(RESERVATIONS)
+        .arg(size)
+        .arg(id.to_string())
+        .arg(reservation(id, owner, size))
+        .arg(if expired { "1" } else { "0" })
+        .invoke_async(&mut *connection)
+        .await
+        .map_err(UploadError::Query)
+}
+
+/// Returns how much of an upload has been received.
+async fn progress(pool: &MobcPool, storage: &AttachmentStorage, session: &UploadSession) -> Result<UploadProgress, UploadError> {
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(UploadError::CreateRedisConnection)?;
+
+    let ttl: i64 = connection
+        .ttl(upload_key(&session.id))
+        .await
+        .map_err(UploadError::Query)?;
+
+    let progress = UploadProgress {
+        id: session.id,
+        offset: upload_offset(storage, &session.id).await?,
+        size: session.request.size,
+        expires_at: Utc::now() + Duration::seconds(ttl.max(0))
+    };
+
+    Ok(progress)
+}
+
+/// Returns the number of bytes received for an upload.
+pub async fn upload_offset(storage: &AttachmentStorage, id: &Identifier) -> Result<u64, UploadError> {
+    let path = storage.upload_path(id);
+
+    blocking(move || Ok(path.metadata()?.len()))
+        .await
+        .map_err(UploadError::Io)
+}
+
+/// Starts an upload with no data received yet, reserving its declared size against the quota of its owner.
+///
+/// The reservation is given back when the upload is discarded or expires.
+pub async fn create_upload(
+    pool: &MobcPool,
+    storage: &AttachmentStorage,
+    session: &UploadSession,
+    quota: &Quota
+) -> Result<UploadProgress, UploadError> {
+    let value = serde_json::to_string(session).map_err(UploadError::Serialize)?;
+
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(UploadError::CreateRedisConnection)?;
+
+    let result: i64 = CREATE
+        .key(upload_key(&session.id))
+        .key(usage_key(&session.owner))
+        .key(uploads_key(&session.owner))
+        .key(RESERVATIONS)
+        .arg(value)
+        .arg(storage.upload_expiry)
+        .arg(session.request.size)
+        .arg(quota.bytes)
+        .arg(quota.uploads)
+        .arg(session.id.to_string())
+        .arg(reservation_expiry(storage))
+        .arg(reservation(&session.id, &session.owner, session.request.size))
+        .invoke_async(&mut *connection)
+        .await
+        .map_err(UploadError::Query)?;
+
+    match result {
+        0 => (),
+        1 => return Err(UploadError::QuotaExceeded(session.owner)),
+        _ => return Err(UploadError::TooManyUploads(quota.uploads))
+    }
+
+    let path = storage.upload_path(&session.id);
+
+    if let Err(error) = blocking(move || File::create(path).map(|_| ())).await {
+        release(pool, &session.id, &session.owner, session.request.size, false).await?;
+
+        return Err(UploadError::Io(error));
+    }
+
+    progress(pool, storage, session).await
+}
+
+/// Returns an unfinished upload that belongs to the given user.
+pub async fn load_upload(pool: &MobcPool, owner: &Identifier, id: &Identifier) -> Result<UploadSession, UploadError> {
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(UploadError::CreateRedisConnection)?;
+
+    let value: Option<String> = connection
+        .get(upload_key(id))
+        .await
+        .map_err(UploadError::Query)?;
+
+    let value = value.ok_or(UploadError::NotFound(*id))?;
+    let session: UploadSession = serde_json::from_str(&value).map_err(UploadError::Serialize)?;
+
+    if session.owner.to_string() != owner.to_string() {
+        return Err(UploadError::NotOwner(*id));
+    }
+
+    Ok(session)
+}
+
+/// Returns how much of an upload that belongs to the given user has been received.
+pub async fn upload_progress(
+    pool: &MobcPool,
+    storage: &AttachmentStorage,
+    owner: &Identifier,
+    id: &Identifier
+) -> Result<UploadProgress, UploadError> {
+    let session = load_upload(pool, owner, id).await?;
+
+    progress(pool, storage, &session).await
+}
+
+/// Appends data to a file in large writes, returning the file once they are done.
+async fn append(mut file: File, data: Bytes) -> Result<File, UploadError> {
+    blocking(move || file.write_all(&data).map(|_| file))
+        .await
+        .map_err(UploadError::Io)
+}
+
+/// Appends exactly `length` bytes of a request or response body to a file.
+pub(crate) async fn receive<S>(mut file: File, mut payload: S, length: u64) -> Result<(), UploadError>
+where S: Stream<Item = Result<Bytes, PayloadError>> + Unpin {
+    let mut buffer = Vec::with_capacity(WRITE_BUFFER_SIZE);
+    let mut received: u64 = 0;
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(UploadError::Payload)?;
+
+        received += chunk.len() as u64;
+
+        if received > length {
+            return Err(UploadError::Overflow(length));
+        }
+
+        buffer.extend_from_slice(&chunk);
+
+        if buffer.len() >= WRITE_BUFFER_SIZE {
+            let data = std::mem::replace(&mut buffer, Vec::with_capacity(WRITE_BUFFER_SIZE));
+
+            file = append(file, Bytes::from(data)).await?;
+        }
+    }
+
+    if received < length {
+        return Err(UploadError::Truncated(length, received));
+    }
+
+    append(file, Bytes::from(buffer)).await?;
+
+    Ok(())
+}
+
+/// Writes the body of a request to the upload starting at the given offset.
+///
+/// The range is applied entirely or not at all, so a failed request can be retried from the same offset.
+pub async fn write_range(
+    pool: &MobcPool,
+    storage: &AttachmentStorage,
+    session: &UploadSession,
+    offset: u64,
+    length: u64,
+    payload: Payload
+) -> Result<UploadProgress, UploadError> {
+    let _guard = storage.claim(&session.id).ok_or(UploadError::Busy(session.id))?;
+
+    let current = upload_offset(storage, &session.id).await?;
+
+    if current != offset {
+        return Err(UploadError::WrongOffset(current));
+    }
+
+    let path = storage.upload_path(&session.id);
+    let opened = path.clone();
+    let file = blocking(move || OpenOptions::new().append(true).open(opened))
+        .await
+        .map_err(UploadError::Io)?;
+
+    if let Err(error) = receive(file, payload, length).await {
+        blocking(move || OpenOptions::new().write(true).open(path)?.set_len(offset))
+            .await
+            .map_err(UploadError::Io)?;
+
+        return Err(error);
+    }
+
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(UploadError::CreateRedisConnection)?;
+
+    pipe()
+        .expire(upload_key(&session.id), storage.upload_expiry as usize).ignore()
+        .cmd("ZADD").arg(RESERVATIONS).arg("XX").arg(reservation_expiry(storage)).arg(reservation(&session.id, &session.owner, session.request.size)).ignore()
+        .query_async::<_, ()>(&mut *connection)
+        .await
+        .map_err(UploadError::Query)?;
+
+    progress(pool, storage, session).await
+}
+
+/// Returns the hexadecimal SHA-256 digest of the data received for an upload.
+pub async fn upload_digest(storage: &AttachmentStorage, id: &Identifier) -> Result<String, UploadError> {
+    let path = storage.upload_path(id);
+
+    blocking(move || digest_file(&path))
+        .await
+        .map_err(UploadError::Io)
+}
+
+/// Forgets an upload and gives back the storage reserved for it, removing its data unless it has already been moved elsewhere.
+pub async fn discard_upload(pool: &MobcPool, storage: &AttachmentStorage, session: &UploadSession) -> Result<(), UploadError> {
+    release(pool, &session.id, &session.owner, session.request.size, false).await?;
+
+    let path = storage.upload_path(&session.id);
+
+    blocking(move || match remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(())
+    })
+    .await
+    .map_err(UploadError::Io)
+}
+
+/// Gives back the storage reserved for uploads that have expired, returning how many were released.
+pub async fn release_expired_uploads(pool: &MobcPool) -> Result<usize, UploadError> {
+    let mut connection = get_connection(pool)
+        .await
+        .map_err(UploadError::CreateRedisConnection)?;
+
+    let expired: Vec<String> = connection
+        .zrangebyscore(RESERVATIONS, "-inf", Utc::now().timestamp())
+        .await
+        .map_err(UploadError::Query)?;
+
+    drop(connection);
+
+    let mut released = 0;
+
+    for value in expired {
+        match parse_reservation(&value) {
+            Some((id, owner, size)) => {
+                if release(pool, &id, &owner, size, true).await? {
+                    released += 1;
+                }
+            },
+            None => error!("Ignoring the malformed upload reservation {}", value)
+        }
+    }
+
+    Ok(released)
+}
+
+/// Removes the data of expired uploads and gives back their reservations until the server starts shutting down.
+pub async fn sweep_uploads(storage: Data<AttachmentStorage>, pool: Data<MobcPool>, state: Data<CommonState>) {
+    let mut timer = interval(std::time::Duration::from_secs(SWEEP_INTERVAL));
+
+    loop {
+        timer.tick().await;
+
+        let _work = match state.work() {
+            Some(value) => value,
+            None => return
+        };
+
+        let sweeping = storage.clone();
+
+        match blocking(move || sweeping.sweep_uploads()).await {
+            Ok(0) => (),
+            Ok(removed) => debug!("Removed {} expired uploads", removed),
+            Err(error) => error!("Unable to remove expired uploads: {}", error)
+        }
+
+        match release_expired_uploads(&pool).await {
+            Ok(0) => (),
+            Ok(released) => debug!("Released the storage reserved by {} expired uploads", released),
+            Err(error) => error!("Unable to release the storage reserved by expired uploads: {}", error)
+        }
+
+        state.beat(SWEEPER, SWEEP_INTERVAL * 2);
+    }
+}
@@ -0,0 +1,7 @@
+use actix_web::{HttpMessage, HttpRequest};
+use common::model::User;
+
+/// Returns the user that has been authenticated for a request, if any.
+pub fn authenticated_user(request: &HttpRequest) -> Option<User> {
+    request.extensions().get::<User>().copied()
+}
@@ -0,0 +1,53 @@
+use std::fmt;
+
+use crate::configuration::configure::{configure, ConfigurationError};
+use crate::configuration::validate::{validate, probe, Severity};
+
+#[derive(Debug)]
+pub enum CheckCommandError {
+    Configure(ConfigurationError),
+    Invalid(usize)
+}
+
+impl fmt::Display for CheckCommandError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckCommandError::Configure(error) => write!(formatter, "{}", error),
+            CheckCommandError::Invalid(count) => write!(formatter, "Found {} configuration errors", count)
+        }
+    }
+}
+
+impl std::error::Error for CheckCommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            CheckCommandError::Configure(ref error) => Some(error),
+            CheckCommandError::Invalid(_) => None
+        }
+    }
+}
+
+pub async fn check(path: &Option<String>, overrides: &[String]) -> Result<(), CheckCommandError> {
+    let configuration = configure(path, overrides).map_err(CheckCommandError::Configure)?;
+
+    let mut problems = validate(&configuration);
+
+    problems.extend(probe(&configuration).await);
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .count();
+
+    if errors > 0 {
+        return Err(CheckCommandError::Invalid(errors));
+    }
+
+    println!("Configuration is valid");
+
+    Ok(())
+}
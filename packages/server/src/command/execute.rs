@@ -2,20 +2,44 @@ use std::io;
 
 use super::launch::launch;
 use super::info::info;
+use super::check::check;
+use super::maintenance::maintenance;
+use super::gc::gc;
 use super::parse::{Arguments, Commands};
 
 pub async fn execute(arguments: &Arguments) -> io::Result<()> {
     match &arguments.command {
-        Commands::Launch { path } => {
-            let server = launch(path, arguments)
+        Commands::Launch { path, overrides } => {
+            let server = launch(path, overrides, arguments)
                 .await
-                .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+                .map_err(io::Error::other)?;
 
             server.await
         },
-        Commands::Info { path } => {
-            info(path, arguments)
-                .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+        Commands::Check { path, overrides } => {
+            check(path, overrides)
+                .await
+                .map_err(io::Error::other)?;
+
+            Ok(())
+        },
+        Commands::Info { path, overrides, options } => {
+            info(path, overrides, options, arguments)
+                .map_err(io::Error::other)?;
+
+            Ok(())
+        },
+        Commands::Gc { path, overrides } => {
+            gc(path, overrides)
+                .await
+                .map_err(io::Error::other)?;
+
+            Ok(())
+        },
+        Commands::Maintenance { toggle, path, overrides, retry_after, message, url } => {
+            maintenance(path, overrides, *toggle, *retry_after, message, url)
+                .await
+                .map_err(io::Error::other)?;
 
             Ok(())
         }
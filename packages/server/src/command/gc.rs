@@ -0,0 +1,56 @@
+use std::fmt;
+use common::database::redis::{create_pool, RedisDatabaseError};
+use common::store::BlobStoreError;
+
+use crate::configuration::configure::{configure, ConfigurationError};
+
+#[derive(Debug)]
+pub enum GcCommandError {
+    Configure(ConfigurationError),
+    Redis(RedisDatabaseError),
+    Storage(BlobStoreError)
+}
+
+impl fmt::Display for GcCommandError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GcCommandError::Configure(error) => write!(formatter, "{}", error),
+            GcCommandError::Redis(error) => write!(formatter, "{}", error),
+            GcCommandError::Storage(error) => write!(formatter, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for GcCommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            GcCommandError::Configure(ref error) => Some(error),
+            GcCommandError::Redis(ref error) => Some(error),
+            GcCommandError::Storage(ref error) => Some(error)
+        }
+    }
+}
+
+/// Removes every unreferenced blob that is older than the grace period and prints what was freed.
+pub async fn gc(path: &Option<String>, overrides: &[String]) -> Result<(), GcCommandError> {
+    let configuration = configure(path, overrides).map_err(GcCommandError::Configure)?;
+
+    let pool = create_pool(configuration.redis.url.as_str())
+        .map_err(GcCommandError::Redis)?;
+
+    let store = configuration.storage.blob_store()
+        .map_err(GcCommandError::Storage)?;
+
+    let collection = store.collect_garbage(&pool)
+        .await
+        .map_err(GcCommandError::Storage)?;
+
+    println!(
+        "Scanned {} blobs and removed {}, freeing {} bytes",
+        collection.scanned,
+        collection.removed,
+        collection.bytes
+    );
+
+    Ok(())
+}
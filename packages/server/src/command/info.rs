@@ -1,20 +1,30 @@
 use std::fmt;
 
-use crate::command::parse::Arguments;
-use crate::configuration::configure::{configure, ConfigurationError};
+use crate::command::parse::{Arguments, Format, InfoOptions};
+use toml::Table;
+
+use crate::configuration::configure::{configure_layers, Configuration, ConfigurationError};
+use crate::configuration::document::{redact, schema, starter};
+use crate::configuration::layer::effective_sources;
 use crate::configuration::init::{init_logging, InitializeError};
 
 #[derive(Debug)]
 pub enum InfoCommandError {
     Configure(ConfigurationError),
-    Initialize(InitializeError)
+    Initialize(InitializeError),
+    Toml(toml::ser::Error),
+    Json(serde_json::Error),
+    Yaml(serde_yaml::Error)
 }
 
 impl fmt::Display for InfoCommandError {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
             InfoCommandError::Configure(error) => write!(formatter, "{}", error),
-            InfoCommandError::Initialize(error) => write!(formatter, "{}", error)
+            InfoCommandError::Initialize(error) => write!(formatter, "{}", error),
+            InfoCommandError::Toml(error) => write!(formatter, "{}", error),
+            InfoCommandError::Json(error) => write!(formatter, "{}", error),
+            InfoCommandError::Yaml(error) => write!(formatter, "{}", error)
         }
     }
 }
@@ -23,17 +33,55 @@ impl std::error::Error for InfoCommandError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
             InfoCommandError::Configure(ref error) => Some(error),
-            InfoCommandError::Initialize(ref error) => Some(error)
+            InfoCommandError::Initialize(ref error) => Some(error),
+            InfoCommandError::Toml(ref error) => Some(error),
+            InfoCommandError::Json(ref error) => Some(error),
+            InfoCommandError::Yaml(ref error) => Some(error)
         }
     }
 }
 
-pub fn info(path: &Option<String>, arguments: &Arguments) -> Result<(), InfoCommandError> {
-    let configuration = configure(path).map_err(InfoCommandError::Configure)?;
+fn render(table: &Table, format: Format) -> Result<String, InfoCommandError> {
+    match format {
+        Format::Toml => toml::to_string(table).map_err(InfoCommandError::Toml),
+        Format::Json => serde_json::to_string_pretty(table).map_err(InfoCommandError::Json),
+        Format::Yaml => serde_yaml::to_string(table).map_err(InfoCommandError::Yaml)
+    }
+}
 
-    init_logging(&configuration.logging.path, &arguments.verbosity).map_err(InfoCommandError::Initialize)?;
+pub fn info(path: &Option<String>, overrides: &[String], options: &InfoOptions, arguments: &Arguments) -> Result<(), InfoCommandError> {
+    if options.schema {
+        let schema = serde_json::to_string_pretty(&schema()).map_err(InfoCommandError::Json)?;
 
-    println!("{:#?}", configuration);
+        println!("{}", schema);
+
+        return Ok(());
+    }
+
+    if options.default {
+        let starter = starter(&Configuration::default()).map_err(InfoCommandError::Toml)?;
+
+        println!("{}", starter);
+
+        return Ok(());
+    }
+
+    let (configuration, sources) = configure_layers(path, overrides).map_err(InfoCommandError::Configure)?;
+
+    init_logging(&configuration.logging, &arguments.verbosity).map_err(InfoCommandError::Initialize)?;
+
+    let mut table = Table::try_from(&configuration).map_err(InfoCommandError::Toml)?;
+
+    redact(&mut table);
+
+    if options.sources {
+        for (key, value, source) in effective_sources(&table, &sources) {
+            println!("{} = {} ({})", key, value, source);
+        }
+    }
+    else {
+        print!("{}", render(&table, options.format)?);
+    }
 
     Ok(())
 }
@@ -1,25 +1,50 @@
 use std::fmt;
+use std::time::Duration;
 use actix_web::{HttpServer, App};
-use actix_web::middleware::{Compress, Logger, NormalizePath, TrailingSlash};
+use actix_web::http::KeepAlive;
+use actix_web::middleware::{Compress, NormalizePath, TrailingSlash};
+use actix_web::rt::spawn;
 use actix_web::web::{scope, Data};
 use actix_server::Server;
 use common::state::CommonState;
-use log::info;
-use mail::route::{receive_mail, send_mail};
+use common::request::RequestIdentity;
+use common::access::AccessLog;
+use common::authentication::BearerAuthentication;
+use common::store::{sweep_blobs, BlobStoreError};
+use log::{info, warn, error};
+use mail::route::{
+    receive_mail, letter_wire_config, send_mail, mail_policy, mail_quota, delete_mail,
+    upload_attachment, download_attachment, download_attachment_data, remove_attachment,
+    start_upload, get_upload, upload_range, finalize_upload, cancel_upload
+};
 use mail::state::MailState;
+use mail::configuration::LiveMailConfiguration;
+use mail::storage::AttachmentStorage;
+use mail::upload::sweep_uploads;
+use mail::fetch::fetch_remote_attachments;
 use common::database::redis::{create_pool, RedisDatabaseError};
 
-use crate::route::{healthcheck, id};
+use crate::listen::{listen, Bound, ListenError};
+use crate::shutdown::shutdown_on_terminate;
+use crate::route::{healthcheck, liveness, readiness, id, status, reload_configuration, set_maintenance};
 use crate::command::parse::Arguments;
 use crate::configuration::configure::{configure, ConfigurationError};
 use crate::configuration::init::{init_logging, InitializeError};
+use crate::configuration::reload::{Reloader, reload_on_hangup};
+use crate::configuration::tls::{server_config, reload_on_change, TlsError};
+use crate::configuration::validate::{validate, probe, has_errors, Problem, Severity};
 
 #[derive(Debug)]
 pub enum LaunchCommandError {
     Configure(ConfigurationError),
     Initialize(InitializeError),
     IO(std::io::Error),
-    Redis(RedisDatabaseError)
+    Storage(BlobStoreError),
+    Redis(RedisDatabaseError),
+    Tls(TlsError),
+    Listen(ListenError),
+    NoListeners,
+    Invalid(Vec<Problem>)
 }
 
 impl fmt::Display for LaunchCommandError {
@@ -28,7 +53,19 @@ impl fmt::Display for LaunchCommandError {
             LaunchCommandError::Configure(error) => write!(formatter, "{}", error),
             LaunchCommandError::Initialize(error) => write!(formatter, "{}", error),
             LaunchCommandError::IO(error) => write!(formatter, "{}", error),
-            LaunchCommandError::Redis(error) => write!(formatter, "{}", error)
+            LaunchCommandError::Storage(error) => write!(formatter, "{}", error),
+            LaunchCommandError::Redis(error) => write!(formatter, "{}", error),
+            LaunchCommandError::Tls(error) => write!(formatter, "{}", error),
+            LaunchCommandError::Listen(error) => write!(formatter, "{}", error),
+            LaunchCommandError::NoListeners => write!(formatter, "There are no sockets to listen on"),
+            LaunchCommandError::Invalid(problems) => {
+                let descriptions: Vec<String> = problems
+                    .iter()
+                    .map(Problem::to_string)
+                    .collect();
+
+                write!(formatter, "Invalid configuration:\n{}", descriptions.join("\n"))
+            }
         }
     }
 }
@@ -39,60 +76,193 @@ impl std::error::Error for LaunchCommandError {
             LaunchCommandError::Configure(ref error) => Some(error),
             LaunchCommandError::Initialize(ref error) => Some(error),
             LaunchCommandError::IO(ref error) => Some(error),
-            LaunchCommandError::Redis(ref error) => Some(error)
+            LaunchCommandError::Storage(ref error) => Some(error),
+            LaunchCommandError::Redis(ref error) => Some(error),
+            LaunchCommandError::Tls(ref error) => Some(error),
+            LaunchCommandError::Listen(ref error) => Some(error),
+            LaunchCommandError::NoListeners => None,
+            LaunchCommandError::Invalid(_) => None
         }
     }
 }
 
-const LOG_FORMAT: &str = "%t %{r}a %r %s %bB %Dms";
-const API_VERSION: &str = "v1";
+pub const API_VERSION: &str = "v1";
 
-pub async fn launch(path: &Option<String>, arguments: &Arguments) -> Result<Server, LaunchCommandError> {
-    let configuration = configure(path)
+pub async fn launch(path: &Option<String>, overrides: &[String], arguments: &Arguments) -> Result<Server, LaunchCommandError> {
+    let configuration = configure(path, overrides)
         .map_err(LaunchCommandError::Configure)?;
 
-    init_logging(&configuration.logging.path, &arguments.verbosity)
+    let mut problems = validate(&configuration);
+
+    // Logging is only set up once its own configuration is known to be usable, so invalid configuration is reported on the console
+    if has_errors(&problems) {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+
+        return Err(LaunchCommandError::Invalid(problems));
+    }
+
+    init_logging(&configuration.logging, &arguments.verbosity)
         .map_err(LaunchCommandError::Initialize)?;
 
-    let pool = create_pool("redis://localhost:6379")
+    problems.extend(probe(&configuration).await);
+
+    for problem in &problems {
+        match problem.severity {
+            Severity::Error => error!("{}", problem),
+            Severity::Warning => warn!("{}", problem)
+        }
+    }
+
+    if has_errors(&problems) {
+        return Err(LaunchCommandError::Invalid(problems));
+    }
+
+    let pool = create_pool(configuration.redis.url.as_str())
         .map_err(LaunchCommandError::Redis)?;
 
-    info!("Starting HTTP server at {}:{}", configuration.http.bind.0, configuration.http.bind.1);
+    let tls = match &configuration.http.tls {
+        Some(tls) => Some(server_config(tls).map_err(LaunchCommandError::Tls)?),
+        None => None
+    };
+
+    let mut sockets = vec![];
+
+    for listener in configuration.http.listeners() {
+        sockets.extend(listen(&listener).map_err(LaunchCommandError::Listen)?);
+    }
+
+    if sockets.is_empty() {
+        return Err(LaunchCommandError::NoListeners);
+    }
 
-    let root = match configuration.http.directory {
+    let root = match &configuration.http.directory {
         Some(value) => format!("{}/{}", value, API_VERSION),
         None => String::from(API_VERSION)
     };
 
+    let blobs = configuration.storage.blob_store()
+        .map_err(LaunchCommandError::Storage)?;
+    let storage = AttachmentStorage::new(&configuration.storage.directory, configuration.storage.upload_expiry, blobs.clone())
+        .map_err(LaunchCommandError::IO)?;
+
     let pool_data = Data::new(pool);
+    let storage_data = Data::new(storage);
     let mail_state_data = Data::new(MailState::default());
-    let common_state_data = Data::new(CommonState::default());
-    let mail_configuration_data = Data::new(configuration.mail.clone());
+    let common_state_data = Data::new(CommonState {
+        host: configuration.http.host.clone(),
+        ..Default::default()
+    });
+
+    if let Some(notice) = configuration.maintenance.notice() {
+        warn!("Starting in maintenance mode, new letters are refused");
+
+        common_state_data.set_maintenance(Some(notice));
+    }
+
+    let shutdown_state_data = common_state_data.clone();
+    let mail_configuration_data = Data::new(LiveMailConfiguration::new(configuration.mail.clone()));
+    let admin_data = Data::new(configuration.admin.clone());
+    let authentication = BearerAuthentication::new(configuration.authentication.users());
+    let reloader_data = Data::new(Reloader::new(
+        path.clone(),
+        overrides.to_vec(),
+        configuration.clone(),
+        mail_configuration_data.clone(),
+        common_state_data.clone()
+    ));
+
+    let certificates = tls
+        .as_ref()
+        .map(|(_, resolver)| resolver.clone());
+
+    if let (Some(resolver), Some(settings)) = (&certificates, &configuration.http.tls) {
+        spawn(reload_on_change(resolver.clone(), settings.check_interval));
+    }
+
+    spawn(reload_on_hangup(reloader_data.clone(), certificates));
+    spawn(sweep_uploads(storage_data.clone(), pool_data.clone(), common_state_data.clone()));
+    spawn(fetch_remote_attachments(storage_data.clone(), pool_data.clone(), mail_configuration_data.clone(), common_state_data.clone()));
+
+    if configuration.storage.gc_interval > 0 {
+        spawn(sweep_blobs(blobs, pool_data.clone(), common_state_data.clone(), configuration.storage.gc_interval));
+    }
 
-    let bind = configuration.http.bind;
     let server = HttpServer::new(move || {
         let mail_scope = scope("mail")
-            .app_data(pool_data.clone())
             .app_data(mail_state_data.clone())
             .app_data(mail_configuration_data.clone())
+            .app_data(storage_data.clone())
+            .app_data(letter_wire_config())
             .service(receive_mail)
-            .service(send_mail);
+            .service(send_mail)
+            .service(mail_policy)
+            .service(mail_quota)
+            .service(delete_mail)
+            .service(upload_attachment)
+            .service(download_attachment)
+            .service(download_attachment_data)
+            .service(remove_attachment)
+            .service(start_upload)
+            .service(get_upload)
+            .service(upload_range)
+            .service(finalize_upload)
+            .service(cancel_upload);
 
         let root_scope = scope(&root)
+            .app_data(pool_data.clone())
             .app_data(common_state_data.clone())
+            .app_data(admin_data.clone())
+            .app_data(reloader_data.clone())
             .service(healthcheck)
+            .service(liveness)
+            .service(readiness)
             .service(id)
+            .service(status)
+            .service(reload_configuration)
+            .service(set_maintenance)
             .service(mail_scope);
 
         App::new()
             .wrap(NormalizePath::new(TrailingSlash::Trim))
-            .wrap(Logger::new(LOG_FORMAT))
+            .wrap(authentication.clone())
+            .wrap(RequestIdentity)
+            .wrap(AccessLog)
             .wrap(Compress::default())
             .service(root_scope)
-    })
-    .bind(bind)
-    .map_err(LaunchCommandError::IO)?
-    .run();
+    });
+
+    let keep_alive = match configuration.http.keep_alive {
+        0 => KeepAlive::Disabled,
+        seconds => KeepAlive::Timeout(Duration::from_secs(seconds))
+    };
+
+    let mut server = server
+        .keep_alive(keep_alive)
+        .client_request_timeout(Duration::from_millis(configuration.http.client_request_timeout))
+        .max_connections(configuration.http.max_connections)
+        .shutdown_timeout(configuration.http.shutdown_timeout)
+        .disable_signals();
+
+    if let Some(workers) = configuration.http.workers {
+        server = server.workers(workers);
+    }
+
+    for socket in sockets {
+        info!("Listening for {} on {}", if tls.is_some() && matches!(socket, Bound::Tcp(_)) { "HTTPS" } else { "HTTP" }, socket);
+
+        server = match (socket, &tls) {
+            (Bound::Tcp(listener), Some((config, _))) => server.listen_rustls(listener, config.clone()),
+            (Bound::Tcp(listener), None) => server.listen(listener),
+            (Bound::Unix(listener, _), _) => server.listen_uds(listener)
+        }
+        .map_err(LaunchCommandError::IO)?;
+    }
+
+    let server = server.run();
+
+    spawn(shutdown_on_terminate(server.handle(), shutdown_state_data, configuration.http.shutdown_timeout));
 
     Ok(server)
 }
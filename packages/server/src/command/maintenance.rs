@@ -0,0 +1,121 @@
+use std::fmt;
+use awc::Client;
+use awc::http::StatusCode;
+
+use crate::command::launch::API_VERSION;
+use crate::command::parse::Toggle;
+use crate::configuration::configure::{configure, Configuration, ConfigurationError, Listener};
+use crate::route::{MaintenanceRequest, Status};
+
+#[derive(Debug)]
+pub enum MaintenanceCommandError {
+    Configure(ConfigurationError),
+    NoToken,
+    NoAddress,
+    Request(String),
+    Rejected(StatusCode, String)
+}
+
+impl fmt::Display for MaintenanceCommandError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaintenanceCommandError::Configure(error) => write!(formatter, "{}", error),
+            MaintenanceCommandError::NoToken => write!(formatter, "admin.token must be set to use the administration API"),
+            MaintenanceCommandError::NoAddress => write!(formatter, "There is no TCP listener to connect to, use --url instead"),
+            MaintenanceCommandError::Request(error) => write!(formatter, "{}", error),
+            MaintenanceCommandError::Rejected(status, body) => write!(formatter, "The server responded with {}: {}", status, body)
+        }
+    }
+}
+
+impl std::error::Error for MaintenanceCommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            MaintenanceCommandError::Configure(ref error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+/// Returns the API root of the first TCP listener, connecting through loopback for wildcard addresses.
+fn local_url(configuration: &Configuration) -> Option<String> {
+    let scheme = match configuration.http.tls {
+        Some(_) => "https",
+        None => "http"
+    };
+    let root = match &configuration.http.directory {
+        Some(value) => format!("{}/{}", value, API_VERSION),
+        None => String::from(API_VERSION)
+    };
+
+    configuration.http
+        .listeners()
+        .into_iter()
+        .find_map(|listener| match listener {
+            Listener::Tcp { address, port } => {
+                let host = match address.as_str() {
+                    "0.0.0.0" => String::from("127.0.0.1"),
+                    "::" => String::from("[::1]"),
+                    value if value.contains(':') => format!("[{}]", value),
+                    value => value.to_string()
+                };
+
+                Some(format!("{}://{}:{}/{}", scheme, host, port, root))
+            },
+            _ => None
+        })
+}
+
+pub async fn maintenance(
+    path: &Option<String>,
+    overrides: &[String],
+    toggle: Toggle,
+    retry_after: Option<u64>,
+    message: &Option<String>,
+    url: &Option<String>
+) -> Result<(), MaintenanceCommandError> {
+    let configuration = configure(path, overrides).map_err(MaintenanceCommandError::Configure)?;
+
+    let token = configuration.admin.token
+        .as_ref()
+        .ok_or(MaintenanceCommandError::NoToken)?;
+    let url = match url {
+        Some(value) => value.trim_end_matches('/').to_string(),
+        None => local_url(&configuration).ok_or(MaintenanceCommandError::NoAddress)?
+    };
+
+    let body = MaintenanceRequest {
+        enabled: toggle == Toggle::On,
+        retry_after,
+        message: message.clone()
+    };
+
+    let mut response = Client::default()
+        .post(format!("{}/admin/maintenance", url))
+        .bearer_auth(token)
+        .send_json(&body)
+        .await
+        .map_err(|error| MaintenanceCommandError::Request(error.to_string()))?;
+
+    if !response.status().is_success() {
+        let body = response
+            .body()
+            .await
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+            .unwrap_or_default();
+
+        return Err(MaintenanceCommandError::Rejected(response.status(), body));
+    }
+
+    let status: Status = response
+        .json()
+        .await
+        .map_err(|error| MaintenanceCommandError::Request(error.to_string()))?;
+
+    match status.maintenance {
+        Some(notice) => println!("{} is in maintenance mode since {}, clients retry after {} seconds", status.host, notice.since, notice.retry_after),
+        None => println!("{} is accepting letters", status.host)
+    }
+
+    Ok(())
+}
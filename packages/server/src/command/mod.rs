@@ -2,3 +2,6 @@ pub mod launch;
 pub mod parse;
 pub mod execute;
 pub mod info;
+pub mod check;
+pub mod maintenance;
+pub mod gc;
@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand, Args};
+use clap::{Parser, Subcommand, Args, ValueEnum};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None, arg_required_else_help = true, propagate_version = true)]
@@ -15,15 +15,91 @@ pub enum Commands {
     /// Start the HTTP server and wait
     Launch {
         #[arg(short = 'p', long = "config-path", help = "Path to configuration file")]
-        path: Option<String>
+        path: Option<String>,
+
+        #[arg(short = 's', long = "set", value_name = "KEY=VALUE", help = "Override a configuration value, read as TOML unless the setting is a string")]
+        overrides: Vec<String>
+    },
+    /// Check the application configuration for problems and exit
+    Check {
+        #[arg(short = 'p', long = "config-path", help = "Path to configuration file")]
+        path: Option<String>,
+
+        #[arg(short = 's', long = "set", value_name = "KEY=VALUE", help = "Override a configuration value, read as TOML unless the setting is a string")]
+        overrides: Vec<String>
     },
     /// Print application configuration information and exit
     Info {
         #[arg(short = 'p', long = "config-path", help = "Path to configuration file")]
-        path: Option<String>
+        path: Option<String>,
+
+        #[arg(short = 's', long = "set", value_name = "KEY=VALUE", help = "Override a configuration value, read as TOML unless the setting is a string")]
+        overrides: Vec<String>,
+
+        #[command(flatten)]
+        options: InfoOptions
+    },
+    /// Remove unreferenced blobs from the blob store and exit
+    Gc {
+        #[arg(short = 'p', long = "config-path", help = "Path to configuration file")]
+        path: Option<String>,
+
+        #[arg(short = 's', long = "set", value_name = "KEY=VALUE", help = "Override a configuration value, read as TOML unless the setting is a string")]
+        overrides: Vec<String>
+    },
+    /// Turn maintenance mode on or off on a running server and exit
+    Maintenance {
+        #[arg(value_enum, help = "Whether maintenance mode should be on or off")]
+        toggle: Toggle,
+
+        #[arg(short = 'p', long = "config-path", help = "Path to configuration file")]
+        path: Option<String>,
+
+        #[arg(short = 's', long = "set", value_name = "KEY=VALUE", help = "Override a configuration value, read as TOML unless the setting is a string")]
+        overrides: Vec<String>,
+
+        #[arg(short = 'r', long = "retry-after", value_name = "SECONDS", help = "How long clients are asked to wait before retrying")]
+        retry_after: Option<u64>,
+
+        #[arg(short = 'm', long = "message", help = "An explanation for clients")]
+        message: Option<String>,
+
+        #[arg(short = 'u', long = "url", help = "API root of the server, such as https://example.com/v1")]
+        url: Option<String>
     }
 }
 
+/// Whether a mode should be enabled.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Toggle {
+    On,
+    Off
+}
+
+/// The format the effective configuration is printed in.
+#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+pub enum Format {
+    #[default]
+    Toml,
+    Json,
+    Yaml
+}
+
+#[derive(Args)]
+pub struct InfoOptions {
+    #[arg(short = 'f', long = "format", value_enum, default_value_t = Format::Toml, help = "Format of the effective configuration")]
+    pub format: Format,
+
+    #[arg(long = "sources", conflicts_with_all = ["format", "default", "schema"], help = "Print where every configuration value comes from")]
+    pub sources: bool,
+
+    #[arg(long = "default", conflicts_with_all = ["format", "schema"], help = "Print a commented configuration file with default values")]
+    pub default: bool,
+
+    #[arg(long = "schema", conflicts_with = "format", help = "Print the JSON Schema of the configuration file")]
+    pub schema: bool
+}
+
 #[derive(Args)]
 #[group(multiple = false)]
 pub struct Verbosity {
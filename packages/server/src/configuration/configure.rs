@@ -1,32 +1,326 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env::vars;
 use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
 use std::fmt;
+use chrono::Utc;
+use common::model::{Identifier, User};
+use common::s3::S3Configuration;
+use common::state::MaintenanceNotice;
+use common::store::{BlobStore, BlobStoreError};
 use log::Level;
 use mail::configuration::MailConfiguration;
 use serde::{Serialize, Deserialize};
-use toml::from_str;
+use schemars::JsonSchema;
+use toml::{from_str, Table};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+use super::layer::{Layers, LayerError, Source, Sources};
+use super::document::string_keys;
+
+/// How log lines are written.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+
+    /// One JSON object per line.
+    Json
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Logging {
-    /// The location of the logging configuration file.
+    /// The location of the logging configuration file, which replaces every other logging setting.
     pub path: Option<String>,
 
-    /// The logging level to use.
-    pub level: Option<Level>
+    /// The logging level to use unless --verbose or --quiet is given.
+    #[schemars(with = "Option<String>")]
+    pub level: Option<Level>,
+
+    /// Logging levels of individual modules, such as "actix_web" or "mail::route".
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, String>")]
+    pub modules: BTreeMap<String, Level>,
+
+    /// The format of log lines, either "text" or "json".
+    #[serde(default)]
+    pub format: LogFormat
+}
+
+/// A TLS protocol version.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+
+    #[serde(rename = "1.3")]
+    Tls13
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Tls {
+    /// The location of the PEM certificate chain, starting with the server certificate.
+    pub certificate: String,
+
+    /// The location of the PEM private key of the server certificate.
+    pub key: String,
+
+    /// The location of PEM certificate authorities that peer client certificates must chain to.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+
+    /// Whether to reject clients without a certificate when a client certificate authority is set.
+    #[serde(default)]
+    pub require_client_certificate: bool,
+
+    /// The oldest TLS version to accept, either "1.2" or "1.3".
+    #[serde(default)]
+    pub minimum_version: TlsVersion,
+
+    /// How often in seconds to check the certificate and key for changes, or zero to only reload on SIGHUP.
+    #[serde(default = "default_certificate_check_interval")]
+    pub check_interval: u64
+}
+
+fn default_certificate_check_interval() -> u64 {
+    60
+}
+
+/// A socket the HTTP server accepts connections on.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Listener {
+    /// A TCP socket on an IPv4 or IPv6 address, such as "0.0.0.0" or "::".
+    Tcp {
+        address: String,
+        port: u16
+    },
+
+    /// A Unix domain socket for a reverse proxy on the same machine, never served over TLS.
+    Unix {
+        path: String,
+
+        /// The permissions of the socket file, such as 0o660.
+        #[serde(default)]
+        mode: Option<u32>
+    },
+
+    /// Every socket passed in by systemd socket activation through LISTEN_FDS.
+    Systemd
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Http {
-    /// The local IP address & port to bind the HTTP server to.
+    /// The local IP address & port to bind the HTTP server to when no listeners are set.
     pub bind: (String, u16),
 
+    /// The sockets to accept connections on, replacing bind.
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
+
     /// The host name of this instance.
     pub host: String,
 
     /// An optional path prefix to serve the API on.
-    pub directory: Option<String>
+    pub directory: Option<String>,
+
+    /// Serve HTTPS instead of plain HTTP.
+    #[serde(default)]
+    pub tls: Option<Tls>,
+
+    /// How many seconds in-flight requests are given to finish after SIGTERM before the server exits.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+
+    /// The number of worker threads, defaulting to one per CPU core.
+    #[serde(default)]
+    pub workers: Option<usize>,
+
+    /// How many seconds an idle connection is kept open, or zero to disable keep-alive.
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: u64,
+
+    /// How many milliseconds a client has to send the request headers, or zero for no limit.
+    #[serde(default = "default_client_request_timeout")]
+    pub client_request_timeout: u64,
+
+    /// The maximum number of concurrent connections each worker accepts.
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_keep_alive() -> u64 {
+    5
+}
+
+fn default_client_request_timeout() -> u64 {
+    5000
+}
+
+fn default_max_connections() -> usize {
+    25000
+}
+
+impl Http {
+    /// Returns the configured listeners, or a TCP listener on bind if there are none.
+    pub fn listeners(&self) -> Vec<Listener> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        vec![
+            Listener::Tcp {
+                address: self.bind.0.clone(),
+                port: self.bind.1
+            }
+        ]
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Redis {
+    /// The connection URL of the Redis server.
+    pub url: String
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Storage {
+    /// The directory where attachment data and unfinished uploads are kept.
+    #[serde(default = "default_storage_directory")]
+    pub directory: String,
+
+    /// How many seconds an unfinished upload is kept after it last received data.
+    #[serde(default = "default_upload_expiry")]
+    pub upload_expiry: u64,
+
+    /// How many seconds pass between garbage collections of unreferenced blobs, or zero to only collect with the gc command.
+    #[serde(default = "default_gc_interval")]
+    pub gc_interval: u64,
+
+    /// How many seconds an unreferenced blob is kept before garbage collection removes it.
+    #[serde(default = "default_gc_grace")]
+    pub gc_grace: u64,
+
+    /// Keep blobs in an S3-compatible bucket instead of the storage directory.
+    #[serde(default)]
+    pub s3: Option<S3Configuration>
+}
+
+impl Storage {
+    /// The directory of the content-addressed blob store.
+    pub fn blob_directory(&self) -> PathBuf {
+        Path::new(&self.directory).join("blobs")
+    }
+
+    /// Opens the configured blob store.
+    pub fn blob_store(&self) -> Result<BlobStore, BlobStoreError> {
+        match &self.s3 {
+            Some(s3) => BlobStore::s3(s3, self.gc_grace),
+            None => BlobStore::new(&self.blob_directory(), self.gc_grace).map_err(BlobStoreError::Io)
+        }
+    }
+}
+
+fn default_storage_directory() -> String {
+    String::from("data")
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+fn default_upload_expiry() -> u64 {
+    86400
+}
+
+fn default_gc_interval() -> u64 {
+    3600
+}
+
+fn default_gc_grace() -> u64 {
+    3600
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            directory: default_storage_directory(),
+            upload_expiry: default_upload_expiry(),
+            gc_interval: default_gc_interval(),
+            gc_grace: default_gc_grace(),
+            s3: None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone)]
+pub struct Admin {
+    /// The bearer token required by administration endpoints, which are disabled without one.
+    pub token: Option<String>
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone)]
+pub struct Authentication {
+    /// The users of this instance, mapping each user identifier to the hexadecimal SHA-256 digest
+    /// of the bearer token the user authenticates with, as printed by `printf %s "$TOKEN" | sha256sum`.
+    #[serde(default)]
+    pub users: BTreeMap<String, String>
+}
+
+impl Authentication {
+    /// Returns the users by the digest of their token, skipping any that are not valid.
+    pub fn users(&self) -> HashMap<String, User> {
+        self.users
+            .iter()
+            .filter_map(|(id, digest)| {
+                let id = Identifier::try_from(id.as_str()).ok()?;
+
+                Some((digest.to_ascii_lowercase(), User { id }))
+            })
+            .collect()
+    }
+}
+
+/// How many seconds clients are asked to wait during maintenance unless told otherwise.
+pub const DEFAULT_MAINTENANCE_RETRY_AFTER: u64 = 300;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Maintenance {
+    /// Whether to refuse new letters while keeping read-only endpoints available.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// How many seconds clients are asked to wait before retrying.
+    #[serde(default = "default_maintenance_retry_after")]
+    pub retry_after: u64,
+
+    /// An optional explanation for clients.
+    #[serde(default)]
+    pub message: Option<String>
+}
+
+fn default_maintenance_retry_after() -> u64 {
+    DEFAULT_MAINTENANCE_RETRY_AFTER
+}
+
+impl Maintenance {
+    /// Returns the notice to show clients if maintenance is enabled.
+    pub fn notice(&self) -> Option<MaintenanceNotice> {
+        if !self.enabled {
+            return None;
+        }
+
+        Some(MaintenanceNotice {
+            retry_after: self.retry_after,
+            message: self.message.clone(),
+            since: Utc::now()
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone)]
 pub struct Configuration {
     /// The logging configuration.
     pub logging: Logging,
@@ -35,14 +329,36 @@ pub struct Configuration {
     pub http: Http,
 
     /// The mail service configuration.
-    pub mail: MailConfiguration
+    pub mail: MailConfiguration,
+
+    /// The administration configuration.
+    #[serde(default)]
+    pub admin: Admin,
+
+    /// How users authenticate with this instance.
+    #[serde(default)]
+    pub authentication: Authentication,
+
+    /// The Redis database configuration.
+    #[serde(default)]
+    pub redis: Redis,
+
+    /// The maintenance mode configuration.
+    #[serde(default)]
+    pub maintenance: Maintenance,
+
+    /// The attachment storage configuration.
+    #[serde(default)]
+    pub storage: Storage
 }
 
 impl Default for Logging {
     fn default() -> Self {
         Self {
             path: Option::None,
-            level: Option::Some(Level::Info)
+            level: Option::Some(Level::Info),
+            modules: BTreeMap::new(),
+            format: LogFormat::default()
         }
     }
 }
@@ -51,8 +367,33 @@ impl Default for Http {
     fn default() -> Self {
         Self {
             bind: (String::from("localhost"), 8100),
+            listeners: vec![],
             host: String::from("localhost:8100"),
-            directory: None
+            directory: None,
+            tls: None,
+            shutdown_timeout: default_shutdown_timeout(),
+            workers: None,
+            keep_alive: default_keep_alive(),
+            client_request_timeout: default_client_request_timeout(),
+            max_connections: default_max_connections()
+        }
+    }
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retry_after: default_maintenance_retry_after(),
+            message: None
+        }
+    }
+}
+
+impl Default for Redis {
+    fn default() -> Self {
+        Self {
+            url: String::from("redis://localhost:6379")
         }
     }
 }
@@ -60,14 +401,18 @@ impl Default for Http {
 #[derive(Debug)]
 pub enum ConfigurationError {
     Read(std::io::Error),
-    Deserialize(toml::de::Error)
+    Deserialize(toml::de::Error),
+    Serialize(toml::ser::Error),
+    Layer(LayerError)
 }
 
 impl fmt::Display for ConfigurationError {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ConfigurationError::Read(error) => write!(formatter, "{}", error),
-            ConfigurationError::Deserialize(error) => write!(formatter, "{}", error)
+            ConfigurationError::Deserialize(error) => write!(formatter, "{}", error),
+            ConfigurationError::Serialize(error) => write!(formatter, "{}", error),
+            ConfigurationError::Layer(error) => write!(formatter, "{}", error)
         }
     }
 }
@@ -76,23 +421,51 @@ impl std::error::Error for ConfigurationError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
             ConfigurationError::Read(ref error) => Some(error),
-            ConfigurationError::Deserialize(ref error) => Some(error)
+            ConfigurationError::Deserialize(ref error) => Some(error),
+            ConfigurationError::Serialize(ref error) => Some(error),
+            ConfigurationError::Layer(ref error) => Some(error)
         }
     }
 }
 
-fn load_configuration_file(path: &String) -> Result<Configuration, ConfigurationError> {
+fn load_configuration_file(path: &String) -> Result<Table, ConfigurationError> {
     let value = read_to_string(path).map_err(ConfigurationError::Read)?;
-    let configuration = from_str::<Configuration>(&value).map_err(ConfigurationError::Deserialize)?;
+    let table = from_str::<Table>(&value).map_err(ConfigurationError::Deserialize)?;
 
-    Ok(configuration)
+    Ok(table)
+}
+
+/// Builds the configuration from its layers, where later layers take precedence:
+/// defaults, the configuration file, `FEDCIPHER_` environment variables and command line overrides.
+pub fn configure_layers(path: &Option<String>, overrides: &[String]) -> Result<(Configuration, Sources), ConfigurationError> {
+    let defaults = Table::try_from(Configuration::default()).map_err(ConfigurationError::Serialize)?;
+    let mut layers = Layers::new(defaults, string_keys());
+
+    if let Some(value) = path {
+        let table = load_configuration_file(value)?;
+
+        layers
+            .merge(&table, Source::File(value.clone()))
+            .map_err(ConfigurationError::Layer)?;
+    }
+
+    layers
+        .merge_environment(vars())
+        .map_err(ConfigurationError::Layer)?;
+    layers
+        .merge_overrides(overrides)
+        .map_err(ConfigurationError::Layer)?;
+
+    let (table, sources) = layers.finish();
+    let configuration = table
+        .try_into::<Configuration>()
+        .map_err(ConfigurationError::Deserialize)?;
+
+    Ok((configuration, sources))
 }
 
-pub fn configure(path: &Option<String>) -> Result<Configuration, ConfigurationError> {
-    let configuration = match path {
-        Some(value) => load_configuration_file(value)?,
-        None => Configuration::default()
-    };
+pub fn configure(path: &Option<String>, overrides: &[String]) -> Result<Configuration, ConfigurationError> {
+    let (configuration, _) = configure_layers(path, overrides)?;
 
     Ok(configuration)
 }
@@ -5,35 +5,39 @@ use log4rs::filter::{Filter, Response};
 use log4rs::filter::threshold::ThresholdFilter;
 use log4rs::{Config, init_file};
 use log4rs::append::console::{ConsoleAppender, Target};
-use log4rs::config::{init_config, Root, Appender};
+use log4rs::config::{init_config, Root, Appender, Logger};
 use log4rs::config::runtime::ConfigErrors;
+use log4rs::encode::Encode;
+use log4rs::encode::json::JsonEncoder;
 use log4rs::encode::pattern::PatternEncoder;
 
 use crate::command::parse::Verbosity;
+use super::configure::{Logging, LogFormat};
 
-const PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S)} {h({l})} {m}{n}";
+const PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S)} {h({l})} {X(request_id)(-)} {m}{n}";
 
-fn stdout() -> ConsoleAppender {
+fn encoder(format: LogFormat) -> Box<dyn Encode> {
+    match format {
+        LogFormat::Text => Box::new(PatternEncoder::new(PATTERN)),
+        LogFormat::Json => Box::new(JsonEncoder::new())
+    }
+}
+
+fn stdout(format: LogFormat) -> ConsoleAppender {
     let target = Target::Stdout;
-    let encoder = Box::new(
-        PatternEncoder::new(PATTERN)
-    );
 
     ConsoleAppender::builder()
         .target(target)
-        .encoder(encoder)
+        .encoder(encoder(format))
         .build()
 }
 
-fn stderr() -> ConsoleAppender {
+fn stderr(format: LogFormat) -> ConsoleAppender {
     let target = Target::Stderr;
-    let encoder = Box::new(
-        PatternEncoder::new(PATTERN)
-    );
 
     ConsoleAppender::builder()
         .target(target)
-        .encoder(encoder)
+        .encoder(encoder(format))
         .build()
 }
 
@@ -86,8 +90,8 @@ impl Filter for InverseThresholdFilter {
     }
 }
 
-pub fn init_logging(path: &Option<String>, verbosity: &Verbosity) -> Result<(), InitializeError> {
-    match path {
+pub fn init_logging(logging: &Logging, verbosity: &Verbosity) -> Result<(), InitializeError> {
+    match &logging.path {
         Some(value) => {
             init_file(value, Default::default()).map_err(InitializeError::File)?;
 
@@ -103,7 +107,7 @@ pub fn init_logging(path: &Option<String>, verbosity: &Verbosity) -> Result<(),
                 .build(
                     "stdout",
                     Box::new(
-                        stdout()
+                        stdout(logging.format)
                     )
                 );
             let stderr = Appender::builder()
@@ -115,26 +119,31 @@ pub fn init_logging(path: &Option<String>, verbosity: &Verbosity) -> Result<(),
                 .build(
                     "stderr",
                     Box::new(
-                        stderr()
+                        stderr(logging.format)
                     )
                 );
 
-            let factory = |level: LevelFilter| {
-                Root::builder().appenders(["stdout", "stderr"]).build(level)
-            };
-
-            let root = if verbosity.verbose {
-                factory(Debug)
+            let level = if verbosity.verbose {
+                Debug
             }
             else if verbosity.quiet {
-                factory(Warn)
+                Warn
             }
             else {
-                factory(Info)
+                logging.level.map_or(Info, |level| level.to_level_filter())
             };
 
+            let loggers = logging.modules
+                .iter()
+                .map(|(module, level)| Logger::builder().build(module, level.to_level_filter()));
+
+            let root = Root::builder()
+                .appenders(["stdout", "stderr"])
+                .build(level);
+
             let config = Config::builder()
                 .appenders([stdout, stderr])
+                .loggers(loggers)
                 .build(root)
                 .map_err(InitializeError::Config)?;
 
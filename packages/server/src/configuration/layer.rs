@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use toml::{Table, Value};
+
+/// The prefix of environment variables that override configuration values.
+pub const ENVIRONMENT_PREFIX: &str = "FEDCIPHER_";
+
+/// The separator between nested keys in environment variable names.
+pub const ENVIRONMENT_SEPARATOR: &str = "__";
+
+/// Where an effective configuration value came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(String),
+    Environment(String),
+    Argument
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(formatter, "default"),
+            Source::File(path) => write!(formatter, "file {}", path),
+            Source::Environment(name) => write!(formatter, "environment variable {}", name),
+            Source::Argument => write!(formatter, "command line")
+        }
+    }
+}
+
+/// The source of every configuration value, keyed by dotted path.
+pub type Sources = BTreeMap<String, Source>;
+
+#[derive(Debug)]
+pub enum LayerError {
+    Override(String),
+    Conflict(String)
+}
+
+impl fmt::Display for LayerError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LayerError::Override(value) => write!(formatter, "{} is not of the form KEY=VALUE", value),
+            LayerError::Conflict(path) => write!(formatter, "{} cannot be set because its parent is not a table", path)
+        }
+    }
+}
+
+impl std::error::Error for LayerError {}
+
+/// A configuration table being built from successive layers.
+pub struct Layers {
+    table: Table,
+    sources: Sources,
+    strings: BTreeSet<String>
+}
+
+/// Parses a raw override as a TOML value, falling back to a plain string.
+///
+/// Values of string settings are taken as they are, so that a token such as `12345` or `true`
+/// stays a string, unless they are quoted like TOML strings.
+fn parse_value(raw: &str, string: bool) -> Value {
+    let quoted = raw.starts_with('"') || raw.starts_with('\'');
+
+    if string && !quoted {
+        return Value::String(raw.to_string());
+    }
+
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/// Calls the visitor for every leaf of a table, where arrays and empty tables are leaves.
+fn visit_leaves(table: &Table, prefix: &mut Vec<String>, visitor: &mut dyn FnMut(&[String], &Value)) {
+    for (key, value) in table {
+        prefix.push(key.clone());
+
+        match value {
+            Value::Table(inner) if !inner.is_empty() => visit_leaves(inner, prefix, visitor),
+            _ => visitor(prefix, value)
+        }
+
+        prefix.pop();
+    }
+}
+
+impl Layers {
+    /// Starts from a table of default values, given the dotted keys of the settings that hold strings.
+    pub fn new(defaults: Table, strings: BTreeSet<String>) -> Self {
+        let mut layers = Layers {
+            table: Table::new(),
+            sources: Sources::new(),
+            strings
+        };
+
+        layers
+            .merge(&defaults, Source::Default)
+            .expect("default configuration values never conflict");
+
+        layers
+    }
+
+    /// Sets a single value, replacing any value of a lower layer.
+    pub fn insert(&mut self, path: &[String], value: Value, source: Source) -> Result<(), LayerError> {
+        let dotted = path.join(".");
+        let (last, parents) = match path.split_last() {
+            Some(value) => value,
+            None => return Ok(())
+        };
+
+        let mut table = &mut self.table;
+
+        for key in parents {
+            let entry = table
+                .entry(key.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+
+            table = match entry {
+                Value::Table(inner) => inner,
+                _ => return Err(LayerError::Conflict(dotted))
+            };
+        }
+
+        table.insert(last.clone(), value);
+
+        self.sources.retain(|key, _| key != &dotted && !key.starts_with(&format!("{}.", dotted)));
+        self.sources.insert(dotted, source);
+
+        Ok(())
+    }
+
+    /// Merges every leaf of a table.
+    pub fn merge(&mut self, table: &Table, source: Source) -> Result<(), LayerError> {
+        let mut leaves = vec![];
+
+        visit_leaves(table, &mut vec![], &mut |path, value| leaves.push((path.to_vec(), value.clone())));
+
+        for (path, value) in leaves {
+            self.insert(&path, value, source.clone())?;
+        }
+
+        Ok(())
+    }
+
+    /// Merges every prefixed environment variable, such as `FEDCIPHER_MAIL__LIMIT__BODY_SIZE`.
+    ///
+    /// Values are read as TOML except for string settings, as described for [`parse_value`].
+    pub fn merge_environment<I: IntoIterator<Item = (String, String)>>(&mut self, variables: I) -> Result<(), LayerError> {
+        let mut variables: Vec<(String, String)> = variables
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENVIRONMENT_PREFIX))
+            .collect();
+
+        variables.sort();
+
+        for (name, raw) in variables {
+            let path: Vec<String> = name[ENVIRONMENT_PREFIX.len()..]
+                .split(ENVIRONMENT_SEPARATOR)
+                .map(str::to_lowercase)
+                .collect();
+
+            let value = parse_value(&raw, self.strings.contains(&path.join(".")));
+
+            self.insert(&path, value, Source::Environment(name.clone()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Merges every command line override of the form `mail.limit.body_size=1024`.
+    ///
+    /// Values are read as TOML except for string settings, as described for [`parse_value`].
+    pub fn merge_overrides(&mut self, overrides: &[String]) -> Result<(), LayerError> {
+        for value in overrides {
+            let (key, raw) = value
+                .split_once('=')
+                .ok_or(LayerError::Override(value.clone()))?;
+            let path: Vec<String> = key
+                .trim()
+                .split('.')
+                .map(String::from)
+                .collect();
+
+            let value = parse_value(raw.trim(), self.strings.contains(&path.join(".")));
+
+            self.insert(&path, value, Source::Argument)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the merged table along with the source of each value.
+    pub fn finish(self) -> (Table, Sources) {
+        (self.table, self.sources)
+    }
+}
+
+/// Returns every leaf of a table keyed by dotted path.
+pub fn leaves(table: &Table) -> BTreeMap<String, Value> {
+    let mut values = BTreeMap::new();
+
+    visit_leaves(table, &mut vec![], &mut |path, value| {
+        values.insert(path.join("."), value.clone());
+    });
+
+    values
+}
+
+/// Returns the source of every leaf of an effective configuration table.
+pub fn effective_sources(table: &Table, sources: &Sources) -> Vec<(String, Value, Source)> {
+    leaves(table)
+        .into_iter()
+        .map(|(key, value)| {
+            let source = sources
+                .get(&key)
+                .cloned()
+                .unwrap_or(Source::Default);
+
+            (key, value, source)
+        })
+        .collect()
+}
@@ -1,2 +1,7 @@
 pub mod configure;
+pub mod document;
 pub mod init;
+pub mod layer;
+pub mod reload;
+pub mod tls;
+pub mod validate;
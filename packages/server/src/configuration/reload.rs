@@ -0,0 +1,236 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use actix_web::web::Data;
+use log::{info, warn, error};
+use common::state::CommonState;
+use mail::configuration::LiveMailConfiguration;
+use serde::Serialize;
+use toml::{Table, Value};
+
+use super::configure::{configure, Configuration, ConfigurationError};
+use super::document::redact;
+use super::layer::leaves;
+use super::tls::CertificateResolver;
+use super::validate::{validate, has_errors, Problem};
+
+/// The prefixes of configuration keys that can be changed without a restart.
+const RELOADABLE_PREFIXES: [&str; 2] = ["mail.", "maintenance."];
+
+/// The prefix of maintenance configuration keys.
+const MAINTENANCE_PREFIX: &str = "maintenance.";
+
+/// A configuration value that differs between the previously loaded and the reloaded configuration.
+#[derive(Serialize, Debug)]
+pub struct Change {
+    /// The dotted path of the value.
+    pub key: String,
+
+    /// The value it was last loaded with, if any, with secrets hidden.
+    pub old: Option<Value>,
+
+    /// The reloaded value, if any, with secrets hidden.
+    pub new: Option<Value>,
+
+    /// Whether the value is now in effect, or requires a restart.
+    pub applied: bool
+}
+
+#[derive(Debug)]
+pub enum ReloadError {
+    Configure(ConfigurationError),
+    Serialize(toml::ser::Error),
+    Invalid(Vec<Problem>)
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReloadError::Configure(error) => write!(formatter, "{}", error),
+            ReloadError::Serialize(error) => write!(formatter, "{}", error),
+            ReloadError::Invalid(problems) => {
+                let descriptions: Vec<String> = problems
+                    .iter()
+                    .map(Problem::to_string)
+                    .collect();
+
+                write!(formatter, "{}", descriptions.join("\n"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ReloadError::Configure(ref error) => Some(error),
+            ReloadError::Serialize(ref error) => Some(error),
+            ReloadError::Invalid(_) => None
+        }
+    }
+}
+
+/// Returns true if a configuration key can be changed without a restart.
+fn is_reloadable(key: &str) -> bool {
+    RELOADABLE_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+}
+
+fn describe(value: &Option<Value>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => String::from("nothing")
+    }
+}
+
+/// Serializes a configuration, returning its values as they are and with secrets hidden.
+fn tables(configuration: &Configuration) -> Result<(Table, Table), ReloadError> {
+    let table = Table::try_from(configuration).map_err(ReloadError::Serialize)?;
+    let mut redacted = table.clone();
+
+    redact(&mut redacted);
+
+    Ok((table, redacted))
+}
+
+/// The configurations a reload is compared against.
+struct Baseline {
+    /// The configuration in effect, of which only the reloadable sections are ever replaced.
+    running: Configuration,
+
+    /// The configuration as it was last loaded, so that a change requiring a restart is only reported once.
+    loaded: Configuration
+}
+
+/// Lists every value that differs between the last loaded and a new configuration.
+///
+/// A change is applied if it can be reloaded or if it restores the running value.
+/// Changes are found on the actual values but only ever carry redacted ones, since they are logged and returned to clients.
+fn diff(baseline: &Baseline, new: &Configuration) -> Result<Vec<Change>, ReloadError> {
+    let (running, _) = tables(&baseline.running)?;
+    let (old, old_redacted) = tables(&baseline.loaded)?;
+    let (new, new_redacted) = tables(new)?;
+    let running = leaves(&running);
+    let (old, old_redacted) = (leaves(&old), leaves(&old_redacted));
+    let (new, new_redacted) = (leaves(&new), leaves(&new_redacted));
+
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+
+    keys.sort();
+    keys.dedup();
+
+    let changes = keys
+        .into_iter()
+        .filter(|key| old.get(*key) != new.get(*key))
+        .map(|key| Change {
+            key: key.clone(),
+            old: old_redacted.get(key).cloned(),
+            new: new_redacted.get(key).cloned(),
+            applied: is_reloadable(key) || running.get(key) == new.get(key)
+        })
+        .collect();
+
+    Ok(changes)
+}
+
+/// Re-reads the configuration from the same layers it was launched with.
+pub struct Reloader {
+    path: Option<String>,
+    overrides: Vec<String>,
+    baseline: Mutex<Baseline>,
+    mail: Data<LiveMailConfiguration>,
+    state: Data<CommonState>
+}
+
+impl Reloader {
+    pub fn new(path: Option<String>, overrides: Vec<String>, configuration: Configuration, mail: Data<LiveMailConfiguration>, state: Data<CommonState>) -> Self {
+        Reloader {
+            path,
+            overrides,
+            baseline: Mutex::new(Baseline {
+                running: configuration.clone(),
+                loaded: configuration
+            }),
+            mail,
+            state
+        }
+    }
+
+    /// Loads the configuration and swaps in the new mail and maintenance configuration if it is valid.
+    ///
+    /// Other sections are reported once but only take effect after a restart.
+    pub fn reload(&self) -> Result<Vec<Change>, ReloadError> {
+        let configuration = configure(&self.path, &self.overrides).map_err(ReloadError::Configure)?;
+        let problems = validate(&configuration);
+
+        if has_errors(&problems) {
+            return Err(ReloadError::Invalid(problems));
+        }
+
+        for problem in &problems {
+            warn!("{}", problem);
+        }
+
+        let mut baseline = match self.baseline.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        };
+
+        let changes = diff(&baseline, &configuration)?;
+
+        if changes.is_empty() {
+            info!("Reloaded configuration without changes");
+
+            return Ok(changes);
+        }
+
+        for change in &changes {
+            if !change.applied {
+                warn!("Changing {} from {} to {} requires a restart", change.key, describe(&change.old), describe(&change.new));
+            }
+            else if is_reloadable(&change.key) {
+                info!("Changed {} from {} to {}", change.key, describe(&change.old), describe(&change.new));
+            }
+            else {
+                info!("Changed {} back to its running value {}", change.key, describe(&change.new));
+            }
+        }
+
+        self.mail.replace(configuration.mail.clone());
+        baseline.running.mail = configuration.mail.clone();
+
+        if changes.iter().any(|change| change.key.starts_with(MAINTENANCE_PREFIX)) {
+            self.state.set_maintenance(configuration.maintenance.notice());
+            baseline.running.maintenance = configuration.maintenance.clone();
+        }
+
+        baseline.loaded = configuration;
+
+        Ok(changes)
+    }
+}
+
+/// Reloads the configuration and any TLS certificate every time the process receives SIGHUP.
+pub async fn reload_on_hangup(reloader: Data<Reloader>, certificates: Option<Arc<CertificateResolver>>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(value) => value,
+        Err(error) => {
+            error!("Unable to listen for SIGHUP: {}", error);
+
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration");
+
+        if let Err(error) = reloader.reload() {
+            error!("Refusing to reload invalid configuration: {}", error);
+        }
+
+        if let Some(resolver) = &certificates {
+            if let Err(error) = resolver.reload() {
+                error!("Keeping the current TLS certificate: {}", error);
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+use std::fs::{metadata, File};
+use std::io::{self, BufReader};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use actix_web::rt::time::interval;
+use log::{info, error};
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, NoClientAuth, ResolvesServerCert};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::version::{TLS12, TLS13};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, SupportedProtocolVersion};
+use rustls_pemfile::{certs, read_all, Item};
+
+use super::configure::{Tls, TlsVersion};
+
+#[derive(Debug)]
+pub enum TlsError {
+    Read(String, io::Error),
+    NoCertificates(String),
+    NoKey(String),
+    UnsupportedKey(String),
+    Config(rustls::Error)
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Read(path, error) => write!(formatter, "{} cannot be read: {}", path, error),
+            TlsError::NoCertificates(path) => write!(formatter, "{} contains no PEM certificates", path),
+            TlsError::NoKey(path) => write!(formatter, "{} contains no PEM private key", path),
+            TlsError::UnsupportedKey(path) => write!(formatter, "{} contains an unsupported private key", path),
+            TlsError::Config(error) => write!(formatter, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            TlsError::Read(_, ref error) => Some(error),
+            TlsError::Config(ref error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| TlsError::Read(path.to_string(), error))
+}
+
+/// Reads every certificate of a PEM file.
+fn load_certificates(path: &str) -> Result<Vec<Certificate>, TlsError> {
+    let certificates = certs(&mut open(path)?)
+        .map_err(|error| TlsError::Read(path.to_string(), error))?;
+
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_string()));
+    }
+
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first private key of a PEM file.
+fn load_key(path: &str) -> Result<PrivateKey, TlsError> {
+    let items = read_all(&mut open(path)?)
+        .map_err(|error| TlsError::Read(path.to_string(), error))?;
+
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None
+        })
+        .ok_or(TlsError::NoKey(path.to_string()))
+}
+
+/// Reads the certificate chain and private key a server presents.
+pub fn load_certified_key(tls: &Tls) -> Result<CertifiedKey, TlsError> {
+    let certificates = load_certificates(&tls.certificate)?;
+    let key = load_key(&tls.key)?;
+    let key = any_supported_type(&key).map_err(|_| TlsError::UnsupportedKey(tls.key.clone()))?;
+
+    Ok(CertifiedKey::new(certificates, key))
+}
+
+/// Reads the certificate authorities client certificates must chain to.
+pub fn load_client_authorities(path: &str) -> Result<RootCertStore, TlsError> {
+    let certificates = certs(&mut open(path)?)
+        .map_err(|error| TlsError::Read(path.to_string(), error))?;
+
+    let mut store = RootCertStore::empty();
+    let (valid, _) = store.add_parsable_certificates(&certificates);
+
+    if valid == 0 {
+        return Err(TlsError::NoCertificates(path.to_string()));
+    }
+
+    Ok(store)
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Serves the current certificate chain and swaps it when the files change.
+pub struct CertificateResolver {
+    tls: Tls,
+    key: RwLock<Arc<CertifiedKey>>,
+    modified: RwLock<(Option<SystemTime>, Option<SystemTime>)>
+}
+
+impl fmt::Debug for CertificateResolver {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("CertificateResolver")
+            .field("certificate", &self.tls.certificate)
+            .field("key", &self.tls.key)
+            .finish()
+    }
+}
+
+impl CertificateResolver {
+    pub fn new(tls: &Tls) -> Result<Self, TlsError> {
+        let modified = (modified(&tls.certificate), modified(&tls.key));
+        let key = load_certified_key(tls)?;
+
+        Ok(CertificateResolver {
+            tls: tls.clone(),
+            key: RwLock::new(Arc::new(key)),
+            modified: RwLock::new(modified)
+        })
+    }
+
+    /// Reads the certificate chain and private key again, keeping the current ones if that fails.
+    ///
+    /// A failed read is not retried until the files change again.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let modified = (modified(&self.tls.certificate), modified(&self.tls.key));
+
+        match self.modified.write() {
+            Ok(mut guard) => *guard = modified,
+            Err(poisoned) => *poisoned.into_inner() = modified
+        }
+
+        let key = load_certified_key(&self.tls)?;
+
+        match self.key.write() {
+            Ok(mut guard) => *guard = Arc::new(key),
+            Err(poisoned) => *poisoned.into_inner() = Arc::new(key)
+        }
+
+        info!("Reloaded TLS certificate from {}", self.tls.certificate);
+
+        Ok(())
+    }
+
+    /// Returns true if either file has been modified since it was last read.
+    pub fn changed(&self) -> bool {
+        let current = (modified(&self.tls.certificate), modified(&self.tls.key));
+
+        match self.modified.read() {
+            Ok(guard) => *guard != current,
+            Err(poisoned) => *poisoned.into_inner() != current
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        match self.key.read() {
+            Ok(guard) => Some(guard.clone()),
+            Err(poisoned) => Some(poisoned.into_inner().clone())
+        }
+    }
+}
+
+static TLS12_AND_LATER: [&SupportedProtocolVersion; 2] = [&TLS13, &TLS12];
+static TLS13_AND_LATER: [&SupportedProtocolVersion; 1] = [&TLS13];
+
+fn protocol_versions(minimum: TlsVersion) -> &'static [&'static SupportedProtocolVersion] {
+    match minimum {
+        TlsVersion::Tls12 => &TLS12_AND_LATER,
+        TlsVersion::Tls13 => &TLS13_AND_LATER
+    }
+}
+
+/// Builds the rustls server configuration along with the resolver that serves its certificates.
+pub fn server_config(tls: &Tls) -> Result<(ServerConfig, Arc<CertificateResolver>), TlsError> {
+    let resolver = Arc::new(CertificateResolver::new(tls)?);
+
+    let verifier = match &tls.client_ca {
+        Some(path) if tls.require_client_certificate => AllowAnyAuthenticatedClient::new(load_client_authorities(path)?),
+        Some(path) => AllowAnyAnonymousOrAuthenticatedClient::new(load_client_authorities(path)?),
+        None => NoClientAuth::new()
+    };
+
+    let mut config = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(protocol_versions(tls.minimum_version))
+        .map_err(TlsError::Config)?
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(resolver.clone());
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok((config, resolver))
+}
+
+/// Reloads the certificate whenever its files change, checking every interval.
+pub async fn reload_on_change(resolver: Arc<CertificateResolver>, seconds: u64) {
+    if seconds == 0 {
+        return;
+    }
+
+    let mut timer = interval(Duration::from_secs(seconds));
+
+    loop {
+        timer.tick().await;
+
+        if !resolver.changed() {
+            continue;
+        }
+
+        if let Err(error) = resolver.reload() {
+            error!("Keeping the current TLS certificate: {}", error);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use std::fmt;
+use std::fs::File;
+use actix_web::http::header::HeaderName;
+use common::database::redis::{create_pool, ping};
+use common::model::{Address, Identifier};
+use common::s3::S3Bucket;
+use mail::configuration::{MailConfiguration, RateLimit, DEFAULT_STAMP_DIFFICULTY};
+
+use super::configure::{Configuration, Http, Listener, LogFormat};
+use super::document::redact_url;
+use super::tls::{load_certified_key, load_client_authorities};
+
+/// How serious a configuration problem is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// The configuration cannot work as intended.
+    Error,
+
+    /// The configuration works but is probably not what was intended.
+    Warning
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(formatter, "error"),
+            Severity::Warning => write!(formatter, "warning")
+        }
+    }
+}
+
+/// A semantic problem with a configuration value.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// How serious the problem is.
+    pub severity: Severity,
+
+    /// The dotted path of the offending value.
+    pub key: String,
+
+    /// A description of the problem.
+    pub message: String
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}: {}: {}", self.severity, self.key, self.message)
+    }
+}
+
+/// Returns true if any of the problems is an error.
+pub fn has_errors(problems: &[Problem]) -> bool {
+    problems
+        .iter()
+        .any(|problem| problem.severity == Severity::Error)
+}
+
+/// Collects problems while validating a configuration.
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn error(&mut self, key: &str, message: String) {
+        self.0.push(Problem {
+            severity: Severity::Error,
+            key: key.to_string(),
+            message
+        });
+    }
+
+    fn warning(&mut self, key: &str, message: String) {
+        self.0.push(Problem {
+            severity: Severity::Warning,
+            key: key.to_string(),
+            message
+        });
+    }
+}
+
+fn validate_http(configuration: &Configuration, problems: &mut Problems) {
+    let http = &configuration.http;
+
+    // The default only suits trying the server out locally, where nothing has to reach its addresses
+    if http.host == Http::default().host {
+        problems.warning("http.host", format!("is left at {}, which other instances cannot address", http.host));
+    }
+    else if !Address::is_valid_host(&http.host) {
+        problems.error("http.host", format!("{} cannot be the host of an address", http.host));
+    }
+
+    if let Some(directory) = &http.directory {
+        if directory.is_empty() || directory.starts_with('/') || directory.ends_with('/') || directory.contains("//") {
+            problems.error("http.directory", format!("{} must be a path without leading, trailing or repeated slashes", directory));
+        }
+    }
+
+    for (index, listener) in http.listeners.iter().enumerate() {
+        let key = format!("http.listeners.{}", index);
+
+        match listener {
+            Listener::Tcp { address, .. } if address.is_empty() => {
+                problems.error(&format!("{}.address", key), String::from("must not be empty"));
+            },
+            Listener::Unix { path, .. } if path.is_empty() => {
+                problems.error(&format!("{}.path", key), String::from("must not be empty"));
+            },
+            Listener::Unix { mode: Some(mode), .. } if *mode > 0o777 => {
+                problems.error(&format!("{}.mode", key), format!("{:o} is not a file permission mode", mode));
+            },
+            _ => {}
+        }
+    }
+
+    let unix = http.listeners
+        .iter()
+        .any(|listener| matches!(listener, Listener::Unix { .. }));
+
+    if unix && configuration.mail.rate.client.is_some() && configuration.mail.rate.client_header.is_none() {
+        problems.warning("mail.rate.client", String::from("does not apply to requests on Unix sockets, which have no client address, unless mail.rate.client_header is set"));
+    }
+
+    if http.listeners.iter().filter(|listener| **listener == Listener::Systemd).count() > 1 {
+        problems.warning("http.listeners", String::from("lists systemd more than once but its sockets are only used once"));
+    }
+
+    if http.workers == Some(0) {
+        problems.error("http.workers", String::from("must be greater than zero"));
+    }
+
+    if http.max_connections == 0 {
+        problems.error("http.max_connections", String::from("must be greater than zero or no connection can be accepted"));
+    }
+
+    if let Some(tls) = &http.tls {
+        if let Err(error) = load_certified_key(tls) {
+            problems.error("http.tls", error.to_string());
+        }
+
+        match &tls.client_ca {
+            Some(path) => {
+                if let Err(error) = load_client_authorities(path) {
+                    problems.error("http.tls.client_ca", error.to_string());
+                }
+            },
+            None if tls.require_client_certificate => {
+                problems.warning("http.tls.require_client_certificate", String::from("has no effect without http.tls.client_ca"));
+            },
+            None => {}
+        }
+    }
+}
+
+fn validate_logging(configuration: &Configuration, problems: &mut Problems) {
+    let logging = &configuration.logging;
+
+    if let Some(path) = &logging.path {
+        if let Err(error) = File::open(path) {
+            problems.error("logging.path", format!("{} cannot be read: {}", path, error));
+        }
+
+        if !logging.modules.is_empty() || logging.format != LogFormat::default() {
+            problems.warning("logging.path", String::from("replaces logging.modules and logging.format, which are ignored"));
+        }
+    }
+
+    if logging.modules.keys().any(String::is_empty) {
+        problems.error("logging.modules", String::from("must not contain an empty module name"));
+    }
+}
+
+fn validate_rate(key: &str, limit: &Option<RateLimit>, problems: &mut Problems) {
+    if let Some(limit) = limit {
+        if limit.capacity == 0 {
+            problems.error(&format!("{}.capacity", key), String::from("must be greater than zero"));
+        }
+
+        if !limit.refill.is_finite() || limit.refill <= 0.0 {
+            problems.error(&format!("{}.refill", key), String::from("must be a positive number"));
+        }
+    }
+}
+
+fn validate_mail(mail: &MailConfiguration, problems: &mut Problems) {
+    let limit = &mail.limit;
+    let require = &mail.require;
+
+    if limit.recipients == 0 {
+        problems.error("mail.limit.recipients", String::from("must be greater than zero or no letter can be received"));
+    }
+
+    if require.subject && limit.subject_size == 0 {
+        problems.error("mail.limit.subject_size", String::from("must be greater than zero when a subject is required"));
+    }
+
+    if require.body && limit.body_size == 0 {
+        problems.error("mail.limit.body_size", String::from("must be greater than zero when a body is required"));
+    }
+
+    if limit.embedded_attachments > 0 && limit.embedded_attachment_size == 0 {
+        problems.warning("mail.limit.embedded_attachment_size", String::from("is zero so only empty embedded attachments can be received"));
+    }
+
+    if limit.remote_attachments > 0 && limit.remote_attachment_size == 0 {
+        problems.warning("mail.limit.remote_attachment_size", String::from("is zero so only empty remote attachments can be received"));
+    }
+
+    if require.sent_at && limit.age == 0 {
+        problems.error("mail.limit.age", String::from("must be greater than zero when a sent timestamp is required"));
+    }
+
+    if limit.age > mail.replay.expiry {
+        problems.warning("mail.limit.age", format!("exceeds mail.replay.expiry of {} so old letters can be replayed", mail.replay.expiry));
+    }
+
+    if require.labels.len() as u64 > limit.labels {
+        problems.error("mail.require.labels", format!("requires {} labels but mail.limit.labels only allows {}", require.labels.len(), limit.labels));
+    }
+
+    if require.labels.iter().any(String::is_empty) {
+        problems.error("mail.require.labels", String::from("must not contain an empty label"));
+    }
+
+    match mail.accept.stamp_difficulty {
+        Some(difficulty) if difficulty > 256 => {
+            problems.error("mail.accept.stamp_difficulty", format!("{} exceeds the 256 bits of a stamp digest", difficulty));
+        },
+        None if mail.accept.first_contact_stamp => {
+            problems.warning("mail.accept.stamp_difficulty", format!("is not set so first contact stamps use and advertise the default difficulty of {}", DEFAULT_STAMP_DIFFICULTY));
+        },
+        _ => {}
+    }
+
+    validate_rate("mail.rate.client", &mail.rate.client, problems);
+    validate_rate("mail.rate.host", &mail.rate.host, problems);
+    validate_rate("mail.rate.sender", &mail.rate.sender, problems);
+    validate_rate("mail.rate.user", &mail.rate.user, problems);
+
+    if let Some(header) = &mail.rate.client_header {
+        if HeaderName::from_bytes(header.as_bytes()).is_err() {
+            problems.error("mail.rate.client_header", format!("{} is not a header name", header));
+        }
+
+        if mail.rate.client_hops == 0 {
+            problems.error("mail.rate.client_hops", String::from("must be at least one, for the proxy that adds the client address"));
+        }
+    }
+
+    if mail.fetch.enabled && mail.fetch.concurrency == 0 {
+        problems.error("mail.fetch.concurrency", String::from("must be greater than zero or no remote attachment can be fetched"));
+    }
+
+    if mail.replay.expiry == 0 {
+        problems.error("mail.replay.expiry", String::from("must be greater than zero"));
+    }
+
+    if mail.quota.default.bytes == 0 || mail.quota.default.letters == 0 {
+        problems.warning("mail.quota.default", String::from("is zero so users without an override cannot receive letters"));
+    }
+
+    if mail.quota.default.uploads == 0 {
+        problems.warning("mail.quota.default.uploads", String::from("is zero so users without an override cannot upload attachments in ranges"));
+    }
+
+    for user in mail.quota.users.keys() {
+        if let Err(error) = Identifier::try_from(user.as_str()) {
+            problems.error(&format!("mail.quota.users.{}", user), format!("is not a user identifier: {}", error));
+        }
+    }
+}
+
+fn validate_authentication(configuration: &Configuration, problems: &mut Problems) {
+    for (user, digest) in &configuration.authentication.users {
+        let key = format!("authentication.users.{}", user);
+
+        if let Err(error) = Identifier::try_from(user.as_str()) {
+            problems.error(&key, format!("is not a user identifier: {}", error));
+        }
+
+        if digest.len() != 64 || !digest.chars().all(|character| character.is_ascii_hexdigit()) {
+            problems.error(&key, String::from("must be the hexadecimal SHA-256 digest of the bearer token of the user"));
+        }
+    }
+}
+
+fn validate_storage(configuration: &Configuration, problems: &mut Problems) {
+    let storage = &configuration.storage;
+
+    if storage.directory.is_empty() {
+        problems.error("storage.directory", String::from("must not be empty"));
+    }
+
+    if storage.upload_expiry == 0 {
+        problems.error("storage.upload_expiry", String::from("must be greater than zero"));
+    }
+
+    if storage.gc_interval == 0 {
+        problems.warning("storage.gc_interval", String::from("is zero, so unreferenced blobs are only removed by the gc command"));
+    }
+
+    if let Some(s3) = &storage.s3 {
+        if let Err(error) = S3Bucket::new(s3) {
+            problems.error("storage.s3.endpoint", error.to_string());
+        }
+
+        if s3.bucket.is_empty() {
+            problems.error("storage.s3.bucket", String::from("must not be empty"));
+        }
+
+        if s3.access_key.is_empty() || s3.secret_key.is_empty() {
+            problems.error("storage.s3", String::from("access_key and secret_key must not be empty"));
+        }
+
+        if s3.timeout == 0 {
+            problems.error("storage.s3.timeout", String::from("must be greater than zero"));
+        }
+    }
+}
+
+/// Returns every semantic problem with a configuration that can be found without connecting to anything.
+pub fn validate(configuration: &Configuration) -> Vec<Problem> {
+    let mut problems = Problems::default();
+
+    validate_http(configuration, &mut problems);
+    validate_logging(configuration, &mut problems);
+    validate_mail(&configuration.mail, &mut problems);
+    validate_authentication(configuration, &mut problems);
+    validate_storage(configuration, &mut problems);
+
+    problems.0
+}
+
+/// Returns every problem with the services a configuration points to.
+pub async fn probe(configuration: &Configuration) -> Vec<Problem> {
+    let mut problems = Problems::default();
+
+    let result = match create_pool(configuration.redis.url.as_str()) {
+        Ok(pool) => ping(&pool).await,
+        Err(error) => Err(error)
+    };
+
+    if let Err(error) = result {
+        problems.error("redis.url", format!("{} cannot be reached: {}", redact_url(&configuration.redis.url), error));
+    }
+
+    if let Some(s3) = &configuration.storage.s3 {
+        if let Ok(bucket) = S3Bucket::new(s3) {
+            if let Err(error) = bucket.check_bucket().await {
+                problems.error("storage.s3.bucket", format!("{} cannot be used: {}", s3.bucket, error));
+            }
+        }
+    }
+
+    problems.0
+}
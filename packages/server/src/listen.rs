@@ -0,0 +1,243 @@
+use std::env::{remove_var, var};
+use std::fmt;
+use std::fs::{remove_dir, remove_file, rename, set_permissions, symlink_metadata, DirBuilder, Permissions};
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::configuration::configure::Listener;
+
+/// The first file descriptor passed by systemd socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The length of the queue of connections waiting to be accepted.
+const BACKLOG: i32 = 2048;
+
+/// Whether the sockets passed by systemd have been taken, since they can only be owned once.
+static SYSTEMD_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// The number of sockets passed by systemd, or why none can be used, as read by [`take_systemd_environment`].
+static SYSTEMD_SOCKETS: OnceLock<Result<RawFd, String>> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum ListenError {
+    Resolve(String, io::Error),
+    Bind(String, io::Error),
+    Permissions(String, io::Error),
+    Systemd(String)
+}
+
+impl fmt::Display for ListenError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenError::Resolve(address, error) => write!(formatter, "Unable to resolve {}: {}", address, error),
+            ListenError::Bind(address, error) => write!(formatter, "Unable to listen on {}: {}", address, error),
+            ListenError::Permissions(path, error) => write!(formatter, "Unable to set the permissions of {}: {}", path, error),
+            ListenError::Systemd(reason) => write!(formatter, "Unable to use systemd sockets: {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for ListenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ListenError::Resolve(_, ref error) => Some(error),
+            ListenError::Bind(_, ref error) => Some(error),
+            ListenError::Permissions(_, ref error) => Some(error),
+            ListenError::Systemd(_) => None
+        }
+    }
+}
+
+/// A listening socket ready to be handed to the HTTP server.
+///
+/// Unix sockets carry the path they are reachable at, which is not the one they were bound to when they were moved into place.
+#[derive(Debug)]
+pub enum Bound {
+    Tcp(TcpListener),
+    Unix(UnixListener, String)
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bound::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => write!(formatter, "{}", address),
+                Err(_) => write!(formatter, "an unknown TCP address")
+            },
+            Bound::Unix(_, path) => write!(formatter, "{}", path)
+        }
+    }
+}
+
+fn bind_tcp(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+
+    // Keep IPv6 listeners from also claiming the IPv4 port so both can be configured side by side
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+
+    Ok(socket.into())
+}
+
+/// Listens on every address a host name resolves to.
+fn listen_tcp(address: &str, port: u16) -> Result<Vec<Bound>, ListenError> {
+    let name = format!("{}:{}", address, port);
+    let mut addresses: Vec<SocketAddr> = (address, port)
+        .to_socket_addrs()
+        .map_err(|error| ListenError::Resolve(name.clone(), error))?
+        .collect();
+
+    addresses.sort();
+    addresses.dedup();
+
+    addresses
+        .into_iter()
+        .map(|address| bind_tcp(address)
+            .map(Bound::Tcp)
+            .map_err(|error| ListenError::Bind(address.to_string(), error)))
+        .collect()
+}
+
+/// Binds a Unix domain socket with the given permissions before it can be connected to.
+///
+/// The socket is bound in a directory only this process can enter, given its mode there and then
+/// moved into place, so that it is never reachable with the permissions of the umask.
+fn bind_unix_private(path: &str, mode: u32) -> Result<UnixListener, ListenError> {
+    let target = Path::new(path);
+    let name = target
+        .file_name()
+        .ok_or_else(|| ListenError::Bind(path.to_string(), io::Error::new(io::ErrorKind::InvalidInput, "the path has no file name")))?;
+    let parent = target
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let staging = parent.join(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    let staged = staging.join(name);
+
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .map_err(|error| ListenError::Bind(staging.display().to_string(), error))?;
+
+    let result = UnixListener::bind(&staged)
+        .map_err(|error| ListenError::Bind(path.to_string(), error))
+        .and_then(|listener| {
+            set_permissions(&staged, Permissions::from_mode(mode))
+                .map_err(|error| ListenError::Permissions(path.to_string(), error))?;
+            rename(&staged, target)
+                .map_err(|error| ListenError::Bind(path.to_string(), error))?;
+
+            Ok(listener)
+        });
+
+    if result.is_err() {
+        let _ = remove_file(&staged);
+    }
+
+    let _ = remove_dir(&staging);
+
+    result
+}
+
+/// Listens on a Unix domain socket, replacing a stale socket file left by a previous run.
+fn listen_unix(path: &str, mode: Option<u32>) -> Result<Vec<Bound>, ListenError> {
+    if let Ok(metadata) = symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            remove_file(path).map_err(|error| ListenError::Bind(path.to_string(), error))?;
+        }
+    }
+
+    let listener = match mode {
+        Some(mode) => bind_unix_private(path, mode)?,
+        None => UnixListener::bind(path).map_err(|error| ListenError::Bind(path.to_string(), error))?
+    };
+
+    Ok(vec![Bound::Unix(listener, path.to_string())])
+}
+
+/// Reads and clears LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES so that child processes do not inherit them.
+///
+/// Must be called before the runtime starts any threads, since changing the environment is not
+/// safe while other threads may read it.
+pub fn take_systemd_environment() {
+    let sockets = systemd_socket_count();
+
+    if sockets.is_ok() {
+        remove_var("LISTEN_PID");
+        remove_var("LISTEN_FDS");
+        remove_var("LISTEN_FDNAMES");
+    }
+
+    let _ = SYSTEMD_SOCKETS.set(sockets);
+}
+
+fn systemd_socket_count() -> Result<RawFd, String> {
+    let pid = var("LISTEN_PID").map_err(|_| String::from("LISTEN_PID is not set"))?;
+
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Err(String::from("LISTEN_PID does not match this process"));
+    }
+
+    var("LISTEN_FDS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or(String::from("LISTEN_FDS is not a number"))
+}
+
+/// Takes ownership of the sockets passed by systemd through LISTEN_PID and LISTEN_FDS.
+fn listen_systemd() -> Result<Vec<Bound>, ListenError> {
+    if SYSTEMD_TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(vec![]);
+    }
+
+    let count = SYSTEMD_SOCKETS
+        .get()
+        .cloned()
+        .unwrap_or(Err(String::from("LISTEN_PID is not set")))
+        .map_err(ListenError::Systemd)?;
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|descriptor| {
+            // Systemd hands these descriptors to this process alone and they are only taken once
+            let socket = unsafe { Socket::from_raw_fd(descriptor) };
+            let address = socket
+                .local_addr()
+                .map_err(|error| ListenError::Bind(format!("file descriptor {}", descriptor), error))?;
+
+            match address.as_socket() {
+                Some(_) => Ok(Bound::Tcp(socket.into())),
+                None => {
+                    let listener: UnixListener = socket.into();
+                    let path = listener
+                        .local_addr()
+                        .ok()
+                        .and_then(|address| address.as_pathname().map(|path| path.display().to_string()))
+                        .unwrap_or(String::from("an unnamed Unix socket"));
+
+                    Ok(Bound::Unix(listener, path))
+                }
+            }
+        })
+        .collect()
+}
+
+/// Opens every socket of a configured listener.
+pub fn listen(listener: &Listener) -> Result<Vec<Bound>, ListenError> {
+    match listener {
+        Listener::Tcp { address, port } => listen_tcp(address, *port),
+        Listener::Unix { path, mode } => listen_unix(path, *mode),
+        Listener::Systemd => listen_systemd()
+    }
+}
@@ -1,15 +1,20 @@
 mod configuration;
 mod command;
+mod listen;
 mod route;
+mod shutdown;
 
 use std::io::Result;
-use actix_web::main;
+use actix_web::rt::System;
 use command::parse::parse;
 use command::execute::execute;
+use listen::take_systemd_environment;
 
-#[main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let arguments = parse();
 
-    execute(&arguments).await
+    // The environment is only safe to change before the runtime starts its threads
+    take_systemd_environment();
+
+    System::new().block_on(execute(&arguments))
 }
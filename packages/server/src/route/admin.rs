@@ -0,0 +1,146 @@
+use std::fmt;
+use actix_web::body::BoxBody;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::web::{Data, Json};
+use actix_web::{post, HttpRequest, HttpResponse, Responder, Result, ResponseError};
+
+use chrono::Utc;
+use common::state::{CommonState, MaintenanceNotice};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::configuration::configure::{Admin, DEFAULT_MAINTENANCE_RETRY_AFTER};
+use crate::configuration::reload::{Reloader, ReloadError};
+use super::status::Status;
+
+#[derive(Debug)]
+pub enum AdminError {
+    Disabled,
+    Unauthorized,
+    Reload(ReloadError)
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdminError::Disabled => {
+                write!(formatter, "Administration is disabled")
+            },
+            AdminError::Unauthorized => {
+                write!(formatter, "A valid administration token is required")
+            },
+            AdminError::Reload(error) => {
+                write!(formatter, "{}", error)
+            }
+        }
+    }
+}
+
+impl ResponseError for AdminError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        match &self {
+            AdminError::Disabled => {
+                HttpResponse::Forbidden().body(self.to_string())
+            },
+            AdminError::Unauthorized => {
+                HttpResponse::Unauthorized().body(self.to_string())
+            },
+            AdminError::Reload(_) => {
+                HttpResponse::UnprocessableEntity().body(self.to_string())
+            }
+        }
+    }
+}
+
+/// Compares two byte strings in time that does not depend on where they differ.
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false;
+    }
+
+    left
+        .iter()
+        .zip(right)
+        .fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// Checks that a request carries the configured administration bearer token.
+pub fn authorize(request: &HttpRequest, admin: &Admin) -> Result<(), AdminError> {
+    let token = admin.token
+        .as_ref()
+        .ok_or(AdminError::Disabled)?;
+
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(AdminError::Unauthorized)?;
+
+    if !constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+        return Err(AdminError::Unauthorized);
+    }
+
+    Ok(())
+}
+
+#[post("/admin/reload")]
+pub async fn reload_configuration(
+    request: HttpRequest,
+    admin: Data<Admin>,
+    reloader: Data<Reloader>
+) -> Result<impl Responder> {
+    authorize(&request, &admin)?;
+
+    let changes = reloader.reload().map_err(AdminError::Reload)?;
+
+    Ok(Json(changes))
+}
+
+/// A request to start or end maintenance.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MaintenanceRequest {
+    /// Whether maintenance mode should be enabled.
+    pub enabled: bool,
+
+    /// How many seconds clients are asked to wait before retrying.
+    #[serde(default)]
+    pub retry_after: Option<u64>,
+
+    /// An optional explanation for clients.
+    #[serde(default)]
+    pub message: Option<String>
+}
+
+#[post("/admin/maintenance")]
+pub async fn set_maintenance(
+    request: HttpRequest,
+    json: Json<MaintenanceRequest>,
+    admin: Data<Admin>,
+    state: Data<CommonState>
+) -> Result<impl Responder> {
+    authorize(&request, &admin)?;
+
+    let body = json.into_inner();
+
+    if body.enabled {
+        let since = state
+            .maintenance()
+            .map_or_else(Utc::now, |notice| notice.since);
+
+        warn!("Entering maintenance mode, new letters are refused");
+
+        state.set_maintenance(Some(MaintenanceNotice {
+            retry_after: body.retry_after.unwrap_or(DEFAULT_MAINTENANCE_RETRY_AFTER),
+            message: body.message,
+            since
+        }));
+    }
+    else {
+        info!("Leaving maintenance mode");
+
+        state.set_maintenance(None);
+    }
+
+    Ok(Json(Status::new(&state)))
+}
@@ -1,6 +1,129 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 use actix_web::{get, HttpResponse, Responder};
+use actix_web::rt::time::timeout;
+use actix_web::web::Data;
+use common::database::redis::{ping, MobcPool};
+use common::state::CommonState;
+use serde::{Serialize, Deserialize};
 
+/// How long the database may take to answer before this instance is considered not ready.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The outcome of a single readiness check.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    /// The dependency works as expected.
+    Pass,
+
+    /// The dependency works but this instance is degraded, which does not affect readiness.
+    Warn,
+
+    /// The dependency does not work and this instance should not receive traffic.
+    Fail
+}
+
+/// The result of probing one dependency of this instance.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Check {
+    pub status: CheckStatus,
+
+    /// How long the check took, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u128>,
+
+    /// Why the check did not pass.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>
+}
+
+impl Check {
+    fn pass() -> Self {
+        Check { status: CheckStatus::Pass, duration: None, message: None }
+    }
+
+    fn warn(message: String) -> Self {
+        Check { status: CheckStatus::Warn, duration: None, message: Some(message) }
+    }
+
+    fn fail(message: String) -> Self {
+        Check { status: CheckStatus::Fail, duration: None, message: Some(message) }
+    }
+}
+
+/// Whether this instance can serve traffic, along with every check that decided it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<String, Check>
+}
+
+async fn check_database(pool: &MobcPool) -> Check {
+    let started = Instant::now();
+
+    let mut check = match timeout(DATABASE_TIMEOUT, ping(pool)).await {
+        Ok(Ok(())) => Check::pass(),
+        Ok(Err(error)) => Check::fail(error.to_string()),
+        Err(_) => Check::fail(format!("No response within {} seconds", DATABASE_TIMEOUT.as_secs()))
+    };
+
+    check.duration = Some(started.elapsed().as_millis());
+    check
+}
+
+fn check_shutdown(state: &CommonState) -> Check {
+    match state.is_draining() {
+        true => Check::fail(String::from("This instance is shutting down")),
+        false => Check::pass()
+    }
+}
+
+fn check_maintenance(state: &CommonState) -> Check {
+    match state.maintenance() {
+        Some(notice) => Check::warn(format!("Letters are refused since {}", notice.since)),
+        None => Check::pass()
+    }
+}
+
+/// Reports that the process is running, without looking at any of its dependencies.
 #[get("/healthcheck")]
 pub async fn healthcheck() -> impl Responder {
     HttpResponse::Ok()
 }
+
+/// Reports that the process is running, for orchestrators that restart instances that stop answering.
+#[get("/healthcheck/live")]
+pub async fn liveness() -> impl Responder {
+    HttpResponse::Ok()
+}
+
+/// Reports whether this instance can serve traffic, for orchestrators that route requests.
+#[get("/healthcheck/ready")]
+pub async fn readiness(state: Data<CommonState>, pool: Data<MobcPool>) -> impl Responder {
+    let mut checks = BTreeMap::new();
+
+    checks.insert(String::from("redis"), check_database(&pool).await);
+    checks.insert(String::from("shutdown"), check_shutdown(&state));
+    checks.insert(String::from("maintenance"), check_maintenance(&state));
+
+    for (worker, heartbeat) in state.heartbeats() {
+        let check = match heartbeat.is_stale() {
+            true => Check::fail(format!("No heartbeat since {}", heartbeat.at)),
+            false => Check::pass()
+        };
+
+        checks.insert(format!("worker.{}", worker), check);
+    }
+
+    let ready = checks
+        .values()
+        .all(|check| check.status != CheckStatus::Fail);
+
+    let readiness = Readiness { ready, checks };
+
+    match ready {
+        true => HttpResponse::Ok().json(readiness),
+        false => HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
@@ -1,6 +1,9 @@
 pub mod healthcheck;
 pub mod id;
 pub mod admin;
+pub mod status;
 
 pub use healthcheck::*;
 pub use id::*;
+pub use admin::*;
+pub use status::*;
@@ -0,0 +1,38 @@
+use actix_web::{get, Responder, Result};
+use actix_web::web::{Data, Json};
+use common::state::{CommonState, MaintenanceNotice};
+use serde::{Serialize, Deserialize};
+
+/// The operational state of this instance.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Status {
+    /// The host name of this instance.
+    pub host: String,
+
+    /// The version of the server.
+    pub version: String,
+
+    /// Whether this instance is shutting down.
+    pub draining: bool,
+
+    /// The current maintenance window, if any.
+    pub maintenance: Option<MaintenanceNotice>
+}
+
+impl Status {
+    pub fn new(state: &CommonState) -> Self {
+        Status {
+            host: state.host.clone(),
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            draining: state.is_draining(),
+            maintenance: state.maintenance()
+        }
+    }
+}
+
+#[get("/status")]
+pub async fn status(state: Data<CommonState>) -> Result<impl Responder> {
+    let response = Json(Status::new(&state));
+
+    Ok(response)
+}
@@ -0,0 +1,82 @@
+use std::future::pending;
+use std::time::{Duration, Instant};
+use actix_server::ServerHandle;
+use actix_web::rt::signal::unix::{signal, Signal, SignalKind};
+use actix_web::rt::time::timeout;
+use actix_web::web::Data;
+use common::state::CommonState;
+use futures_util::future::{select, Either};
+use log::{info, warn, error};
+
+/// Starts listening for a signal, logging why if that is not possible.
+fn listen(kind: SignalKind, name: &str) -> Option<Signal> {
+    match signal(kind) {
+        Ok(value) => Some(value),
+        Err(error) => {
+            error!("Unable to listen for {}: {}", name, error);
+
+            None
+        }
+    }
+}
+
+/// Waits for a signal, or forever if it could not be listened for.
+async fn receive(signal: &mut Option<Signal>) {
+    match signal {
+        Some(value) => {
+            value.recv().await;
+        },
+        None => pending().await
+    }
+}
+
+/// Waits for SIGTERM or SIGINT, returning the name of the signal received.
+///
+/// A signal that could not be listened for keeps its default action of ending the process, so
+/// the other one is still waited for. Returns None if neither can be listened for.
+async fn terminated() -> Option<&'static str> {
+    let mut terminate = listen(SignalKind::terminate(), "SIGTERM");
+    let mut interrupt = listen(SignalKind::interrupt(), "SIGINT");
+
+    if terminate.is_none() && interrupt.is_none() {
+        return None;
+    }
+
+    let received = select(Box::pin(receive(&mut terminate)), Box::pin(receive(&mut interrupt))).await;
+
+    match received {
+        Either::Left(_) => Some("SIGTERM"),
+        Either::Right(_) => Some("SIGINT")
+    }
+}
+
+/// Shuts the server down gracefully once the process is asked to terminate.
+///
+/// New letters are refused straight away and background workers stop after their current pass.
+/// Those passes and the requests that are already being handled share the grace period to
+/// finish, after which the server stops regardless.
+pub async fn shutdown_on_terminate(server: ServerHandle, state: Data<CommonState>, grace: u64) {
+    let name = match terminated().await {
+        Some(value) => value,
+        None => return
+    };
+
+    info!("Received {}, shutting down within {} seconds", name, grace);
+
+    state.drain();
+
+    let deadline = Instant::now() + Duration::from_secs(grace);
+
+    // The process exits as soon as the server stops, so background passes are waited for first
+    if timeout(Duration::from_secs(grace), state.idle()).await.is_err() {
+        warn!("Stopping background work that did not finish within {} seconds", grace);
+    }
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+
+    if timeout(remaining, server.stop(true)).await.is_err() {
+        warn!("Stopping requests that did not finish within {} seconds", grace);
+
+        server.stop(false).await;
+    }
+}